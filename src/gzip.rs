@@ -0,0 +1,377 @@
+//! Gzip compression for `--gzip-output`
+//!
+//! Data is compressed in blocks with the fixed Huffman codes from RFC 1951, which don't need a
+//! code table of their own, and back-references found with a hash of the next three bytes
+use std::io::{self, Write};
+
+// How much input to gather before compressing it as one block
+const BLOCK_SIZE: usize = 1 << 16;
+// How far back a match can be, and how short and long it can be
+const WINDOW_SIZE: usize = 1 << 15;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// How many earlier places with the same hash are tried, trading speed for size
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+const END_OF_BLOCK: u16 = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+// Carry a CRC-32, as used by gzip, on over more bytes
+fn crc32(crc: u32, bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!crc, |crc, &byte| {
+        CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+/// Compresses everything written to it into a gzip stream written to the inner writer
+///
+/// The stream is only complete once [`GzipWriter::finish`] has been called. If the writer is
+/// dropped first, it tries to finish then, but any error is lost
+pub struct GzipWriter<W: Write> {
+    // None once finished
+    inner: Option<W>,
+    // Input waiting to be compressed
+    pending: Vec<u8>,
+    // Compressed bits that don't make up a whole byte yet, lowest first
+    bits: u64,
+    bit_count: u32,
+    // Compressed bytes waiting to be written out
+    out: Vec<u8>,
+    crc: u32,
+    // How much input there's been, modulo 2^32 as gzip records it
+    size: u32,
+    header_written: bool,
+}
+
+impl<W: Write> GzipWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner: Some(inner),
+            pending: Vec::with_capacity(BLOCK_SIZE),
+            bits: 0,
+            bit_count: 0,
+            out: Vec::new(),
+            crc: 0,
+            size: 0,
+            header_written: false,
+        }
+    }
+
+    /// Compress whatever's left, end the stream, and give back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.end()?;
+        Ok(self.inner.take().expect("Only finished once"))
+    }
+
+    fn end(&mut self) -> io::Result<()> {
+        self.compress_pending()?;
+        // An empty block marked as the last one
+        self.push_bits(0b011, 3);
+        self.push_literal(END_OF_BLOCK);
+        if self.bit_count > 0 {
+            self.push_bits(0, 8 - self.bit_count);
+        }
+        self.out.extend_from_slice(&self.crc.to_le_bytes());
+        self.out.extend_from_slice(&self.size.to_le_bytes());
+        self.write_out()?;
+        self.inner_mut().flush()
+    }
+
+    fn inner_mut(&mut self) -> &mut W {
+        self.inner.as_mut().expect("Not written to after finishing")
+    }
+
+    // Write out whole bytes of compressed data, starting with the header if it hasn't gone yet
+    fn write_out(&mut self) -> io::Result<()> {
+        if !self.header_written {
+            self.header_written = true;
+            // Deflate, with no flags, no modification time and an unknown OS
+            let header = [0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+            self.inner_mut().write_all(&header)?;
+        }
+        let out = std::mem::take(&mut self.out);
+        self.inner_mut().write_all(&out)
+    }
+
+    // Compress the pending input as a block, unless there isn't any
+    fn compress_pending(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.pending);
+        self.crc = crc32(self.crc, &block);
+        self.size = self.size.wrapping_add(block.len() as u32);
+        // Not the last block, with fixed codes
+        self.push_bits(0b010, 3);
+        self.compress_block(&block);
+        self.push_literal(END_OF_BLOCK);
+        self.pending = block;
+        self.pending.clear();
+        self.write_out()
+    }
+
+    // Encode `block` as literals and back-references to earlier in the block
+    fn compress_block(&mut self, block: &[u8]) {
+        let mut chains = Chains {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            previous: vec![usize::MAX; block.len()],
+        };
+        let mut at = 0;
+        while at < block.len() {
+            let (length, distance) = chains.longest_match(block, at);
+            if length < MIN_MATCH {
+                self.push_literal(u16::from(block[at]));
+                chains.insert(block, at);
+                at += 1;
+                continue;
+            }
+            self.push_match(length, distance);
+            for at in at..at + length {
+                chains.insert(block, at);
+            }
+            at += length;
+        }
+    }
+
+    fn push_match(&mut self, length: usize, distance: usize) {
+        let code = LENGTH_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= length)
+            .expect("Matches are at least 3 long");
+        self.push_literal(257 + code as u16);
+        self.push_bits(
+            (length - usize::from(LENGTH_BASE[code])) as u64,
+            LENGTH_EXTRA[code].into(),
+        );
+        let code = DISTANCE_BASE
+            .iter()
+            .rposition(|&base| usize::from(base) <= distance)
+            .expect("Distances are at least 1");
+        // Distance codes are all 5 bits long
+        self.push_code(code as u16, 5);
+        self.push_bits(
+            (distance - usize::from(DISTANCE_BASE[code])) as u64,
+            DISTANCE_EXTRA[code].into(),
+        );
+    }
+
+    // Push a literal byte, a length code, or the end of the block, with its fixed code
+    fn push_literal(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.push_code(0x30 + symbol, 8),
+            144..=255 => self.push_code(0x190 + symbol - 144, 9),
+            256..=279 => self.push_code(symbol - 256, 7),
+            _ => self.push_code(0xc0 + symbol - 280, 8),
+        }
+    }
+
+    // Huffman codes are packed starting from their most significant bit
+    fn push_code(&mut self, code: u16, length: u32) {
+        let reversed = code.reverse_bits() >> (16 - length);
+        self.push_bits(reversed.into(), length);
+    }
+
+    // Everything else is packed starting from its least significant bit
+    fn push_bits(&mut self, value: u64, count: u32) {
+        self.bits |= value << self.bit_count;
+        self.bit_count += count;
+        while self.bit_count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+}
+
+// Earlier places in a block, chained by the hash of the three bytes starting there
+struct Chains {
+    // The latest place with each hash
+    head: Vec<usize>,
+    // The place before each one with the same hash
+    previous: Vec<usize>,
+}
+
+impl Chains {
+    fn hash(block: &[u8], at: usize) -> usize {
+        let key = u32::from_le_bytes([block[at], block[at + 1], block[at + 2], 0]);
+        (key.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, block: &[u8], at: usize) {
+        if at + MIN_MATCH <= block.len() {
+            let hash = Self::hash(block, at);
+            self.previous[at] = self.head[hash];
+            self.head[hash] = at;
+        }
+    }
+
+    // The longest earlier run of bytes that matches those starting at `at`, as its length and
+    // how far back it starts
+    fn longest_match(&self, block: &[u8], at: usize) -> (usize, usize) {
+        if at + MIN_MATCH > block.len() {
+            return (0, 0);
+        }
+        let max_length = MAX_MATCH.min(block.len() - at);
+        let mut best = (0, 0);
+        let mut candidate = self.head[Self::hash(block, at)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || at - candidate > WINDOW_SIZE {
+                break;
+            }
+            let length = block[candidate..]
+                .iter()
+                .zip(&block[at..at + max_length])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if length > best.0 {
+                best = (length, at - candidate);
+                if length == max_length {
+                    break;
+                }
+            }
+            candidate = self.previous[candidate];
+        }
+        best
+    }
+}
+
+impl<W: Write> Write for GzipWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let taken = buf.len().min(BLOCK_SIZE - self.pending.len());
+        self.pending.extend_from_slice(&buf[..taken]);
+        if self.pending.len() == BLOCK_SIZE {
+            self.compress_pending()?;
+        }
+        Ok(taken)
+    }
+
+    // Everything written so far is compressed and passed on, except for the last few bits,
+    // which can only be written with whatever comes next
+    fn flush(&mut self) -> io::Result<()> {
+        self.compress_pending()?;
+        self.inner_mut().flush()
+    }
+}
+
+impl<W: Write> Drop for GzipWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            let _ = self.end();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::process::{Command, Stdio};
+
+    // Decompress with the system's gzip, so the stream is checked by something that didn't
+    // write it
+    fn gunzip(compressed: &[u8]) -> Result<Vec<u8>> {
+        let mut gzip = Command::new("gzip")
+            .arg("-dc")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        gzip.stdin
+            .take()
+            .expect("Piped stdin")
+            .write_all(compressed)?;
+        let output = gzip.wait_with_output()?;
+        assert!(output.status.success(), "gzip rejected the stream");
+        Ok(output.stdout)
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(0, b""), 0);
+        assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+        // It can be carried on from one piece to the next
+        assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn round_trip() -> Result<()> {
+        // Some noise, which won't have many matches
+        let mut state = 1u32;
+        let noise = (0..200_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect::<Vec<_>>();
+        let tvs = [
+            Vec::new(),
+            b"a".to_vec(),
+            b"Foo\nBar\nBaz\n".to_vec(),
+            b"abcabcabcabcabcabc".to_vec(),
+            // Longer than a block, and more than the longest match
+            "The quick brown fox jumps over the lazy dog\n"
+                .repeat(5000)
+                .into_bytes(),
+            vec![0; 1000],
+            noise,
+        ];
+        for tv in tvs {
+            let mut gzip = GzipWriter::new(Vec::new());
+            // In uneven pieces, with flushes in between
+            for (i, piece) in tv.chunks(7919).enumerate() {
+                gzip.write_all(piece)?;
+                if i % 3 == 0 {
+                    gzip.flush()?;
+                }
+            }
+            let compressed = gzip.finish()?;
+            assert_eq!(gunzip(&compressed)?, tv, "{} bytes", tv.len());
+        }
+
+        // Repetitive input gets smaller
+        let mut gzip = GzipWriter::new(Vec::new());
+        gzip.write_all(&b"line\n".repeat(10_000))?;
+        assert!(gzip.finish()?.len() < 1000);
+
+        // Dropping the writer finishes the stream too
+        let mut compressed = Vec::new();
+        GzipWriter::new(&mut compressed).write_all(b"Foo\n")?;
+        assert_eq!(gunzip(&compressed)?, b"Foo\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+//! Rust utilities for Dagans
+//!
+//! The logic behind some of the binaries, for use from other code
+use anyhow::{Result, bail};
+use std::{
+    io::{Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+pub mod select;
+
+const PAGE_SIZE: usize = 4096;
+// How long to wait between checks for more input with `keep_reading`
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Fill in a printf-style file name template, replacing `%d` with `index` and `%%` with `%`
+///
+/// # Example
+///
+/// ```
+/// assert_eq!(dagan_utils::numbered_name("out_%d.txt", 3)?, "out_3.txt");
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn numbered_name(template: &str, index: usize) -> Result<String> {
+    let mut name = String::with_capacity(template.len());
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            name.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('d') => name.push_str(&index.to_string()),
+            Some('%') => name.push('%'),
+            _ => bail!("Expected `%d` or `%%` in template: {template}"),
+        }
+    }
+    Ok(name)
+}
+
+/// How [`stream_split_with`] copies its input
+#[derive(Debug, Clone, Default)]
+pub struct SplitOptions {
+    /// Flush every sink after each block, so none of them gets far ahead of the others
+    pub sync: bool,
+    /// When the input seems to have ended, keep checking for more for this long before
+    /// stopping. For sources that report the end of input before they're really done
+    pub keep_reading: Option<Duration>,
+    /// Stop after copying this many bytes, even if there's more input
+    pub max_bytes: Option<u64>,
+}
+
+/// Copy everything from `reader` to both `a` and `b`, like `sss` does with stdin
+///
+/// # Example
+///
+/// ```
+/// let input: &[u8] = b"foo\nbar\n";
+/// let mut a = Vec::new();
+/// let mut b = Vec::new();
+/// dagan_utils::stream_split(input, &mut a, &mut b)?;
+/// assert_eq!(a, input);
+/// assert_eq!(b, input);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn stream_split<R: Read, W1: Write, W2: Write>(reader: R, mut a: W1, mut b: W2) -> Result<()> {
+    stream_split_many(reader, &mut [&mut a, &mut b])
+}
+
+/// Copy everything from `reader` to every one of `sinks`
+pub fn stream_split_many(reader: impl Read, sinks: &mut [&mut dyn Write]) -> Result<()> {
+    stream_split_with(reader, sinks, &SplitOptions::default())
+}
+
+/// Like [`stream_split_many`], but flush every sink after each block, so none of them gets far
+/// ahead of the others
+pub fn stream_split_synced(reader: impl Read, sinks: &mut [&mut dyn Write]) -> Result<()> {
+    let options = SplitOptions {
+        sync: true,
+        ..Default::default()
+    };
+    stream_split_with(reader, sinks, &options)
+}
+
+/// Copy everything from `reader` to every one of `sinks`, as set out by `options`
+pub fn stream_split_with(
+    mut reader: impl Read,
+    sinks: &mut [&mut dyn Write],
+    options: &SplitOptions,
+) -> Result<()> {
+    let mut buf = [0u8; PAGE_SIZE];
+    // When we last ran out of input, if we haven't had any since
+    let mut idle_since = None;
+    let mut remaining = options.max_bytes.unwrap_or(u64::MAX);
+    while remaining != 0 {
+        let mut bytes = reader.read(&mut buf)?;
+        if bytes == 0 {
+            let Some(patience) = options.keep_reading else {
+                break;
+            };
+            let idle_since = idle_since.get_or_insert_with(Instant::now);
+            if idle_since.elapsed() >= patience {
+                break;
+            }
+            thread::sleep(POLL_INTERVAL.min(patience));
+            continue;
+        }
+        idle_since = None;
+        // Only part of the last block may fit under the cap
+        if let Ok(remaining) = usize::try_from(remaining) {
+            bytes = bytes.min(remaining);
+        }
+        remaining -= bytes as u64;
+
+        for sink in sinks.iter_mut() {
+            sink.write_all(&buf[0..bytes])?;
+        }
+        if options.sync {
+            for sink in sinks.iter_mut() {
+                sink.flush()?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::VecDeque,
+        io::{self, BufWriter, Cursor},
+    };
+
+    #[test]
+    fn basic_functionality() {
+        let tvs = [
+            "",
+            "hi",
+            "foo\nbar",
+            "\n",
+            "\r",
+            "\r\nbaz\n\t",
+            "The quic\nk brown fox jamped\n over the lazy dorg\n\n",
+            "👋",
+        ];
+        for tv in tvs {
+            let stdin = Cursor::new(String::from(tv));
+            let mut stdout = Vec::<u8>::new();
+            let mut stderr = Vec::<u8>::new();
+            stream_split(stdin, &mut stdout, &mut stderr).unwrap();
+            assert_eq!(tv, String::from_utf8(stdout).unwrap());
+            assert_eq!(tv, String::from_utf8(stderr).unwrap());
+        }
+    }
+
+    #[test]
+    fn numbered_names() -> Result<()> {
+        assert_eq!(numbered_name("out_%d.txt", 0)?, "out_0.txt");
+        assert_eq!(numbered_name("%d/%d", 12)?, "12/12");
+        assert_eq!(numbered_name("100%%_%d", 3)?, "100%_3");
+        assert!(numbered_name("out_%s", 0).is_err());
+        assert!(numbered_name("out_%", 0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn many_sinks() -> Result<()> {
+        let input = "foo\nbar\n";
+        let mut sinks = [Vec::<u8>::new(), Vec::new(), Vec::new()];
+        let [a, b, c] = &mut sinks;
+        stream_split_many(Cursor::new(input), &mut [a, b, c])?;
+        for sink in sinks {
+            assert_eq!(input, String::from_utf8(sink)?);
+        }
+
+        // No sinks is fine too
+        stream_split_many(Cursor::new(input), &mut [])?;
+        Ok(())
+    }
+
+    #[test]
+    fn sync() -> Result<()> {
+        // Longer than a block, so there's more than one flush
+        let input = "The quick brown fox\n".repeat(PAGE_SIZE);
+        let mut stdout = BufWriter::new(Vec::<u8>::new());
+        let mut stderr = BufWriter::new(Vec::<u8>::new());
+        stream_split_synced(Cursor::new(&input), &mut [&mut stdout, &mut stderr])?;
+        // Everything has already been flushed through
+        assert!(stdout.buffer().is_empty());
+        assert!(stderr.buffer().is_empty());
+        assert_eq!(input, String::from_utf8(stdout.into_inner()?)?);
+        assert_eq!(input, String::from_utf8(stderr.into_inner()?)?);
+        Ok(())
+    }
+
+    // Hands out one piece per read, reporting the end of input wherever there's no piece
+    struct Hiccups(VecDeque<Option<&'static str>>);
+
+    impl Read for Hiccups {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let piece = self.0.pop_front().flatten().unwrap_or_default();
+            buf[..piece.len()].copy_from_slice(piece.as_bytes());
+            Ok(piece.len())
+        }
+    }
+
+    #[test]
+    fn keep_reading() -> Result<()> {
+        let reader = || Hiccups(VecDeque::from([Some("foo"), None, None, Some("bar")]));
+        let tvs = [
+            (None, "foo"),
+            (Some(Duration::ZERO), "foo"),
+            (Some(Duration::from_millis(300)), "foobar"),
+        ];
+        for tv in tvs {
+            let mut a = Vec::<u8>::new();
+            let mut b = Vec::<u8>::new();
+            let options = SplitOptions {
+                keep_reading: tv.0,
+                ..Default::default()
+            };
+            let start = Instant::now();
+            stream_split_with(reader(), &mut [&mut a, &mut b], &options)?;
+            assert_eq!(tv.1, String::from_utf8(a)?);
+            assert_eq!(tv.1, String::from_utf8(b)?);
+            // Gives up once the input has been quiet for long enough, not before
+            if let Some(patience) = tv.0 {
+                assert!(start.elapsed() >= patience);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_bytes() -> Result<()> {
+        let input = "The quick brown fox\n".repeat(PAGE_SIZE);
+        let tvs = [
+            0,
+            1,
+            20,
+            PAGE_SIZE,
+            PAGE_SIZE + 7,
+            input.len(),
+            input.len() + 1,
+        ];
+        for max_bytes in tvs {
+            let mut a = Vec::<u8>::new();
+            let mut b = Vec::<u8>::new();
+            let options = SplitOptions {
+                max_bytes: Some(max_bytes as u64),
+                ..Default::default()
+            };
+            stream_split_with(Cursor::new(&input), &mut [&mut a, &mut b], &options)?;
+            let expected = &input[..max_bytes.min(input.len())];
+            assert_eq!(expected, String::from_utf8(a)?);
+            assert_eq!(expected, String::from_utf8(b)?);
+        }
+        Ok(())
+    }
+}
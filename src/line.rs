@@ -1,300 +1,8227 @@
 use anyhow::{Result, anyhow, bail};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use dagan_utils::{
+    numbered_name,
+    select::{
+        self, LAST_LINE, Pattern, Selector, check_order, is_exhausted, parse_number, read_line,
+        split_label, substitute_anchors, times_selected, trim_terminator,
+    },
+};
 use std::{
-    fs::File,
-    io::{self, BufRead, BufReader, Read, Write},
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet, VecDeque},
+    env,
+    fs::{self, File, Metadata},
+    io::{
+        self, BufRead, BufReader, BufWriter, IsTerminal, LineWriter, Read, Seek, SeekFrom, Write,
+    },
+    mem,
     num::NonZeroUsize,
-    path::PathBuf,
+    path::{Path, PathBuf},
+    process::{self, Child, Command, ExitStatus, Stdio},
+    slice,
+    str::FromStr,
+    sync::{
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, UNIX_EPOCH},
 };
 
-// Pattern that may have a starting and ending line number
-// Parsed from a Rust-like range pattern:
-// `..`, `5..`, `6..=10`, etc
-#[derive(Debug, Clone)]
-struct Pattern {
-    start: Option<NonZeroUsize>,
-    // This end is INCLUSIVE
-    end: Option<NonZeroUsize>,
-}
-
-impl Pattern {
-    // Check if a line number would be included
-    fn is_included(&self, line: NonZeroUsize) -> bool {
-        if let Some(start) = self.start
-            && line < start
-        {
-            return false;
+#[cfg(feature = "gzip")]
+mod gzip;
+
+// Parse an anchors file of `NAME=LINE` definitions
+// Blank lines and lines starting with `#` are ignored
+fn parse_anchors(text: &str) -> Result<HashMap<String, String>> {
+    let mut anchors = HashMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
-        if let Some(end) = self.end
-            && line > end
-        {
-            return false;
+        let Some((name, value)) = line.split_once('=') else {
+            bail!("Expected `NAME=LINE` in anchors file, got: {line}");
+        };
+        let (name, value) = (name.trim(), value.trim());
+        if !is_anchor_name(name) {
+            bail!("Invalid anchor name: {name}");
         }
-        true
+        if value.is_empty() {
+            bail!("Anchor `{name}` has no value");
+        }
+        anchors.insert(name.into(), value.into());
     }
+    Ok(anchors)
+}
 
-    // Construct a pattern from a string
-    fn parse(pattern: &str) -> Result<Self> {
-        fn try_nonzero(num: usize) -> Result<NonZeroUsize> {
-            NonZeroUsize::new(num).ok_or_else(|| anyhow!("Line numbers are 1-indexed"))
+// Parse a renumber map of `OLD NEW` line number pairs, separated by whitespace
+// Blank lines and lines starting with `#` are ignored, as in an anchors file
+fn parse_renumber_map(text: &str) -> Result<HashMap<usize, usize>> {
+    let mut map = HashMap::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
         }
+        let mut numbers = line.split_whitespace().map(str::parse::<usize>);
+        let (Some(Ok(old)), Some(Ok(new)), None) = (numbers.next(), numbers.next(), numbers.next())
+        else {
+            bail!("Expected `OLD NEW` line numbers in renumber map, got: {line}");
+        };
+        if map.insert(old, new).is_some() {
+            bail!("Line {old} is mapped more than once in renumber map");
+        }
+    }
+    Ok(map)
+}
 
-        if let Some((start, end)) = pattern.split_once("..") {
-            let start = if start.is_empty() {
-                None
-            } else {
-                Some(try_nonzero(start.parse()?)?)
-            };
+fn is_anchor_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
 
-            let end = if end.is_empty() {
-                None
-            } else if let Some(end) = end.strip_prefix("=") {
-                Some(try_nonzero(end.parse()?)?)
-            } else {
-                let num: usize = end.parse()?;
-                if num <= 1 {
-                    bail!("End of exclusive range must be greater than 1");
-                }
-                Some(try_nonzero(num - 1)?)
-            };
+// Guess whether the input's lines end with `\r\n` or `\n`, going by whichever is more common
+// at the start of it
+fn dominant_terminator(block: &[u8]) -> &'static [u8] {
+    let (mut crlf, mut lf) = (0, 0);
+    for (index, _) in block.iter().enumerate().filter(|&(_, &byte)| byte == b'\n') {
+        if index > 0 && block[index - 1] == b'\r' {
+            crlf += 1;
+        } else {
+            lf += 1;
+        }
+    }
+    if crlf > lf { b"\r\n" } else { b"\n" }
+}
+
+// Read a line like `read_line`, but with `--on-error=skip`, report read errors to `errors` and
+// try again instead of failing. Whatever was read of the line before the error is lost
+fn read_line_or_skip(
+    fin: &mut impl BufRead,
+    line: &mut Vec<u8>,
+    options: &Options,
+    number: usize,
+    mut errors: impl Write,
+) -> Result<bool> {
+    // Give up on input that never recovers
+    const MAX_CONSECUTIVE_ERRORS: usize = 100;
 
-            if let (Some(start), Some(end)) = (start, end)
-                && start > end
+    let mut failures = 0;
+    loop {
+        match read_line(fin, line, options.max_line_length) {
+            Err(err)
+                if options.on_read_error == ReadErrorPolicy::Skip
+                    && err.downcast_ref::<io::Error>().is_some()
+                    && failures < MAX_CONSECUTIVE_ERRORS =>
             {
-                bail!("Reverse patterns not supported");
+                writeln!(errors, "Error reading line {number}, skipping: {err}")?;
+                failures += 1;
             }
+            result => return result,
+        }
+    }
+}
+
+fn parse_patterns(patterns: &str, options: &Options) -> Result<Vec<Pattern>> {
+    select::parse_patterns(patterns, options.pattern_separator(), &options.anchors)
+}
 
-            Ok(Self { start, end })
-        } else if let Ok(start) = pattern.parse::<usize>() {
-            let val = Some(try_nonzero(start)?);
-            Ok(Self {
-                start: val,
-                end: val,
+// Parse patterns written as offsets from an anchor line, like `+1..=+5`
+fn parse_relative_patterns(
+    patterns: &str,
+    options: &Options,
+    anchor: NonZeroUsize,
+) -> Result<Vec<Pattern>> {
+    patterns
+        .split(options.pattern_separator())
+        .map(|pattern| {
+            let (pattern, label) = split_label(pattern);
+            let pattern = substitute_anchors(pattern, &options.anchors)?;
+            Ok(Pattern {
+                label,
+                ..Pattern::parse(&resolve_offsets(&pattern, anchor.get())?)?
             })
-        } else {
-            bail!("Could not interpret line number pattern: {pattern}");
+        })
+        .collect()
+}
+
+// Replace offsets in a pattern with the line numbers they point to
+// An open start counts from the anchor line itself
+fn resolve_offsets(pattern: &str, anchor: usize) -> Result<String> {
+    if let Some((pattern, step)) = pattern.split_once(':') {
+        return Ok(format!("{}:{step}", resolve_offsets(pattern, anchor)?));
+    }
+
+    let resolve = |number: &str| -> Result<String> {
+        // The last line is the same wherever the anchor is
+        if number.is_empty() || number.trim_start_matches('=') == "$" {
+            return Ok(number.into());
         }
+        let (inclusive, offset) = match number.strip_prefix('=') {
+            Some(offset) => ("=", offset),
+            None => ("", number),
+        };
+        let Some(offset) = offset.strip_prefix('+') else {
+            bail!("Expected an offset like `+1`, got: {number}");
+        };
+        let line = anchor
+            .checked_add(parse_number(offset)?)
+            .ok_or_else(|| anyhow!("Offset is too large: {offset}"))?;
+        Ok(format!("{inclusive}{line}"))
+    };
+
+    if let Some((start, end)) = pattern.split_once("..") {
+        let start = if start.is_empty() {
+            anchor.to_string()
+        } else {
+            resolve(start)?
+        };
+        Ok(format!("{start}..{}", resolve(end)?))
+    } else {
+        resolve(pattern)
+    }
+}
+
+// True if `needle` appears anywhere in `haystack`
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+}
+
+// Cut a line off after `width` characters. Bytes that aren't valid UTF-8 count as a character each
+fn truncate_chars(line: &[u8], width: usize) -> &[u8] {
+    // Every character starts with a byte that isn't a continuation byte
+    let end = line
+        .iter()
+        .enumerate()
+        .filter(|(_, byte)| **byte & 0xc0 != 0x80)
+        .nth(width)
+        .map_or(line.len(), |(index, _)| index);
+    &line[..end]
+}
+
+// How many times `needle` appears in `haystack`, without overlapping
+fn count_occurrences(haystack: &[u8], needle: &[u8]) -> usize {
+    let mut count = 0;
+    let mut rest = haystack;
+    while let Some(index) = rest
+        .windows(needle.len())
+        .position(|window| window == needle)
+    {
+        count += 1;
+        rest = &rest[index + needle.len()..];
     }
+    count
 }
 
-fn write_lines(
+fn write_lines(fin: impl Read, fout: impl Write, patterns: &str, options: &Options) -> Result<()> {
+    write_lines_from(fin, fout, patterns, options, NonZeroUsize::MIN)
+}
+
+// Like `write_lines`, but with input that starts at line `first` of the file
+fn write_lines_from(
     fin: impl Read,
-    mut fout: impl Write,
+    fout: impl Write,
     patterns: &str,
-    options: Options,
+    options: &Options,
+    first: NonZeroUsize,
 ) -> Result<()> {
-    let patterns = patterns
-        .split(",")
-        .map(Pattern::parse)
-        .collect::<Result<Vec<Pattern>>>()?;
-
-    // We consume lines, so patterns must be given in order
-    // In the future, this restriction could be lifted
-    patterns.iter().try_fold(
-        Pattern {
-            start: None,
-            end: None,
-        },
-        |prev, this| {
-            if prev.start.is_some() || prev.end.is_some() {
-                let prev_end = prev.end.unwrap_or(NonZeroUsize::MAX);
-                let this_start = this.start.unwrap_or(NonZeroUsize::MIN);
-                if prev_end > this_start {
-                    return Err(anyhow!("Lines currently must be given in order"));
-                }
-            }
-            Ok(this.clone())
-        },
-    )?;
+    // Relative patterns can't be resolved until we find the line they're relative to
+    let mut resolved = if options.after.is_some() {
+        check_order(&parse_relative_patterns(
+            patterns,
+            options,
+            NonZeroUsize::MIN,
+        )?)?;
+        None
+    } else {
+        let patterns = parse_patterns(patterns, options)?;
+        check_order(&patterns)?;
+        Some(Selector::new(patterns))
+    };
+    let mut selection = Selection::new(fout, options);
+    // Which patterns have already had their label shown
+    let mut labeled = HashSet::new();
+    // How many lines each pattern may show, and how many it has shown so far
+    let limit = options
+        .limit_per_range
+        .map_or(usize::MAX, NonZeroUsize::get);
+    let mut shown_per_pattern = HashMap::<usize, usize>::new();
+    let mut context = options
+        .context
+        .as_ref()
+        .map(|context| ContextWindow::new(context.lines));
 
-    let fin = BufReader::new(fin);
-    for (number, line) in fin.lines().enumerate() {
+    let mut fin = BufReader::new(fin);
+    if options.line_terminator_auto {
+        selection.output.terminator = dominant_terminator(fin.fill_buf()?);
+    }
+    let mut buf = Vec::new();
+    for number in first.get().. {
+        // Stop early if we've been interrupted, but still flush what we have
+        if options.stop.load(Ordering::Relaxed) || options.at_limit() {
+            break;
+        }
+        if !read_line_or_skip(&mut fin, &mut buf, options, number, &options.diagnostics)? {
+            break;
+        }
         // Lines are 1-indexed
-        let number = NonZeroUsize::new(number + 1).expect("Overflow");
-        let line = line?;
-
-        // Write line as many times as the pattern list calls for it
-        let mut can_break = true;
-        for pattern in &patterns {
-            if pattern.is_included(number) {
-                if options.show_line_number {
-                    write!(fout, "{number}\t")?;
-                }
-                // This seems to perform better than using `writeln!`
-                fout.write_all(line.as_bytes())?;
-                // TODO: This seems to have a large perf hit. Figure out a way to avoid flushing
-                fout.write_all(b"\n")?;
-            }
-            // Don't bother reading the rest if we don't have to
-            if let Some(end) = pattern.end {
-                if end > number {
-                    can_break = false;
+        let number = NonZeroUsize::new(number).expect("Overflow");
+        let line = options.decode(trim_terminator(&buf), number)?;
+        // Keep the line's own terminator until it's written, if it's to be preserved
+        let terminated;
+        let shown_line = if options.line_terminator_auto {
+            terminated = [&line[..], &buf[trim_terminator(&buf).len()..]].concat();
+            &terminated[..]
+        } else {
+            &line[..]
+        };
+
+        if resolved.is_none()
+            && let Some(after) = &options.after
+            && contains_bytes(&line, after.as_bytes())
+        {
+            resolved = Some(Selector::new(parse_relative_patterns(
+                patterns, options, number,
+            )?));
+        }
+        // `$` can't be placed until we know this is the last line
+        if let Some(selector) = &mut resolved
+            && selector.refers_to_last()
+            && fin.fill_buf()?.is_empty()
+        {
+            selector.resolve_last(number);
+        }
+
+        let selected = match (&resolved, &options.within) {
+            (Some(_), _) if !options.is_sampled(&line) => 0,
+            // Inside the window, the patterns say what to leave out
+            (Some(selector), Some(within)) => {
+                usize::from(within.is_included(number) && selector.times_selected(number) == 0)
+            }
+            (Some(selector), None) => selector.times_selected(number),
+            _ => 0,
+        };
+        // Skipped lines were still selected, so they don't belong in the complement
+        let shown = selected > 0 && !options.skips(&line);
+
+        if options.within.is_some() {
+            // Exclusions can't repeat a line, so it's shown at most once
+            if shown {
+                selection.push(number, shown_line)?;
+            }
+        } else {
+            // Labels come before every copy of the line, even one from an earlier pattern
+            if shown && options.show_labels {
+                for (index, pattern) in resolved.iter().flat_map(Selector::patterns).enumerate() {
+                    if let Some(label) = &pattern.label
+                        && pattern.is_included(number)
+                        && labeled.insert(index)
+                    {
+                        selection.output.write_label(label)?;
+                    }
                 }
-            } else {
-                can_break = false
             }
+            // Write line as many times as the pattern list calls for it
+            if let Some(selector) = &mut resolved {
+                selector.push(number, shown_line, shown, |index, number, line| {
+                    let count = shown_per_pattern.entry(index).or_default();
+                    if *count < limit {
+                        *count += 1;
+                        selection.push(number, line)?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+        if let (Some(window), Some(context)) = (&mut context, &options.context) {
+            let mut sink = context.sink.borrow_mut();
+            window.push(number, &line, shown, |_, line, gap| {
+                if gap {
+                    sink.write_all(b"--\n")?;
+                }
+                sink.write_all(line)?;
+                sink.write_all(b"\n")?;
+                Ok(())
+            })?;
         }
-        if can_break {
+        if selected == 0
+            && let Some(complement) = &options.complement
+        {
+            let mut complement = complement.borrow_mut();
+            complement.write_all(&line)?;
+            complement.write_all(b"\n")?;
+        }
+
+        // Don't bother reading the rest if we don't have to
+        // The complement needs every line, so we can never stop early
+        // Within a window, running out of exclusions means everything else is shown
+        // A pattern that has shown all the lines it's allowed to is as good as over, and so is
+        // everything once `--nth` has gone past the last occurrence it wants
+        let exhausted = selection.output.past_nth()
+            || match &options.within {
+                Some(within) => is_exhausted(slice::from_ref(within), number),
+                None => resolved.as_ref().is_some_and(|selector| {
+                    selector
+                        .patterns()
+                        .iter()
+                        .enumerate()
+                        .all(|(index, pattern)| {
+                            is_exhausted(slice::from_ref(pattern), number)
+                                || shown_per_pattern.get(&index) >= Some(&limit)
+                        })
+                }),
+            };
+        if options.complement.is_none()
+            && exhausted
+            && context.as_ref().is_none_or(|context| context.after == 0)
+        {
             break;
         }
     }
 
-    Ok(())
-}
+    // The input ended partway through a descending range
+    if let Some(selector) = &mut resolved {
+        selector.finish(|index, number, line| {
+            let count = shown_per_pattern.entry(index).or_default();
+            if *count < limit {
+                *count += 1;
+                selection.push(number, line)?;
+            }
+            Ok(())
+        })?;
+    }
 
-#[derive(Default)]
-struct Options {
-    show_line_number: bool,
-}
+    if let Some(after) = &options.after
+        && resolved.is_none()
+        && !options.stop.load(Ordering::Relaxed)
+    {
+        writeln!(&options.diagnostics, "Warning: no line contains `{after}`")?;
+    }
 
-/// Display selected lines from a file or stdin
-#[derive(Parser)]
-struct Args {
-    /// Show line numbers
-    #[clap(short = 'n')]
-    show_line_number: bool,
-    /// The lines or ranges of lines to display, separated by a comma
-    ///
-    /// # Examples
-    ///
-    /// "5" - show line 5  
-    /// "1,6,7" - show lines 1, 6, and 7
-    /// "5..7" - Show lines 5 and 6  
-    /// "5..=7" - Show lines 5, 6, and 7  
-    /// "1,5..7" - Show lines 1, 5, and 6
-    /// ".." - Show all lines
-    /// "5.." - Show all after and including 5
-    /// "..7" - Show all lines up to 7, excluding 7
-    /// "..=7" - Show all lines up to 7, including 7
-    ///
-    /// # Note
-    ///
-    /// Lines must be specified in order. This restriction might be lifted in the future.
-    #[clap(verbatim_doc_comment)]
-    lines: String,
-    /// The file to read
-    file: Option<PathBuf>,
+    if let Some(complement) = &options.complement {
+        complement.borrow_mut().flush()?;
+    }
+    if let Some(context) = &options.context {
+        context.sink.borrow_mut().flush()?;
+    }
+    selection.finish()
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let options = Options {
-        show_line_number: args.show_line_number,
-    };
-    let stdout = io::stdout().lock();
-    if let Some(file) = args.file {
-        let file = File::open(file)?;
-        write_lines(file, stdout, &args.lines, options)?;
-    } else {
-        let stdin = io::stdin().lock();
-        write_lines(stdin, stdout, &args.lines, options)?;
+// Write the lines each pattern selects to a file of its own, named by filling in `template` with
+// the pattern's index. A line selected by several patterns goes to each of their files
+fn split_ranges(fin: impl Read, patterns: &str, template: &str, options: &Options) -> Result<()> {
+    let patterns = parse_patterns(patterns, options)?;
+    check_order(&patterns)?;
+    if patterns.iter().any(|pattern| pattern.descending) {
+        bail!("Negative steps can't be used when splitting ranges into files");
+    }
+    if patterns.iter().any(Pattern::refers_to_last) {
+        bail!("`$` can't be used when splitting ranges into files");
+    }
+    if !template.contains("%d") && patterns.len() > 1 {
+        bail!("Template must contain `%d` to split more than one range into files");
+    }
+    let mut outputs = (0..patterns.len())
+        .map(|index| {
+            let file = File::create(numbered_name(template, index)?)?;
+            Ok(Output::new(BufWriter::new(file), options))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut fin = BufReader::new(fin);
+    let mut buf = Vec::new();
+    for number in 1.. {
+        if options.stop.load(Ordering::Relaxed) {
+            break;
+        }
+        if !read_line_or_skip(&mut fin, &mut buf, options, number, &options.diagnostics)? {
+            break;
+        }
+        let number = NonZeroUsize::new(number).expect("Overflow");
+        let line = options.decode(trim_terminator(&buf), number)?;
+        if options.is_sampled(&line) && !options.skips(&line) {
+            for (pattern, output) in patterns.iter().zip(&mut outputs) {
+                if pattern.is_included(number) {
+                    output.write_line(number, &line)?;
+                }
+            }
+        }
+        if is_exhausted(&patterns, number) {
+            break;
+        }
+    }
+
+    for output in outputs {
+        output.finish()?;
     }
     Ok(())
 }
 
-#[cfg(test)]
-mod tests {
-    use std::io::Cursor;
+// Where each line of a file starts, so we can jump straight to any line
+#[derive(Debug, PartialEq)]
+struct LineIndex {
+    // The file's size and modification time when it was indexed, to tell if it's changed since
+    size: u64,
+    modified: Duration,
+    // Where each line starts, from line 1 on
+    offsets: Vec<u64>,
+}
 
-    use super::*;
+impl LineIndex {
+    // Starts index files, so something else isn't mistaken for one
+    const MAGIC: &[u8; 8] = b"lineidx1";
 
-    #[test]
-    fn lines_must_be_specified_in_order() {
-        let failing_patterns = [
-            ("4,4", false),
-            ("4,5", false),
-            ("5,4", true),
-            ("1..9,4", true),
-            ("8..9,4", true),
-            ("2..4,1", true),
-            ("2..4,4", false),
-            ("2..=4,4", false),
-        ];
+    fn build(fin: impl Read, metadata: &Metadata) -> Result<Self> {
+        let mut fin = BufReader::new(fin);
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            let read = fin.read_until(b'\n', &mut buf)?;
+            if read == 0 {
+                break;
+            }
+            offsets.push(offset);
+            offset += read as u64;
+        }
+        Ok(Self {
+            size: metadata.len(),
+            modified: modified_since_epoch(metadata)?,
+            offsets,
+        })
+    }
 
-        for tv in failing_patterns {
-            let fin = Cursor::new(String::from("Foo\nBar\nBaz"));
-            let mut fout = Vec::new();
-            let patterns = tv.0;
-            let should_error = tv.1;
-            assert_eq!(
-                should_error,
-                write_lines(fin, &mut fout, patterns, Default::default()).is_err()
-            );
+    fn read(mut fin: impl Read) -> Result<Self> {
+        let mut magic = [0; 8];
+        fin.read_exact(&mut magic)?;
+        if &magic != Self::MAGIC {
+            bail!("Not a line index");
         }
+        let mut read_u64 = || -> Result<u64> {
+            let mut bytes = [0; 8];
+            fin.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes))
+        };
+        let size = read_u64()?;
+        let modified = Duration::from_nanos(read_u64()?);
+        let count = read_u64()?;
+        let offsets = (0..count).map(|_| read_u64()).collect::<Result<_>>()?;
+        Ok(Self {
+            size,
+            modified,
+            offsets,
+        })
     }
 
-    #[test]
-    fn select_lines() -> Result<()> {
-        let tvs: &[(&str, &str, &[&str])] = &[
-            ("", "1,2,2", &[]),
-            ("Foo\nBar", "1,2,2", &["Foo", "Bar", "Bar"]),
-            ("Foo\nBar\nBaz", "..", &["Foo", "Bar", "Baz"]),
-            ("Foo\nBar\nBaz", "1..", &["Foo", "Bar", "Baz"]),
-            ("Foo\nBar\nBaz", "2..", &["Bar", "Baz"]),
-            ("Foo\nBar\nBaz", "2..3", &["Bar"]),
-            ("Foo\nBar\nBaz", "2..=3", &["Bar", "Baz"]),
-            ("Foo\nBar\nBaz", "..=3", &["Foo", "Bar", "Baz"]),
-            ("Foo\nBar\nBaz", "..3", &["Foo", "Bar"]),
-            ("Foo\nBar\nBaz", "..3,3,3", &["Foo", "Bar", "Baz", "Baz"]),
-            ("Foo\nBar", "..", &["Foo", "Bar"]),
-            ("Foo\nBar\n", "..", &["Foo", "Bar"]),
-            ("Foo\nBar\n\n", "..", &["Foo", "Bar", ""]),
-            ("Foo\nBar\nBaz", "1,2..", &["Foo", "Bar", "Baz"]),
-        ];
+    fn write(&self, mut fout: impl Write) -> Result<()> {
+        fout.write_all(Self::MAGIC)?;
+        let modified = u64::try_from(self.modified.as_nanos())?;
+        for number in [self.size, modified, self.offsets.len() as u64] {
+            fout.write_all(&number.to_le_bytes())?;
+        }
+        for offset in &self.offsets {
+            fout.write_all(&offset.to_le_bytes())?;
+        }
+        fout.flush()?;
+        Ok(())
+    }
 
-        for tv in tvs {
-            let fin = Cursor::new(String::from(tv.0));
-            let mut fout = Vec::new();
-            let patterns = tv.1;
-            write_lines(fin, &mut fout, patterns, Default::default())?;
+    // True if the file hasn't changed since it was indexed
+    fn is_current(&self, metadata: &Metadata) -> bool {
+        self.size == metadata.len()
+            && modified_since_epoch(metadata).is_ok_and(|modified| modified == self.modified)
+    }
+}
 
-            let actual_lines = String::from_utf8(fout)?;
-            let actual_lines = actual_lines.lines().collect::<Vec<_>>();
-            for (expected_line, actual_line) in tv.2.iter().zip(actual_lines.iter()) {
-                assert_eq!(expected_line, actual_line);
+fn modified_since_epoch(metadata: &Metadata) -> Result<Duration> {
+    Ok(metadata.modified()?.duration_since(UNIX_EPOCH)?)
+}
+
+// Select lines from a file, using the index at `index_path` to jump straight to the first line
+// that could be selected. A missing or out-of-date index is rebuilt if `build` is set, and
+// otherwise not used
+fn write_indexed(
+    path: &Path,
+    index_path: &Path,
+    build: bool,
+    fout: impl Write,
+    patterns: &str,
+    options: &Options,
+) -> Result<()> {
+    let metadata = fs::metadata(path)?;
+    let index = File::open(index_path)
+        .ok()
+        .and_then(|fin| LineIndex::read(BufReader::new(fin)).ok())
+        .filter(|index| index.is_current(&metadata));
+    let index = match index {
+        Some(index) => Some(index),
+        None if build => {
+            let index = LineIndex::build(File::open(path)?, &metadata)?;
+            index.write(BufWriter::new(File::create(index_path)?))?;
+            Some(index)
+        }
+        None => {
+            writeln!(
+                &options.diagnostics,
+                "Warning: {} is missing or out of date, so it won't be used",
+                index_path.display()
+            )?;
+            None
+        }
+    };
+
+    let fin = open_file(path, options.open_timeout)?;
+    match index {
+        Some(index) => write_lines_indexed(fin, &index, fout, patterns, options),
+        None => write_lines(fin, fout, patterns, options),
+    }
+}
+
+// Select lines from `fin`, which `index` was built from, starting at the first line that could
+// be selected
+fn write_lines_indexed<R: Read + Seek>(
+    mut fin: R,
+    index: &LineIndex,
+    fout: impl Write,
+    patterns: &str,
+    options: &Options,
+) -> Result<()> {
+    // These need to see every line from the start
+    let needs_every_line = options.complement.is_some()
+        || options.context.is_some()
+        || options.after.is_some()
+        || options.within.is_some();
+    if needs_every_line {
+        fin.seek(SeekFrom::Start(0))?;
+        return write_lines(fin, fout, patterns, options);
+    }
+    let last = NonZeroUsize::new(index.offsets.len()).unwrap_or(NonZeroUsize::MIN);
+    let first = parse_patterns(patterns, options)?
+        .iter()
+        .map(|pattern| match pattern.start {
+            Some(LAST_LINE) => last,
+            start => start.unwrap_or(NonZeroUsize::MIN),
+        })
+        .min()
+        .unwrap_or(NonZeroUsize::MIN);
+    // Past the last line, there's nothing to read
+    let offset = index
+        .offsets
+        .get(first.get() - 1)
+        .copied()
+        .unwrap_or(index.size);
+    fin.seek(SeekFrom::Start(offset))?;
+    write_lines_from(fin, fout, patterns, options, first)
+}
+
+// Answer requests for lines from `path`, one per line of `control`, until it ends
+// Each request is a pattern list, like LINES. The answer is `ok N`, a newline, then the N bytes of
+// the selection, or `error MESSAGE` and a newline if it can't be answered. Blank requests are
+// ignored
+fn serve(
+    path: &Path,
+    control: impl BufRead,
+    mut fout: impl Write,
+    options: &Options,
+) -> Result<()> {
+    let mut fin = open_file(path, options.open_timeout)?;
+    let metadata = fin.metadata()?;
+    let mut index = LineIndex::build(&mut fin, &metadata)?;
+    for request in control.lines() {
+        if options.stop.load(Ordering::Relaxed) {
+            break;
+        }
+        let request = request?;
+        let request = request.trim();
+        if request.is_empty() {
+            continue;
+        }
+        // The file may have changed between requests
+        let metadata = fs::metadata(path)?;
+        if !index.is_current(&metadata) {
+            fin = open_file(path, options.open_timeout)?;
+            index = LineIndex::build(&mut fin, &metadata)?;
+        }
+        // Each request gets the whole selection, up to `--max-lines` and the budget
+        options.emitted.set(0);
+        options.budget_used.set(0);
+        let mut selection = Vec::new();
+        match write_lines_indexed(&mut fin, &index, &mut selection, request, options) {
+            Ok(()) => {
+                writeln!(fout, "ok {}", selection.len())?;
+                fout.write_all(&selection)?;
+            }
+            Err(err) => {
+                let message = err.to_string().replace('\n', " ");
+                writeln!(fout, "error {message}")?;
             }
-            assert_eq!(tv.2.len(), actual_lines.len());
         }
-        Ok(())
+        fout.flush()?;
     }
+    Ok(())
+}
 
-    #[test]
-    fn pattern_parsing() {
-        let p = Pattern::parse("1").unwrap();
-        assert_eq!(p.start.unwrap().get(), 1);
-        assert_eq!(p.end.unwrap().get(), 1);
+// Interleave the lines of several inputs, one line from each in turn
+fn zip_lines(
+    fins: Vec<impl Read>,
+    fout: impl Write,
+    patterns: &str,
+    options: &Options,
+) -> Result<()> {
+    let patterns = parse_patterns(patterns, options)?;
+    check_order(&patterns)?;
+    if patterns.iter().any(|pattern| pattern.descending) {
+        bail!("Negative steps can't be used when zipping");
+    }
+    if patterns.iter().any(Pattern::refers_to_last) {
+        bail!("`$` can't be used when zipping");
+    }
+    let mut output = Output::new(fout, options);
+
+    // Inputs are dropped once they run out of lines
+    let mut fins = fins
+        .into_iter()
+        .map(BufReader::new)
+        .map(Some)
+        .collect::<Vec<_>>();
+    let mut buf = Vec::new();
+    for number in 1.. {
+        if options.stop.load(Ordering::Relaxed) || fins.iter().all(Option::is_none) {
+            break;
+        }
+        let number = NonZeroUsize::new(number).expect("Overflow");
+        for slot in &mut fins {
+            let Some(fin) = slot else {
+                continue;
+            };
+            if !read_line_or_skip(fin, &mut buf, options, number.get(), &options.diagnostics)? {
+                *slot = None;
+                continue;
+            }
+            let line = options.decode(trim_terminator(&buf), number)?;
+            if options.skips(&line) || !options.is_sampled(&line) {
+                continue;
+            }
+            for _ in 0..times_selected(&patterns, number) {
+                output.write_line(number, &line)?;
+            }
+        }
+        if is_exhausted(&patterns, number) {
+            break;
+        }
+    }
 
-        let p = Pattern::parse("..").unwrap();
-        assert_eq!(p.start, None);
-        assert_eq!(p.end, None);
+    output.finish()
+}
 
-        let p = Pattern::parse("5..").unwrap();
-        assert_eq!(p.start.unwrap().get(), 5);
-        assert_eq!(p.end, None);
+// A block of lines marked by the text at its start and end
+struct Between {
+    start: String,
+    end: String,
+    // Leave out the lines that mark the block
+    exclusive: bool,
+    // Fail if the block never ends
+    require_end: bool,
+}
 
-        let p = Pattern::parse("42..100").unwrap();
-        assert_eq!(p.start.unwrap().get(), 42);
-        assert_eq!(p.end.unwrap().get(), 99);
+// Show the lines from the first one containing the start text up to the next one containing
+// the end text
+fn write_between(
+    fin: impl Read,
+    fout: impl Write,
+    between: &Between,
+    options: &Options,
+) -> Result<()> {
+    let mut selection = Selection::new(fout, options);
+    let mut started = false;
+    let mut ended = false;
 
-        let p = Pattern::parse("..2").unwrap();
-        assert_eq!(p.start, None);
-        assert_eq!(p.end.unwrap().get(), 1);
+    let mut fin = BufReader::new(fin);
+    let mut buf = Vec::new();
+    for number in 1.. {
+        if options.stop.load(Ordering::Relaxed)
+            || !read_line_or_skip(&mut fin, &mut buf, options, number, &options.diagnostics)?
+        {
+            break;
+        }
+        let number = NonZeroUsize::new(number).expect("Overflow");
+        let line = options.decode(trim_terminator(&buf), number)?;
 
-        let p = Pattern::parse("..=2").unwrap();
-        assert_eq!(p.start, None);
-        assert_eq!(p.end.unwrap().get(), 2);
+        // The end is only looked for after the start line
+        let marker = if started {
+            ended = contains_bytes(&line, between.end.as_bytes());
+            ended
+        } else {
+            started = contains_bytes(&line, between.start.as_bytes());
+            started
+        };
+        if started
+            && !(marker && between.exclusive)
+            && options.is_sampled(&line)
+            && !options.skips(&line)
+        {
+            selection.push(number, &line)?;
+        }
+        if ended {
+            break;
+        }
+    }
 
-        let p = Pattern::parse("1..=1").unwrap();
-        assert_eq!(p.start.unwrap().get(), 1);
-        assert_eq!(p.end.unwrap().get(), 1);
+    if started && !ended && between.require_end && !options.stop.load(Ordering::Relaxed) {
+        bail!(
+            "No line after `{}` contains `{}`",
+            between.start,
+            between.end
+        );
+    }
+    selection.finish()
+}
 
-        let p = Pattern::parse("5..=100").unwrap();
-        assert_eq!(p.start.unwrap().get(), 5);
-        assert_eq!(p.end.unwrap().get(), 100);
+// Show every line the patterns select that contains `around`, along with `context` lines on
+// either side. The context lines needn't be selected themselves
+fn write_around(
+    fin: impl Read,
+    fout: impl Write,
+    patterns: &str,
+    around: &str,
+    context: usize,
+    options: &Options,
+) -> Result<()> {
+    let selector = Selector::new(parse_patterns(patterns, options)?);
+    let mut selection = Selection::new(fout, options);
+    let mut window = ContextWindow::new(context);
 
-        assert!(Pattern::parse("0..5").is_err());
-        assert!(Pattern::parse("..0").is_err());
-        assert!(Pattern::parse("..1").is_err());
-        assert!(Pattern::parse("0").is_err());
+    let mut fin = BufReader::new(fin);
+    let mut buf = Vec::new();
+    for number in 1.. {
+        if options.stop.load(Ordering::Relaxed)
+            || !read_line_or_skip(&mut fin, &mut buf, options, number, &options.diagnostics)?
+        {
+            break;
+        }
+        let number = NonZeroUsize::new(number).expect("Overflow");
+        // Lines outside the window don't count, even as context
+        if let Some(within) = &options.within
+            && !within.is_included(number)
+        {
+            if is_exhausted(slice::from_ref(within), number) {
+                break;
+            }
+            continue;
+        }
+        let line = options.decode(trim_terminator(&buf), number)?;
+
+        let matched = selector.times_selected(number) > 0
+            && contains_bytes(&line, around.as_bytes())
+            && options.is_sampled(&line)
+            && !options.skips(&line);
+        window.push(number, &line, matched, |number, line, gap| {
+            if gap {
+                selection.output.write_separator()?;
+            }
+            selection.push(number, line)
+        })?;
+    }
+    selection.finish()
+}
+
+// Write the concrete ranges that the patterns select, rather than the lines themselves
+fn write_ranges(
+    fin: impl Read,
+    mut fout: impl Write,
+    patterns: &str,
+    options: &Options,
+) -> Result<()> {
+    let patterns = parse_patterns(patterns, options)?;
+
+    // Only open-ended patterns need to know where the input ends
+    let mut line_count = 0;
+    if patterns.iter().any(|pattern| pattern.end.is_none()) {
+        let mut fin = BufReader::new(fin);
+        let mut buf = Vec::new();
+        while read_line_or_skip(
+            &mut fin,
+            &mut buf,
+            options,
+            line_count + 1,
+            &options.diagnostics,
+        )? {
+            line_count += 1;
+        }
+    }
+
+    let mut ranges = patterns
+        .iter()
+        .filter_map(|pattern| pattern.resolve(line_count))
+        .collect::<Vec<_>>();
+    ranges.sort();
+    let ranges = ranges
+        .iter()
+        .map(|(start, end)| format!("{start}-{end}"))
+        .collect::<Vec<_>>();
+    writeln!(fout, "{}", ranges.join(" "))?;
+    fout.flush()?;
+    Ok(())
+}
+
+// Read a list of file names, each ending in `separator`
+fn read_file_list(mut fin: impl Read, separator: u8) -> Result<Vec<PathBuf>> {
+    let mut list = Vec::new();
+    fin.read_to_end(&mut list)?;
+    list.split(|&byte| byte == separator)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let name = if separator == b'\n' {
+                trim_terminator(name)
+            } else {
+                name
+            };
+            let name = std::str::from_utf8(name)
+                .map_err(|_| anyhow!("File name is not valid UTF-8: {name:?}"))?;
+            Ok(PathBuf::from(name))
+        })
+        .collect()
+}
+
+// Open a file for reading, treating "-" as stdin
+// With a limit on open files, this waits for a place among them, which is given back once the
+// input is dropped
+fn open_input(path: &Path, options: &Options, reverse: bool) -> Result<Box<dyn Read>> {
+    let permit = options.open_files.as_ref().map(OpenFiles::acquire);
+    open_input_with(path, options, reverse, permit)
+}
+
+// Open a file for reading, holding `permit` for as long as it's open
+fn open_input_with(
+    path: &Path,
+    options: &Options,
+    reverse: bool,
+    permit: Option<OpenFilePermit>,
+) -> Result<Box<dyn Read>> {
+    let fin: Box<dyn Read> = if path.to_str() == Some("-") {
+        if options.start_at_byte.is_some() {
+            bail!("Can't seek in stdin");
+        }
+        Box::new(io::stdin().lock())
+    } else if let Some(offset) = options.start_at_byte {
+        Box::new(seek_to_line(
+            open_file(path, options.open_timeout)?,
+            offset,
+        )?)
+    } else {
+        Box::new(open_file(path, options.open_timeout)?)
+    };
+    let name = options.input_name(path);
+    let fin = screen_binary(fin, &name, options.binary, &options.diagnostics)?;
+    if reverse {
+        return Ok(Box::new(io::Cursor::new(reverse_lines(fin, options)?)));
+    }
+    Ok(Box::new(Permitted {
+        fin,
+        _permit: permit,
+    }))
+}
+
+// With `--zip`, every input is read from until they're all done, so an input can't wait for
+// another to be closed. While there's room, inputs are held open as usual, leaving one place
+// free. The rest are opened again for each read, in that last place, and closed straight after
+fn open_for_zip(path: &Path, options: &Options, reverse: bool) -> Result<Box<dyn Read>> {
+    let Some(open_files) = &options.open_files else {
+        return open_input(path, options, reverse);
+    };
+    // A reversed file is read in full up front, so it isn't held open anyway
+    if reverse {
+        return open_input(path, options, reverse);
+    }
+    if let Some(permit) = open_files.try_hold() {
+        return open_input_with(path, options, reverse, Some(permit));
+    }
+    // Only a regular file can be found again after it's closed
+    if path.to_str() == Some("-") || !fs::metadata(path)?.is_file() {
+        bail!(
+            "Only regular files can be zipped past the open file limit, and {} isn't one",
+            options.input_name(path)
+        );
+    }
+    let offset = match options.start_at_byte {
+        Some(offset) => seek_to_line(File::open(path)?, offset)?.stream_position()?,
+        None => 0,
+    };
+    let fin = Box::new(ReopenedFile {
+        path: path.to_owned(),
+        offset,
+        open_files: Arc::clone(open_files),
+    });
+    screen_binary(
+        fin,
+        &options.input_name(path),
+        options.binary,
+        &options.diagnostics,
+    )
+}
+
+// A limit on how many inputs are open at once, shared by everything that opens them
+struct OpenFiles {
+    // How many more inputs may be opened
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl OpenFiles {
+    fn new(max: NonZeroUsize) -> Arc<Self> {
+        Arc::new(Self {
+            free: Mutex::new(max.get()),
+            freed: Condvar::new(),
+        })
+    }
+
+    // Wait until there's a place for another input, and take it
+    fn acquire(self: &Arc<Self>) -> OpenFilePermit {
+        let mut free = self.free.lock().expect("Poisoned");
+        while *free == 0 {
+            free = self.freed.wait(free).expect("Poisoned");
+        }
+        *free -= 1;
+        OpenFilePermit(Arc::clone(self))
+    }
+
+    // Take a place for an input that will be held open indefinitely, but only if that still
+    // leaves one free, so inputs that are only opened briefly never wait forever
+    fn try_hold(self: &Arc<Self>) -> Option<OpenFilePermit> {
+        let mut free = self.free.lock().expect("Poisoned");
+        if *free < 2 {
+            return None;
+        }
+        *free -= 1;
+        Some(OpenFilePermit(Arc::clone(self)))
+    }
+}
+
+// A place among the open inputs, given back when dropped
+struct OpenFilePermit(Arc<OpenFiles>);
+
+impl Drop for OpenFilePermit {
+    fn drop(&mut self) {
+        *self.0.free.lock().expect("Poisoned") += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+// An input that keeps its place among the open inputs until it's dropped
+struct Permitted<R> {
+    fin: R,
+    _permit: Option<OpenFilePermit>,
+}
+
+impl<R: Read> Read for Permitted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.fin.read(buf)
+    }
+}
+
+// A file that's opened again for each read, and closed straight after, so it only takes a place
+// among the open inputs while it's being read
+struct ReopenedFile {
+    path: PathBuf,
+    // Where the next read starts
+    offset: u64,
+    open_files: Arc<OpenFiles>,
+}
+
+impl Read for ReopenedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let _permit = self.open_files.acquire();
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset))?;
+        let read = file.read(buf)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+// Open a file, giving up after `timeout` if it's a named pipe that nothing has opened to write to
+fn open_file(path: &Path, timeout: Option<Duration>) -> Result<File> {
+    let Some(timeout) = timeout.filter(|_| is_fifo(path)) else {
+        return Ok(File::open(path)?);
+    };
+    let (sender, receiver) = mpsc::channel();
+    let owned = path.to_owned();
+    // Opening can't be interrupted, so it's left to finish or not on its own
+    thread::spawn(move || sender.send(File::open(owned)));
+    match receiver.recv_timeout(timeout) {
+        Ok(file) => Ok(file?),
+        Err(_) => bail!(
+            "Gave up waiting for something to write to {}",
+            path.display()
+        ),
+    }
+}
+
+#[cfg(unix)]
+fn is_fifo(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+fn is_fifo(_path: &Path) -> bool {
+    false
+}
+
+// Which file on disk this is, so a file replaced under the same name can be told apart
+#[cfg(unix)]
+fn file_id(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+// Start reading from the first line that begins at or after `offset`
+fn seek_to_line<R: Read + Seek>(mut fin: R, offset: u64) -> Result<BufReader<R>> {
+    let Some(before) = offset.checked_sub(1) else {
+        return Ok(BufReader::new(fin));
+    };
+    fin.seek(SeekFrom::Start(before))?;
+    let mut fin = BufReader::new(fin);
+    // Unless the byte before the offset ends a line, we've landed partway through one
+    let mut previous = [0];
+    if fin.read(&mut previous)? == 1 && previous[0] != b'\n' {
+        fin.read_until(b'\n', &mut Vec::new())?;
+    }
+    Ok(fin)
+}
+
+// Look for a NUL byte at the start of the input, which means it's probably binary, and act on it
+// according to `policy`. Skipped input reads as empty
+fn screen_binary(
+    fin: Box<dyn Read>,
+    name: &str,
+    policy: BinaryPolicy,
+    mut errors: impl Write,
+) -> Result<Box<dyn Read>> {
+    if policy == BinaryPolicy::Print {
+        return Ok(fin);
+    }
+    let mut fin = BufReader::new(fin);
+    if !fin.fill_buf()?.contains(&0) {
+        return Ok(Box::new(fin));
+    }
+    if policy == BinaryPolicy::Skip {
+        writeln!(errors, "Skipping binary file {name}")?;
+        return Ok(Box::new(io::empty()));
+    }
+    writeln!(errors, "Warning: {name} looks like a binary file")?;
+    Ok(Box::new(fin))
+}
+
+// Where the output goes, possibly compressed on the way
+enum OutputFile {
+    Plain(Box<dyn Write>),
+    #[cfg(feature = "gzip")]
+    Gzip(gzip::GzipWriter<Box<dyn Write>>),
+}
+
+impl OutputFile {
+    // Write out anything still held back. A compressed stream is only complete after this
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Self::Plain(mut fout) => fout.flush(),
+            #[cfg(feature = "gzip")]
+            Self::Gzip(fout) => fout.finish().map(drop),
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Plain(fout) => fout.write(buf),
+            #[cfg(feature = "gzip")]
+            Self::Gzip(fout) => fout.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Plain(fout) => fout.flush(),
+            #[cfg(feature = "gzip")]
+            Self::Gzip(fout) => fout.flush(),
+        }
+    }
+}
+
+// Open the file the output goes to, or stdout if there isn't one
+#[cfg_attr(not(feature = "gzip"), allow(unused_variables))]
+fn open_output(path: Option<&Path>, gzip: bool) -> Result<OutputFile> {
+    let fout: Box<dyn Write> = match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    #[cfg(feature = "gzip")]
+    if gzip {
+        return Ok(OutputFile::Gzip(gzip::GzipWriter::new(fout)));
+    }
+    Ok(OutputFile::Plain(fout))
+}
+
+// The pager to use with `--paginate`, from `$PAGER` if it's set
+fn pager_command(pager: Option<String>) -> String {
+    pager
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| "less -R".into())
+}
+
+// Start a pager with a pipe to its stdin, or None if it can't be started
+// The command is split on whitespace rather than run with the shell, so a missing pager is
+// noticed here instead of after we've started writing to it
+fn spawn_pager(command: &str) -> Option<Child> {
+    let mut words = command.split_whitespace();
+    Command::new(words.next()?)
+        .args(words)
+        .stdin(Stdio::piped())
+        .spawn()
+        .ok()
+}
+
+// Open where the output goes: a pager started with `command` if there is one, or else the
+// output file or stdout. A pager that can't be started is warned about and left out
+fn open_paged_output(
+    command: Option<&str>,
+    path: Option<&Path>,
+    gzip: bool,
+    options: &Options,
+) -> Result<(OutputFile, Option<Child>)> {
+    let mut pager = None;
+    if let Some(command) = command {
+        pager = spawn_pager(command);
+        if pager.is_none() {
+            writeln!(
+                &options.diagnostics,
+                "Warning: could not start pager `{command}`, writing to stdout instead"
+            )?;
+        }
+    }
+    let fout = match &mut pager {
+        Some(pager) => OutputFile::Plain(Box::new(
+            pager.stdin.take().expect("Pager has a piped stdin"),
+        )),
+        None => open_output(path, gzip)?,
+    };
+    Ok((fout, pager))
+}
+
+// Wait for the pager to finish, once everything has been written to it
+// Quitting the pager early closes the pipe, so a broken pipe only means the rest of the output
+// wasn't wanted
+fn wait_for_pager(mut pager: Child, written: Result<bool>) -> Result<(ExitStatus, bool)> {
+    let status = pager.wait()?;
+    let all_ok = match written {
+        Err(err) if is_broken_pipe(&err) => true,
+        written => written?,
+    };
+    Ok((status, all_ok))
+}
+
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
+// Handle each file in turn. Normally the first failure stops everything, but with `keep_going`,
+// failures are reported to `errors` and the rest of the files are still handled
+// Returns false if any file failed
+fn each_input(
+    paths: &[PathBuf],
+    keep_going: bool,
+    options: &Options,
+    mut errors: impl Write,
+    mut handle: impl FnMut(&Path) -> Result<()>,
+) -> Result<bool> {
+    let mut all_ok = true;
+    for path in paths {
+        if let Err(err) = handle(path) {
+            if !keep_going {
+                return Err(err);
+            }
+            writeln!(errors, "{}: {err}", options.input_name(path))?;
+            all_ok = false;
+        }
+    }
+    Ok(all_ok)
+}
+
+// Show the selection again every time the file changes, until stopped
+fn watch(
+    path: &Path,
+    mut fout: impl Write,
+    patterns: &str,
+    options: &Options,
+    interval: Duration,
+) -> Result<()> {
+    // What the file looked like last time we showed it
+    let mut last_seen = None;
+    while !options.stop.load(Ordering::Relaxed) {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            // While a log is being rotated, it may briefly not exist. Wait for the new one
+            Err(err) if err.kind() == io::ErrorKind::NotFound && last_seen.is_some() => {
+                thread::sleep(interval);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        // A new file counts as a change even if its size and time match the old one's
+        let metadata = file.metadata()?;
+        let seen = (metadata.modified()?, metadata.len(), file_id(&metadata));
+        if last_seen != Some(seen) {
+            last_seen = Some(seen);
+            // Each refresh shows the whole selection again, up to `--max-lines` and the budget
+            options.emitted.set(0);
+            options.budget_used.set(0);
+            let mut selection = Vec::new();
+            write_lines(file, &mut selection, patterns, options)?;
+            // Clear the screen and move to the top left
+            fout.write_all(b"\x1b[2J\x1b[H")?;
+            fout.write_all(&selection)?;
+            fout.flush()?;
+        }
+        thread::sleep(interval);
+    }
+    Ok(())
+}
+
+fn parse_pattern_separator(separator: &str) -> Result<char> {
+    let mut chars = separator.chars();
+    let (Some(separator), None) = (chars.next(), chars.next()) else {
+        bail!("Pattern separator must be a single character");
+    };
+    // These already mean something in a pattern
+    if separator.is_ascii_alphanumeric() || ".=:#+-_{}$".contains(separator) {
+        bail!("Pattern separator can't be `{separator}`");
+    }
+    Ok(separator)
+}
+
+fn parse_interval(seconds: &str) -> Result<Duration> {
+    let seconds = seconds
+        .parse()
+        .map_err(|_| anyhow!("Could not interpret interval: {seconds}"))?;
+    Ok(Duration::try_from_secs_f64(seconds)?)
+}
+
+// Parse the range given to `--within`
+fn parse_window(window: &str) -> Result<Pattern> {
+    let window = Pattern::parse(window)?;
+    if window.descending {
+        bail!("The window can't have a negative step");
+    }
+    if window.refers_to_last() {
+        bail!("The window can't start at `$`");
+    }
+    Ok(window)
+}
+
+// Parse the range given to `--bytes`
+fn parse_byte_range(range: &str) -> Result<Pattern> {
+    let range = Pattern::parse(range)?;
+    if range.step != NonZeroUsize::MIN || range.descending {
+        bail!("Byte ranges can't have a step");
+    }
+    Ok(range)
+}
+
+// Parse a size in bytes, which may end with `k`, `m`, or `g` for KiB, MiB, or GiB
+fn parse_size(size: &str) -> Result<NonZeroUsize> {
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&size[..index], 1 << 10),
+        Some((index, 'm' | 'M')) => (&size[..index], 1 << 20),
+        Some((index, 'g' | 'G')) => (&size[..index], 1 << 30),
+        _ => (size, 1),
+    };
+    let number: usize = number
+        .parse()
+        .map_err(|_| anyhow!("Could not interpret size: {size}"))?;
+    number
+        .checked_mul(multiplier)
+        .and_then(NonZeroUsize::new)
+        .ok_or_else(|| anyhow!("Size must be between 1 byte and {} bytes", usize::MAX))
+}
+
+// Expand shell-style braces in a pattern list, as in `{1..3}` or `{1,5}..=9`
+fn expand_braces(patterns: &str, separator: char) -> Result<String> {
+    let mut expanded = Vec::new();
+    for pattern in split_outside_braces(patterns, separator) {
+        expanded.extend(expand_pattern(pattern)?);
+    }
+    Ok(expanded.join(&separator.to_string()))
+}
+
+// Split on `separator`, except where it's inside braces
+fn split_outside_braces(text: &str, separator: char) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    for (index, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            c if c == separator && depth == 0 => {
+                pieces.push(&text[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    pieces.push(&text[start..]);
+    pieces
+}
+
+// Expand the first pair of braces in a single pattern, then whatever braces are left in each
+// result
+fn expand_pattern(pattern: &str) -> Result<Vec<String>> {
+    let Some(open) = pattern.find('{') else {
+        return Ok(vec![pattern.into()]);
+    };
+    let mut depth = 0;
+    let close = pattern[open..]
+        .char_indices()
+        .find_map(|(index, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => return None,
+            }
+            (depth == 0).then_some(open + index)
+        })
+        .ok_or_else(|| anyhow!("Unclosed `{{` in pattern: {pattern}"))?;
+    let (prefix, body, suffix) = (
+        &pattern[..open],
+        &pattern[open + 1..close],
+        &pattern[close + 1..],
+    );
+
+    let alternatives = split_outside_braces(body, ',');
+    let alternatives = if alternatives.len() > 1 {
+        alternatives.into_iter().map(String::from).collect()
+    } else {
+        brace_sequence(body)?
+    };
+    let mut expanded = Vec::new();
+    for alternative in alternatives {
+        expanded.extend(expand_pattern(&format!("{prefix}{alternative}{suffix}"))?);
+    }
+    Ok(expanded)
+}
+
+// Expand a sequence like `1..3`, or `1..9..4` with a step, counting down if the end comes first
+fn brace_sequence(sequence: &str) -> Result<Vec<String>> {
+    let numbers = sequence
+        .split("..")
+        .map(parse_number)
+        .collect::<Result<Vec<_>>>()
+        .map_err(|_| anyhow!("Expected a list or sequence in braces: {{{sequence}}}"))?;
+    let (start, end, step) = match numbers[..] {
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step),
+        _ => bail!("Expected a list or sequence in braces: {{{sequence}}}"),
+    };
+    if step == 0 {
+        bail!("Step must not be zero: {{{sequence}}}");
+    }
+    let numbers = if start <= end {
+        (start..=end).step_by(step).collect::<Vec<_>>()
+    } else {
+        (end..=start).rev().step_by(step).collect()
+    };
+    Ok(numbers.iter().map(usize::to_string).collect())
+}
+
+// Read a pattern list, where newlines work the same as the separator
+fn read_patterns(mut fin: impl Read, separator: char) -> Result<String> {
+    let mut patterns = String::new();
+    fin.read_to_string(&mut patterns)?;
+    let patterns = patterns
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(&separator.to_string());
+    if patterns.is_empty() {
+        bail!("No patterns given on stdin");
+    }
+    Ok(patterns)
+}
+
+// Read the whole input and put its lines in reverse order
+fn reverse_lines(fin: impl Read, options: &Options) -> Result<Vec<u8>> {
+    let mut fin = BufReader::new(fin);
+    let mut buf = Vec::new();
+    let mut lines = Vec::new();
+    while read_line_or_skip(
+        &mut fin,
+        &mut buf,
+        options,
+        lines.len() + 1,
+        &options.diagnostics,
+    )? {
+        lines.push(trim_terminator(&buf).to_vec());
+    }
+
+    let mut reversed = Vec::new();
+    for line in lines.iter().rev() {
+        reversed.extend_from_slice(line);
+        reversed.push(b'\n');
+    }
+    Ok(reversed)
+}
+
+// Describe the input as a whole rather than selecting from it
+fn write_summary(fin: impl Read, mut fout: impl Write, options: &Options) -> Result<()> {
+    let mut lines = 0;
+    let mut bytes = 0;
+    let mut longest_line = 0;
+    let mut blank_lines = 0;
+
+    let mut fin = BufReader::new(fin);
+    let mut buf = Vec::new();
+    while read_line_or_skip(&mut fin, &mut buf, options, lines + 1, &options.diagnostics)? {
+        let line = trim_terminator(&buf);
+        lines += 1;
+        bytes += buf.len();
+        longest_line = longest_line.max(line.len());
+        if line.is_empty() {
+            blank_lines += 1;
+        }
+    }
+
+    writeln!(fout, "lines: {lines}")?;
+    writeln!(fout, "bytes: {bytes}")?;
+    writeln!(fout, "longest line: {longest_line}")?;
+    writeln!(fout, "blank lines: {blank_lines}")?;
+    fout.flush()?;
+    Ok(())
+}
+
+// Copy out the bytes in `range` rather than lines, last to first if `reverse` is set
+// Reversing goes byte by byte, so multi-byte values come out with their bytes swapped around
+fn write_bytes(
+    mut fin: impl Read,
+    mut fout: impl Write,
+    range: &Pattern,
+    reverse: bool,
+    options: &Options,
+) -> Result<()> {
+    let start = range.start.map_or(0, |start| start.get() - 1) as u64;
+    io::copy(&mut fin.by_ref().take(start), &mut io::sink())?;
+    let length = range.end.map_or(u64::MAX, |end| end.get() as u64 - start);
+    let mut fin = fin.take(length);
+
+    let copied = if reverse {
+        // Nothing can be written until the last byte has been read
+        let mut bytes = Vec::new();
+        fin.read_to_end(&mut bytes)?;
+        bytes.reverse();
+        fout.write_all(&bytes)?;
+        bytes.len() as u64
+    } else {
+        io::copy(&mut fin, &mut fout)?
+    };
+    if copied > 0 {
+        options.selected_any.set(true);
+    }
+    fout.flush()?;
+    Ok(())
+}
+
+impl Options {
+    // Convert a raw line into what we'll actually be working with
+    #[cfg_attr(not(feature = "encoding"), allow(unused_variables))]
+    fn decode<'a>(&self, line: &'a [u8], number: NonZeroUsize) -> Result<Cow<'a, [u8]>> {
+        #[cfg(feature = "encoding")]
+        if let Some(transcoding) = &self.transcoding {
+            return Ok(match transcoding.decode(line, number)? {
+                Cow::Borrowed(line) => Cow::Borrowed(line.as_bytes()),
+                Cow::Owned(line) => Cow::Owned(line.into_bytes()),
+            });
+        }
+        Ok(Cow::Borrowed(line))
+    }
+
+    // True if a selected line should be left out of the output anyway
+    fn skips(&self, line: &[u8]) -> bool {
+        self.skip.is_some_and(|skip| skip.matches(line))
+            || self.select_empty.is_some_and(|empty| !empty.matches(line))
+            || self.min_content_length.is_some_and(|min| line.len() < min)
+            || self.max_content_length.is_some_and(|max| line.len() > max)
+            || (self.ascii_only && !line.is_ascii())
+            || self
+                .column_filter
+                .as_ref()
+                .is_some_and(|filter| !filter.passes(line))
+    }
+
+    // What separates the patterns in a pattern list
+    fn pattern_separator(&self) -> char {
+        self.pattern_separator.unwrap_or(',')
+    }
+
+    // True if a line's contents put it in the sample
+    fn is_sampled(&self, line: &[u8]) -> bool {
+        if self.hash_sample.is_none() && self.hash_prefix.is_none() {
+            return true;
+        }
+        let hash = fnv1a(line);
+        self.hash_sample
+            .is_none_or(|sample| hash.is_multiple_of(sample.get() as u64))
+            && self
+                .hash_prefix
+                .as_ref()
+                .is_none_or(|prefix| format!("{:016x}", mix(hash)).starts_with(prefix.as_str()))
+    }
+}
+
+fn parse_hash_prefix(prefix: &str) -> Result<String> {
+    if prefix.is_empty() || prefix.len() > 16 || !prefix.bytes().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Hash prefix must be 1 to 16 hex digits, got: {prefix}");
+    }
+    Ok(prefix.to_ascii_lowercase())
+}
+
+// 64-bit FNV-1a. Unlike std's hashers, this is guaranteed to be stable between runs and builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+// MurmurHash3's finalizer. FNV-1a barely changes the high bits of a hash for short, similar
+// lines, so they need spreading out before they can be used as a prefix
+fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+// Where to write copies of the output, and the output so far
+struct Repeat {
+    count: NonZeroUsize,
+    sink: RefCell<Box<dyn Write>>,
+    selection: RefCell<Vec<u8>>,
+}
+
+impl Repeat {
+    // Write out the copies, once there's nothing more to add to the output
+    fn finish(&self) -> Result<()> {
+        let selection = self.selection.borrow();
+        let mut sink = self.sink.borrow_mut();
+        for _ in 0..self.count.get() {
+            sink.write_all(&selection)?;
+        }
+        sink.flush()?;
+        Ok(())
+    }
+}
+
+// Where to write the selection along with the lines around it
+struct Context {
+    // How many lines to include before and after each selected line
+    lines: usize,
+    sink: RefCell<Box<dyn Write>>,
+}
+
+// Where warnings and other messages about the run go: stderr, unless `--errors-to` gives a file
+struct Diagnostics(RefCell<Box<dyn Write>>);
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self(RefCell::new(Box::new(io::stderr())))
+    }
+}
+
+// Shared through `Options`, so it's written to through a shared reference, like `&File`
+impl Write for &Diagnostics {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+// Finds the lines around each shown line, merging groups that overlap
+// Each line is handed to `write` along with whether there's a gap before it, where grep
+// would write `--`
+struct ContextWindow {
+    // How many lines to include before and after each shown line
+    lines: usize,
+    // Recent lines that weren't shown, which may come before the next one that is
+    before: VecDeque<(NonZeroUsize, Vec<u8>)>,
+    // How many more lines to write after the last one shown
+    after: usize,
+    last_written: Option<NonZeroUsize>,
+}
+
+impl ContextWindow {
+    fn new(lines: usize) -> Self {
+        Self {
+            lines,
+            before: VecDeque::new(),
+            after: 0,
+            last_written: None,
+        }
+    }
+
+    fn push(
+        &mut self,
+        number: NonZeroUsize,
+        line: &[u8],
+        shown: bool,
+        mut write: impl FnMut(NonZeroUsize, &[u8], bool) -> Result<()>,
+    ) -> Result<()> {
+        let mut write = |number: NonZeroUsize, line: &[u8]| {
+            let gap = self
+                .last_written
+                .is_some_and(|last| last.get() + 1 < number.get());
+            self.last_written = Some(number);
+            write(number, line, gap)
+        };
+        if shown {
+            for (number, line) in self.before.drain(..) {
+                write(number, &line)?;
+            }
+            write(number, line)?;
+            self.after = self.lines;
+        } else if self.after > 0 {
+            self.after -= 1;
+            write(number, line)?;
+        } else if self.lines > 0 {
+            if self.before.len() == self.lines {
+                self.before.pop_front();
+            }
+            self.before.push_back((number, line.to_vec()));
+        }
+        Ok(())
+    }
+}
+
+// Takes selected lines in order, holding back any that might not end up being shown
+struct Selection<'a, W: Write> {
+    output: Output<'a, W>,
+    // Selected lines that might end up in the tail window
+    tail_window: VecDeque<(NonZeroUsize, Vec<u8>)>,
+    last_unique: Option<LastUnique>,
+    // Selected lines waiting to be sorted
+    unsorted: Vec<(NonZeroUsize, Vec<u8>)>,
+}
+
+impl<'a, W: Write> Selection<'a, W> {
+    fn new(fout: W, options: &'a Options) -> Self {
+        Self {
+            output: Output::new(fout, options),
+            tail_window: VecDeque::new(),
+            last_unique: options.last_unique.map(LastUnique::new),
+            unsorted: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, number: NonZeroUsize, line: &[u8]) -> Result<()> {
+        if let Some(tail) = &self.output.options.tail {
+            if self.tail_window.len() == tail.window() {
+                self.tail_window.pop_front();
+            }
+            self.tail_window.push_back((number, line.to_vec()));
+        } else if let Some(last_unique) = &mut self.last_unique {
+            last_unique.push(number, line);
+        } else if self.output.options.sort.is_some() {
+            self.unsorted.push((number, line.to_vec()));
+        } else {
+            self.output.write_line(number, line)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        if let Some(tail) = &self.output.options.tail {
+            let end = self.tail_window.len().saturating_sub(tail.offset);
+            for (number, line) in self.tail_window.range(..end) {
+                self.output.write_line(*number, line)?;
+            }
+        }
+        if let Some(last_unique) = &self.last_unique {
+            for (number, line) in &last_unique.lines {
+                self.output.write_line(*number, line)?;
+            }
+        }
+        if let Some(sort) = self.output.options.sort {
+            sort.apply(&mut self.unsorted);
+            for (number, line) in &self.unsorted {
+                self.output.write_line(*number, line)?;
+            }
+        }
+        self.output.finish()
+    }
+}
+
+// Writes selected lines, keeping track of any state needed to display them
+struct Output<'a, W: Write> {
+    fout: W,
+    options: &'a Options,
+    // How many non-blank lines have been written
+    nonblank_count: usize,
+    // How many lines have been left out with `--skip`
+    skipped: usize,
+    // How many lines have come up to be written, counted for `--nth`
+    occurrences: usize,
+    // The number of the last line written
+    last_number: Option<NonZeroUsize>,
+    // How many bytes have been counted with `--count-bytes`
+    byte_count: usize,
+    // How many matches have been counted with `--count-matches`
+    match_count: usize,
+    // Every different line seen with `--count-distinct`
+    distinct: HashSet<Vec<u8>>,
+    // How many lines have been counted with `--count-nonempty`
+    line_count: usize,
+    // Every different line written with `--unique`
+    seen: HashSet<Vec<u8>>,
+    // Lines waiting to be written as JSON
+    json_lines: Vec<String>,
+    // With `--uniq`, the last line, its terminator, and how many times in a row it's come up
+    // so far
+    run: Option<(NonZeroUsize, Vec<u8>, Vec<u8>, usize)>,
+    // Ends lines that don't bring their own terminator
+    terminator: &'static [u8],
+    // Rows waiting to be lined up with `--table`
+    table_rows: Vec<(String, String)>,
+    // With `--one-line`, true once the line has been started
+    line_open: bool,
+    // With `--collapse-ranges`, the runs of consecutive line numbers written so far
+    runs: Vec<(usize, usize)>,
+    // With `--hunk-headers`, the first line number of the current block, how many lines it has,
+    // and what's been written for it, which waits until the block's size is known
+    hunk: Option<(usize, usize, Vec<u8>)>,
+}
+
+impl<'a, W: Write> Output<'a, W> {
+    fn new(fout: W, options: &'a Options) -> Self {
+        Self {
+            fout,
+            options,
+            nonblank_count: 0,
+            skipped: 0,
+            occurrences: 0,
+            last_number: None,
+            byte_count: 0,
+            match_count: 0,
+            distinct: HashSet::new(),
+            line_count: 0,
+            seen: HashSet::new(),
+            json_lines: Vec::new(),
+            run: None,
+            terminator: b"\n",
+            table_rows: Vec::new(),
+            line_open: false,
+            runs: Vec::new(),
+            hunk: None,
+        }
+    }
+
+    // With `--line-terminator-auto`, lines come with their own terminators, if they have one
+    fn write_line(&mut self, number: NonZeroUsize, line: &[u8]) -> Result<()> {
+        let (line, terminator) = if self.options.line_terminator_auto {
+            let content = trim_terminator(line);
+            match &line[content.len()..] {
+                [] => (content, self.terminator),
+                terminator => (content, terminator),
+            }
+        } else {
+            (line, &b"\n"[..])
+        };
+        let Some(line) = self.transform(number, line)? else {
+            return Ok(());
+        };
+        #[cfg(feature = "base64")]
+        let terminator = match &self.options.base64 {
+            Some(base64) if base64.concat => &b""[..],
+            _ => terminator,
+        };
+        if self.options.uniq.is_none() {
+            return self.emit(number, &line, terminator, 1);
+        }
+
+        // A line can't be written until we know how many times it repeats
+        if let Some((_, last, _, count)) = &mut self.run
+            && last[..] == line[..]
+        {
+            *count += 1;
+            return Ok(());
+        }
+        self.end_run()?;
+        self.run = Some((number, line.into_owned(), terminator.to_vec(), 1));
+        Ok(())
+    }
+
+    // Write the line held back by `--uniq`, if there is one
+    fn end_run(&mut self) -> Result<()> {
+        if let Some((number, last, terminator, count)) = self.run.take() {
+            self.emit(number, &last, &terminator, count)?;
+        }
+        Ok(())
+    }
+
+    // Write the block held back by `--hunk-headers`, after a header saying which lines it covers
+    fn end_hunk(&mut self) -> Result<()> {
+        if let Some((start, count, lines)) = self.hunk.take() {
+            let start = start.saturating_add(self.options.number_shift);
+            let header = self.paint(|theme| theme.separator, &format!("@@ -{start},{count} @@"));
+            self.write_all(format!("{header}\n").as_bytes())?;
+            self.write_all(&lines)?;
+        }
+        Ok(())
+    }
+
+    // Write a line that's been through every change it's going to get, after it came up `count`
+    // times in a row
+    fn emit(
+        &mut self,
+        number: NonZeroUsize,
+        line: &[u8],
+        terminator: &[u8],
+        count: usize,
+    ) -> Result<()> {
+        if self.options.unique {
+            if self.seen.contains(line) {
+                return Ok(());
+            }
+            self.seen.insert(line.to_vec());
+        }
+        if let Some(nth) = &self.options.nth {
+            self.occurrences += 1;
+            if !nth.is_included(NonZeroUsize::new(self.occurrences).expect("Overflow")) {
+                return Ok(());
+            }
+        }
+        if self.skipped < self.options.offset {
+            self.skipped += 1;
+            return Ok(());
+        }
+        if self.options.at_limit() {
+            return Ok(());
+        }
+        #[cfg(feature = "encoding")]
+        let encoded;
+        #[cfg(feature = "encoding")]
+        let written = if let Some(transcoding) = &self.options.transcoding
+            && transcoding.reencode
+        {
+            encoded = transcoding.encode(line);
+            &encoded[..]
+        } else {
+            line
+        };
+        #[cfg(not(feature = "encoding"))]
+        let written = line;
+
+        // Lines next to each other in the input, in either direction, are in the same group
+        let gap = self.options.group_by_gap
+            && self
+                .last_number
+                .is_some_and(|last| last.get().abs_diff(number.get()) > 1);
+        let rendered = self.render(number, written, terminator, count);
+        if let Some(budget) = self.options.byte_budget {
+            // Everything written for the line counts, decorations and all. A line that's only
+            // being counted or held for the end counts as itself
+            let mut size = match &rendered {
+                Some(rendered) => rendered.len(),
+                None => line.len() + terminator.len(),
+            };
+            size += usize::from(gap);
+            if self.options.one_line && !self.line_open {
+                size += self.terminator.len();
+            }
+            // A line that doesn't fit still uses up the budget, so nothing after it is written
+            // either, even a line short enough to fit
+            let used = self.options.budget_used.get().saturating_add(size);
+            self.options.budget_used.set(used);
+            if used > budget {
+                return Ok(());
+            }
+        }
+        if self.options.group_by_gap {
+            self.last_number = Some(number);
+        }
+        if gap {
+            self.write_all(b"\n")?;
+        }
+        self.options.selected_any.set(true);
+        let emitted = self.options.emitted.get();
+        self.options.emitted.set(emitted + 1);
+        if let Some(chunk) = &self.options.chunk
+            && emitted.is_multiple_of(chunk.size.get())
+        {
+            self.start_chunk(chunk, emitted / chunk.size)?;
+        }
+        if self.options.collapse_ranges.is_some() {
+            match self.runs.last_mut() {
+                Some((_, end)) if number.get() == *end + 1 => *end = number.get(),
+                Some((_, end)) if number.get() == *end => {}
+                _ => self.runs.push((number.get(), number.get())),
+            }
+        }
+        if self.options.hunk_headers {
+            match &mut self.hunk {
+                Some((start, count, _)) if number.get() == *start + *count => *count += 1,
+                _ => {
+                    self.end_hunk()?;
+                    self.hunk = Some((number.get(), 1, Vec::new()));
+                }
+            }
+        }
+
+        let Some(rendered) = rendered else {
+            self.record(number, line, written, terminator);
+            return Ok(());
+        };
+        self.write_all(&rendered)?;
+        // With `--one-line`, the only terminator is written at the end
+        if self.options.one_line {
+            self.line_open = true;
+        }
+        if self.options.unbuffered {
+            self.fout.flush()?;
+        }
+        Ok(())
+    }
+
+    // Keep track of a line that isn't written as it comes, but is counted or written at the end.
+    // `written` is the line as it would be written, which may be in another encoding
+    fn record(&mut self, number: NonZeroUsize, line: &[u8], written: &[u8], terminator: &[u8]) {
+        if let Some(text) = &self.options.count_matches {
+            self.match_count += count_occurrences(line, text.as_bytes());
+        } else if self.options.count_distinct {
+            if !self.distinct.contains(line) {
+                self.distinct.insert(line.to_vec());
+            }
+        } else if self.options.count_lines {
+            self.line_count += 1;
+        } else if self.options.json.is_some() {
+            // JSON is written all at once at the end, so the count can come first
+            self.json_lines
+                .push(String::from_utf8_lossy(line).into_owned());
+        } else if let Some(counting) = self.options.count_bytes {
+            self.byte_count += written.len();
+            if counting == ByteCounting::WithTerminators {
+                self.byte_count += terminator.len();
+            }
+        } else {
+            // The numbers can't be lined up until we know how wide the widest one is
+            self.table_rows.push((
+                self.format_number(number.get()),
+                String::from_utf8_lossy(written).into_owned(),
+            ));
+        }
+    }
+
+    // Lay out everything that's written for a line, after it came up `count` times in a row.
+    // Returns None if the line isn't written now, but kept track of by `record`
+    fn render(
+        &mut self,
+        number: NonZeroUsize,
+        line: &[u8],
+        terminator: &[u8],
+        count: usize,
+    ) -> Option<Vec<u8>> {
+        if self.options.count_matches.is_some()
+            || self.options.count_distinct
+            || self.options.count_lines
+        {
+            return None;
+        }
+        if self.options.numbers_only {
+            let number = self.format_number(number.get());
+            return Some(format!("{number}\n").into_bytes());
+        }
+        if self.options.json.is_some()
+            || self.options.count_bytes.is_some()
+            || (self.options.format.is_none() && self.options.table.is_some())
+        {
+            return None;
+        }
+
+        let mut rendered = Vec::new();
+        if let Some(format) = &self.options.format {
+            for piece in &format.pieces {
+                match piece {
+                    FormatPiece::Literal(literal) => rendered.extend_from_slice(literal.as_bytes()),
+                    FormatPiece::Number => {
+                        let number = self.format_number(number.get());
+                        rendered.extend_from_slice(number.as_bytes());
+                    }
+                    FormatPiece::Line => rendered.extend_from_slice(line),
+                }
+            }
+            rendered.extend_from_slice(terminator);
+            return Some(rendered);
+        }
+
+        if self.options.uniq == Some(Uniq::Count) {
+            rendered.extend_from_slice(format!("{count:>7} ").as_bytes());
+        }
+        if let Some(numbering) = self.options.number_nonblank {
+            if !line.is_empty() {
+                self.nonblank_count += 1;
+                let prefix = match numbering {
+                    NonblankNumbering::Count => {
+                        self.options.number_radix.format(self.nonblank_count)
+                    }
+                    NonblankNumbering::LineNumber => self.format_number(number.get()),
+                };
+                let prefix = self.paint(|theme| theme.number, &prefix);
+                rendered.extend_from_slice(format!("{prefix}\t").as_bytes());
+            }
+        } else if self.options.show_line_number {
+            let shown = self.paint(|theme| theme.number, &self.format_number(number.get()));
+            rendered.extend_from_slice(format!("{shown}\t").as_bytes());
+            if let Some(map) = &self.options.renumber_map {
+                // Lines the map doesn't know about get a placeholder, so the columns still line up
+                let mapped = map.get(&number.get()).map_or_else(
+                    || "-".into(),
+                    |&mapped| self.options.number_radix.format(mapped),
+                );
+                let mapped = self.paint(|theme| theme.number, &mapped);
+                rendered.extend_from_slice(format!("{mapped}\t").as_bytes());
+            }
+        }
+
+        if let Some(wrap) = &self.options.wrap {
+            self.wrap_into(&mut rendered, line, terminator, wrap);
+        } else {
+            rendered.extend_from_slice(line);
+        }
+        // With `--one-line`, the only terminator is written at the end
+        if !self.options.one_line {
+            rendered.extend_from_slice(terminator);
+        }
+        Some(rendered)
+    }
+
+    // Lay out a line in pieces of at most `wrap.width` characters, each on its own line. Pieces
+    // after the first line up with the first one, past any count or number in front of it
+    fn wrap_into(&self, rendered: &mut Vec<u8>, line: &[u8], terminator: &[u8], wrap: &Wrap) {
+        let mut continuation = String::new();
+        if self.options.uniq == Some(Uniq::Count) {
+            continuation.push_str(&" ".repeat(8));
+        }
+        // Empty lines aren't numbered with `-b`, but they never need wrapping either
+        if self.options.number_nonblank.is_some() {
+            continuation.push('\t');
+        } else if self.options.show_line_number {
+            continuation.push('\t');
+            if self.options.renumber_map.is_some() {
+                continuation.push('\t');
+            }
+        }
+        continuation.push_str(&" ".repeat(wrap.indent));
+
+        let mut rest = line;
+        loop {
+            let piece = truncate_chars(rest, wrap.width.get());
+            rendered.extend_from_slice(piece);
+            rest = &rest[piece.len()..];
+            if rest.is_empty() {
+                return;
+            }
+            rendered.extend_from_slice(terminator);
+            rendered.extend_from_slice(continuation.as_bytes());
+        }
+    }
+
+    // Show a line number as it's displayed, which may not count from 1
+    fn format_number(&self, number: usize) -> String {
+        self.options
+            .number_radix
+            .format(number.saturating_add(self.options.number_shift))
+    }
+
+    // Mark the start of a labeled pattern's lines
+    fn write_label(&mut self, label: &str) -> Result<()> {
+        // The label goes after any lines that came before it
+        self.end_run()?;
+        let label = self.paint(|theme| theme.label, &format!("==> {label} <=="));
+        self.write_all(format!("{label}\n").as_bytes())
+    }
+
+    // Color text with a color from the theme, if the output is colored
+    fn paint(&self, color: impl Fn(&Theme) -> u8, text: &str) -> String {
+        match &self.options.theme {
+            Some(theme) => format!("\x1b[{}m{text}\x1b[0m", color(theme)),
+            None => text.into(),
+        }
+    }
+
+    // Begin the `index`th chunk of output, either by opening its file or by separating it from
+    // the last one
+    fn start_chunk(&mut self, chunk: &Chunk, index: usize) -> Result<()> {
+        if let Some(prefix) = &chunk.prefix {
+            let mut file = chunk.file.borrow_mut();
+            if let Some(file) = file.as_mut() {
+                file.flush()?;
+            }
+            *file = Some(BufWriter::new(File::create(format!("{prefix}{index}"))?));
+        } else if index > 0 {
+            self.write_all(b"--\n")?;
+        }
+        Ok(())
+    }
+
+    // Mark a gap between groups of lines with `--`, like grep
+    fn write_separator(&mut self) -> Result<()> {
+        self.end_run()?;
+        // Only text output has anywhere to put it
+        if self.options.json.is_some()
+            || self.options.count_bytes.is_some()
+            || self.options.count_matches.is_some()
+            || self.options.count_distinct
+            || self.options.count_lines
+            || self.options.one_line
+            || self.options.hunk_headers
+        {
+            return Ok(());
+        }
+        let separator = self.paint(|theme| theme.separator, "--");
+        self.write_all(format!("{separator}\n").as_bytes())
+    }
+
+    // Write to the output, and mirror to the tee file if there is one
+    // When chunks go to their own files, the current chunk's file stands in for the output
+    fn write_all(&mut self, bytes: &[u8]) -> Result<()> {
+        if let Some((_, _, lines)) = &mut self.hunk {
+            lines.extend_from_slice(bytes);
+            return Ok(());
+        }
+        // The byte order mark goes before whatever's written first, from any file
+        if self.options.bom.take() {
+            self.write_all(b"\xef\xbb\xbf")?;
+        }
+        if let Some(chunk) = &self.options.chunk
+            && let Some(file) = chunk.file.borrow_mut().as_mut()
+        {
+            file.write_all(bytes)?;
+        } else {
+            self.fout.write_all(bytes)?;
+        }
+        if let Some(repeat) = &self.options.repeat {
+            repeat.selection.borrow_mut().extend_from_slice(bytes);
+        }
+        if let Some(tee) = &self.options.tee {
+            tee.borrow_mut().write_all(bytes)?;
+        }
+        Ok(())
+    }
+
+    // Change what a selected line looks like before it's written. `None` means the line is to be
+    // left out after all
+    #[cfg_attr(
+        not(any(feature = "base64", feature = "pretty-json")),
+        allow(unused_variables)
+    )]
+    fn transform<'b>(&self, number: NonZeroUsize, line: &'b [u8]) -> Result<Option<Cow<'b, [u8]>>> {
+        let mut line = Cow::Borrowed(line);
+        if self.options.strip_non_ascii && !line.is_ascii() {
+            line = Cow::Owned(line.iter().copied().filter(u8::is_ascii).collect());
+        }
+        if let Some(fields) = &self.options.fields {
+            line = Cow::Owned(fields.select(&line));
+        }
+        if let Some(trim) = self.options.trim {
+            line = match line {
+                Cow::Borrowed(line) => Cow::Borrowed(trim.apply(line)),
+                Cow::Owned(line) => Cow::Owned(trim.apply(&line).to_vec()),
+            };
+        }
+        #[cfg(feature = "base64")]
+        if let Some(base64) = &self.options.base64 {
+            match decode_base64(&line) {
+                Ok(decoded) => line = Cow::Owned(decoded),
+                Err(err) => match base64.on_error {
+                    Base64ErrorPolicy::Fail => bail!("Line {number} is not valid base64: {err}"),
+                    Base64ErrorPolicy::Skip => return Ok(None),
+                    Base64ErrorPolicy::Keep => {}
+                },
+            }
+        }
+        #[cfg(feature = "pretty-json")]
+        if let Some(policy) = self.options.pretty_json {
+            match pretty_json(&line) {
+                Ok(pretty) => line = Cow::Owned(pretty),
+                Err(err) if policy == InvalidJsonPolicy::Fail => {
+                    bail!("Line {number} is not valid JSON: {err}")
+                }
+                Err(_) => {}
+            }
+        }
+        if let Some(substitution) = &self.options.substitution
+            && let Some(replaced) = substitution.apply(&line)
+        {
+            line = Cow::Owned(replaced);
+        }
+        if let Some(replacement) = &self.options.tab_replacement
+            && line.contains(&b'\t')
+        {
+            let mut replaced = Vec::with_capacity(line.len());
+            for &byte in line.iter() {
+                if byte == b'\t' {
+                    replaced.extend_from_slice(replacement.as_bytes());
+                } else {
+                    replaced.push(byte);
+                }
+            }
+            line = Cow::Owned(replaced);
+        }
+        if let Some(width) = self.options.truncate {
+            line = match line {
+                Cow::Borrowed(line) => Cow::Borrowed(truncate_chars(line, width.get())),
+                Cow::Owned(line) => Cow::Owned(truncate_chars(&line, width.get()).to_vec()),
+            };
+        }
+        Ok(Some(line))
+    }
+
+    // True once every occurrence `--nth` could show has come up
+    fn past_nth(&self) -> bool {
+        self.options
+            .nth
+            .as_ref()
+            .and_then(|nth| nth.end)
+            .is_some_and(|end| self.occurrences >= end.get())
+    }
+
+    // Write anything that can only be known once every line has been seen, then flush
+    fn finish(mut self) -> Result<()> {
+        self.end_run()?;
+        self.end_hunk()?;
+        if let Some(start) = self.options.nth.as_ref().and_then(|nth| nth.start)
+            && start.get() > self.occurrences
+            && !self.options.stop.load(Ordering::Relaxed)
+        {
+            writeln!(
+                &self.options.diagnostics,
+                "Warning: asked for occurrence {start}, but only {} lines came up",
+                self.occurrences
+            )?;
+        }
+        if self.line_open {
+            self.write_all(self.terminator)?;
+        }
+        if let Some(sink) = &self.options.collapse_ranges {
+            let runs = self
+                .runs
+                .iter()
+                .map(|(start, end)| format!("{start}-{end}"))
+                .collect::<Vec<_>>();
+            let runs = if runs.is_empty() {
+                "none".into()
+            } else {
+                runs.join(", ")
+            };
+            let mut sink = sink.borrow_mut();
+            writeln!(sink, "emitted: {runs}")?;
+            sink.flush()?;
+        }
+        if let Some(table) = self.options.table {
+            let width = self
+                .table_rows
+                .iter()
+                .map(|(number, _)| number.len())
+                .max()
+                .unwrap_or(0);
+            for (number, line) in mem::take(&mut self.table_rows) {
+                let mut row = format!("{number:>width$} | {line}");
+                if let Some(max_width) = table.max_width
+                    && let Some((end, _)) = row.char_indices().nth(max_width.get())
+                {
+                    row.truncate(end);
+                }
+                row.push('\n');
+                self.write_all(row.as_bytes())?;
+            }
+        }
+        if self.options.count_bytes.is_some() {
+            self.write_all(format!("{}\n", self.byte_count).as_bytes())?;
+        }
+        if self.options.count_matches.is_some() {
+            self.write_all(format!("{}\n", self.match_count).as_bytes())?;
+        }
+        if self.options.count_distinct {
+            self.write_all(format!("{}\n", self.distinct.len()).as_bytes())?;
+        }
+        if self.options.count_lines {
+            self.write_all(format!("{}\n", self.line_count).as_bytes())?;
+        }
+        if let Some(json) = self.options.json {
+            let lines = self
+                .json_lines
+                .iter()
+                .map(|line| json_string(line))
+                .collect::<Vec<_>>()
+                .join(",");
+            let json = match json {
+                JsonOutput::Array => format!("[{lines}]\n"),
+                JsonOutput::Meta => {
+                    format!(
+                        "{{\"count\":{},\"lines\":[{lines}]}}\n",
+                        self.json_lines.len()
+                    )
+                }
+            };
+            self.write_all(json.as_bytes())?;
+        }
+        self.fout.flush()?;
+        if let Some(tee) = &self.options.tee {
+            tee.borrow_mut().flush()?;
+        }
+        if let Some(chunk) = &self.options.chunk
+            && let Some(file) = chunk.file.borrow_mut().as_mut()
+        {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Quote a string for JSON, escaping anything that needs it
+fn json_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if u32::from(c) < 0x20 => {
+                quoted.push_str(&format!("\\u{:04x}", u32::from(c)));
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Lay out a JSON value over several lines, indented two spaces per level, checking that it's
+// valid along the way
+#[cfg(feature = "pretty-json")]
+fn pretty_json(json: &[u8]) -> Result<Vec<u8>> {
+    str::from_utf8(json)?;
+    let mut prettifier = JsonPrettifier {
+        json,
+        position: 0,
+        out: Vec::with_capacity(json.len() * 2),
+    };
+    prettifier.value(0)?;
+    prettifier.skip_whitespace();
+    if prettifier.position != json.len() {
+        return Err(prettifier.error());
+    }
+    Ok(prettifier.out)
+}
+
+#[cfg(feature = "pretty-json")]
+struct JsonPrettifier<'a> {
+    json: &'a [u8],
+    // How far into `json` we've read
+    position: usize,
+    out: Vec<u8>,
+}
+
+#[cfg(feature = "pretty-json")]
+impl JsonPrettifier<'_> {
+    fn error(&self) -> anyhow::Error {
+        match self.peek() {
+            Some(byte) => anyhow!("Unexpected `{}` at byte {}", byte as char, self.position),
+            None => anyhow!("Unexpected end of input"),
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.json.get(self.position).copied()
+    }
+
+    // Step past `byte` if it's next
+    fn eat(&mut self, byte: u8) -> bool {
+        let next = self.peek() == Some(byte);
+        if next {
+            self.position += 1;
+        }
+        next
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.position += 1;
+        }
+    }
+
+    fn newline(&mut self, depth: usize) {
+        self.out.push(b'\n');
+        self.out.resize(self.out.len() + depth * 2, b' ');
+    }
+
+    fn value(&mut self, depth: usize) -> Result<()> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.container(depth, b'}'),
+            Some(b'[') => self.container(depth, b']'),
+            Some(b'"') => self.string(),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => {
+                let rest = &self.json[self.position..];
+                let Some(literal) = [&b"true"[..], b"false", b"null"]
+                    .into_iter()
+                    .find(|literal| rest.starts_with(literal))
+                else {
+                    return Err(self.error());
+                };
+                self.position += literal.len();
+                self.out.extend_from_slice(literal);
+                Ok(())
+            }
+        }
+    }
+
+    // An object or array, put one member per line unless it's empty
+    fn container(&mut self, depth: usize, close: u8) -> Result<()> {
+        self.out.push(self.json[self.position]);
+        self.position += 1;
+        self.skip_whitespace();
+        if self.eat(close) {
+            self.out.push(close);
+            return Ok(());
+        }
+        loop {
+            self.newline(depth + 1);
+            if close == b'}' {
+                self.skip_whitespace();
+                if self.peek() != Some(b'"') {
+                    return Err(self.error());
+                }
+                self.string()?;
+                self.skip_whitespace();
+                if !self.eat(b':') {
+                    return Err(self.error());
+                }
+                self.out.extend_from_slice(b": ");
+            }
+            self.value(depth + 1)?;
+            self.skip_whitespace();
+            if self.eat(b',') {
+                self.out.push(b',');
+            } else if self.eat(close) {
+                break;
+            } else {
+                return Err(self.error());
+            }
+        }
+        self.newline(depth);
+        self.out.push(close);
+        Ok(())
+    }
+
+    // Strings are copied as they are, escapes and all
+    fn string(&mut self) -> Result<()> {
+        let start = self.position;
+        self.position += 1;
+        loop {
+            match self.peek() {
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.position += 1;
+                    let hex = self.json.get(self.position + 1..self.position + 5);
+                    match self.peek() {
+                        Some(b'"' | b'\\' | b'/' | b'b' | b'f' | b'n' | b'r' | b't') => {
+                            self.position += 1
+                        }
+                        Some(b'u')
+                            if hex.is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) =>
+                        {
+                            self.position += 5
+                        }
+                        _ => return Err(self.error()),
+                    }
+                }
+                None | Some(0..0x20) => return Err(self.error()),
+                Some(_) => self.position += 1,
+            }
+        }
+        self.position += 1;
+        self.out.extend_from_slice(&self.json[start..self.position]);
+        Ok(())
+    }
+
+    fn number(&mut self) -> Result<()> {
+        let start = self.position;
+        self.eat(b'-');
+        // Leading zeros aren't allowed, so a 0 can't be followed by more digits
+        if !self.eat(b'0') && self.digits() == 0 {
+            return Err(self.error());
+        }
+        if self.eat(b'.') && self.digits() == 0 {
+            return Err(self.error());
+        }
+        if self.eat(b'e') || self.eat(b'E') {
+            if !self.eat(b'+') {
+                self.eat(b'-');
+            }
+            if self.digits() == 0 {
+                return Err(self.error());
+            }
+        }
+        self.out.extend_from_slice(&self.json[start..self.position]);
+        Ok(())
+    }
+
+    // Step past a run of digits, returning how many there were
+    fn digits(&mut self) -> usize {
+        let start = self.position;
+        while self.peek().is_some_and(|byte| byte.is_ascii_digit()) {
+            self.position += 1;
+        }
+        self.position - start
+    }
+}
+
+// Layout of each output line, parsed from a template like `{n}: {line}`
+#[derive(Debug, Clone, PartialEq)]
+struct Format {
+    pieces: Vec<FormatPiece>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum FormatPiece {
+    Literal(String),
+    // The line number
+    Number,
+    // The line itself
+    Line,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    // Braces are escaped by doubling them, as in Rust's `format!`
+    fn from_str(template: &str) -> Result<Self> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(c) = rest.chars().next() {
+            if let Some(tail) = rest.strip_prefix("{{") {
+                literal.push('{');
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix("}}") {
+                literal.push('}');
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix('{') {
+                let Some((name, tail)) = tail.split_once('}') else {
+                    bail!("Unclosed `{{` in format: {template}");
+                };
+                let piece = match name {
+                    "n" => FormatPiece::Number,
+                    "line" => FormatPiece::Line,
+                    _ => bail!("Unknown placeholder in format: {{{name}}}"),
+                };
+                if !literal.is_empty() {
+                    pieces.push(FormatPiece::Literal(std::mem::take(&mut literal)));
+                }
+                pieces.push(piece);
+                rest = tail;
+            } else if c == '}' {
+                bail!("Unmatched `}}` in format: {template}");
+            } else {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(FormatPiece::Literal(literal));
+        }
+        Ok(Self { pieces })
+    }
+}
+
+// A sed-style substitution, parsed from `/PATTERN/REPLACEMENT/` or `/PATTERN/REPLACEMENT/g`
+// Any character can stand in for `/`. PATTERN is matched literally
+#[derive(Debug, Clone, PartialEq)]
+struct Substitution {
+    pattern: Vec<u8>,
+    replacement: Vec<ReplacementPiece>,
+    // Replace every match, not just the first
+    global: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum ReplacementPiece {
+    Literal(Vec<u8>),
+    // The text that matched, written `&` like in sed
+    Match,
+}
+
+impl FromStr for Substitution {
+    type Err = anyhow::Error;
+
+    // A backslash takes the next character literally, whether it's the delimiter, `&`, or `\`
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut chars = spec.chars();
+        let Some(delimiter) = chars.next().filter(|c| !c.is_alphanumeric() && *c != '\\') else {
+            bail!("Expected a substitution like `/PATTERN/REPLACEMENT/`, got: {spec}");
+        };
+        // The pattern and replacement, with `None` for an unescaped `&`
+        let mut parts = vec![Vec::new()];
+        while parts.len() < 3 {
+            let Some(c) = chars.next() else {
+                bail!("Unterminated substitution: {spec}");
+            };
+            let c = match c {
+                '\\' => Some(chars.next().unwrap_or('\\')),
+                '&' => None,
+                c if c == delimiter => {
+                    parts.push(Vec::new());
+                    continue;
+                }
+                c => Some(c),
+            };
+            parts.last_mut().expect("Never empty").push(c);
+        }
+        parts.pop();
+        let [pattern, replacement] = <[Vec<Option<char>>; 2]>::try_from(parts).expect("Two parts");
+        let global = match chars.as_str() {
+            "" => false,
+            "g" => true,
+            flags => bail!("Unknown substitution flags: {flags}"),
+        };
+
+        let pattern = pattern
+            .into_iter()
+            .map(|c| c.unwrap_or('&'))
+            .collect::<String>()
+            .into_bytes();
+        if pattern.is_empty() {
+            bail!("The pattern to replace can't be empty");
+        }
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        for c in replacement {
+            match c {
+                Some(c) => literal.push(c),
+                None => {
+                    if !literal.is_empty() {
+                        pieces.push(ReplacementPiece::Literal(mem::take(&mut literal).into()));
+                    }
+                    pieces.push(ReplacementPiece::Match);
+                }
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(ReplacementPiece::Literal(literal.into()));
+        }
+        Ok(Self {
+            pattern,
+            replacement: pieces,
+            global,
+        })
+    }
+}
+
+impl Substitution {
+    // The line with the substitution made, or None if the pattern isn't in it
+    fn apply(&self, line: &[u8]) -> Option<Vec<u8>> {
+        let mut replaced = Vec::with_capacity(line.len());
+        let mut rest = line;
+        let mut matched = false;
+        while let Some(index) = rest
+            .windows(self.pattern.len())
+            .position(|window| window == self.pattern)
+        {
+            matched = true;
+            replaced.extend_from_slice(&rest[..index]);
+            for piece in &self.replacement {
+                match piece {
+                    ReplacementPiece::Literal(literal) => replaced.extend_from_slice(literal),
+                    ReplacementPiece::Match => replaced.extend_from_slice(&self.pattern),
+                }
+            }
+            rest = &rest[index + self.pattern.len()..];
+            if !self.global {
+                break;
+            }
+        }
+        replaced.extend_from_slice(rest);
+        matched.then_some(replaced)
+    }
+}
+
+// Where to write each file's output, parsed from a template like `{dir}/{stem}.sliced.{ext}`
+#[derive(Debug, Clone, PartialEq)]
+struct OutputTemplate {
+    pieces: Vec<TemplatePiece>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TemplatePiece {
+    Literal(String),
+    // The directory the input file is in
+    Dir,
+    // The input file's name, without its extension
+    Stem,
+    // The input file's extension, without the dot
+    Ext,
+}
+
+impl FromStr for OutputTemplate {
+    type Err = anyhow::Error;
+
+    // Braces are escaped by doubling them, as in `--format`
+    fn from_str(template: &str) -> Result<Self> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(c) = rest.chars().next() {
+            if let Some(tail) = rest.strip_prefix("{{") {
+                literal.push('{');
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix("}}") {
+                literal.push('}');
+                rest = tail;
+            } else if let Some(tail) = rest.strip_prefix('{') {
+                let Some((name, tail)) = tail.split_once('}') else {
+                    bail!("Unclosed `{{` in output template: {template}");
+                };
+                let piece = match name {
+                    "dir" => TemplatePiece::Dir,
+                    "stem" => TemplatePiece::Stem,
+                    "ext" => TemplatePiece::Ext,
+                    _ => bail!("Unknown placeholder in output template: {{{name}}}"),
+                };
+                if !literal.is_empty() {
+                    pieces.push(TemplatePiece::Literal(mem::take(&mut literal)));
+                }
+                pieces.push(piece);
+                rest = tail;
+            } else if c == '}' {
+                bail!("Unmatched `}}` in output template: {template}");
+            } else {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(TemplatePiece::Literal(literal));
+        }
+        Ok(Self { pieces })
+    }
+}
+
+impl OutputTemplate {
+    // Fill in the template for the input file at `path`
+    fn render(&self, path: &Path) -> PathBuf {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy(),
+            _ => Cow::Borrowed("."),
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let ext = path.extension().unwrap_or_default().to_string_lossy();
+        let mut rendered = String::new();
+        for piece in &self.pieces {
+            rendered.push_str(match piece {
+                TemplatePiece::Literal(literal) => literal,
+                TemplatePiece::Dir => &dir,
+                TemplatePiece::Stem => &stem,
+                TemplatePiece::Ext => &ext,
+            });
+        }
+        PathBuf::from(rendered)
+    }
+}
+
+// Create the file that the output for `path` goes to with `--out-template`
+fn create_templated_output(
+    path: &Path,
+    template: &OutputTemplate,
+    create_dirs: bool,
+) -> Result<BufWriter<File>> {
+    if path.to_str() == Some("-") {
+        bail!("Can't name an output file after stdin");
+    }
+    let output = template.render(path);
+    // Creating the output would empty the input before it's read
+    if fs::canonicalize(&output)
+        .is_ok_and(|output| fs::canonicalize(path).is_ok_and(|path| output == path))
+    {
+        bail!(
+            "Output file would overwrite its input: {}",
+            output.display()
+        );
+    }
+    if create_dirs && let Some(dir) = output.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = File::create(&output)
+        .map_err(|err| anyhow!("Could not create {}: {err}", output.display()))?;
+    Ok(BufWriter::new(file))
+}
+
+// Which fields to keep from each selected line
+struct Fields {
+    patterns: Vec<Pattern>,
+    separator: Vec<u8>,
+}
+
+impl Fields {
+    fn new(patterns: &str, separator: &str) -> Result<Self> {
+        if separator.is_empty() {
+            bail!("Field separator must not be empty");
+        }
+        let patterns = patterns
+            .split(",")
+            .map(Pattern::parse)
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            patterns,
+            separator: separator.into(),
+        })
+    }
+
+    // Fields are given in the order the patterns list them, and may be repeated
+    fn select(&self, line: &[u8]) -> Vec<u8> {
+        let fields = split_bytes(line, &self.separator);
+        let mut selected = Vec::with_capacity(line.len());
+        let mut first = true;
+        for pattern in &self.patterns {
+            let Some((start, end)) = pattern.resolve(fields.len()) else {
+                continue;
+            };
+            for field in fields.iter().take(end).skip(start - 1) {
+                if !first {
+                    selected.extend_from_slice(&self.separator);
+                }
+                selected.extend_from_slice(field);
+                first = false;
+            }
+        }
+        selected
+    }
+}
+
+// A test that one column of a line must pass for the line to be shown
+#[derive(Debug)]
+struct ColumnFilter {
+    column: NonZeroUsize,
+    // Split columns on this, or on runs of whitespace if there isn't one
+    separator: Option<Vec<u8>>,
+    test: ColumnTest,
+}
+
+#[derive(Debug)]
+enum ColumnTest {
+    Equals(String),
+    Contains(String),
+}
+
+impl ColumnFilter {
+    fn passes(&self, line: &[u8]) -> bool {
+        let index = self.column.get() - 1;
+        let column = match &self.separator {
+            Some(separator) => split_bytes(line, separator).get(index).copied(),
+            None => line
+                .split(u8::is_ascii_whitespace)
+                .filter(|column| !column.is_empty())
+                .nth(index),
+        };
+        let Some(column) = column else {
+            return false;
+        };
+        match &self.test {
+            ColumnTest::Equals(value) => column == value.as_bytes(),
+            ColumnTest::Contains(text) => contains_bytes(column, text.as_bytes()),
+        }
+    }
+}
+
+fn split_bytes<'a>(bytes: &'a [u8], separator: &[u8]) -> Vec<&'a [u8]> {
+    let mut parts = Vec::new();
+    let mut rest = bytes;
+    while let Some(index) = rest
+        .windows(separator.len())
+        .position(|window| window == separator)
+    {
+        parts.push(&rest[..index]);
+        rest = &rest[index + separator.len()..];
+    }
+    parts.push(rest);
+    parts
+}
+
+// How to convert input from a non-UTF-8 encoding
+#[cfg(feature = "encoding")]
+struct Transcoding {
+    encoding: &'static encoding_rs::Encoding,
+    on_error: DecodeErrorPolicy,
+    // Convert output back to the original encoding
+    reencode: bool,
+}
+
+#[cfg(feature = "encoding")]
+impl Transcoding {
+    fn decode<'a>(&self, line: &'a [u8], number: NonZeroUsize) -> Result<Cow<'a, str>> {
+        match self.on_error {
+            DecodeErrorPolicy::Fail => self
+                .encoding
+                .decode_without_bom_handling_and_without_replacement(line)
+                .ok_or_else(|| anyhow!("Line {number} is not valid {}", self.encoding.name())),
+            DecodeErrorPolicy::Replace => Ok(self.encoding.decode_without_bom_handling(line).0),
+        }
+    }
+
+    // Characters that can't be represented are written as HTML character references
+    fn encode(&self, line: &[u8]) -> Vec<u8> {
+        let line = String::from_utf8_lossy(line);
+        self.encoding.encode(&line).0.into_owned()
+    }
+}
+
+#[cfg(feature = "encoding")]
+fn parse_encoding(label: &str) -> Result<&'static encoding_rs::Encoding> {
+    let encoding = encoding_rs::Encoding::for_label(label.as_bytes())
+        .ok_or_else(|| anyhow!("Unknown encoding: {label}"))?;
+    // Lines are split on the byte `\n`, which means something else in UTF-16
+    if !encoding.is_ascii_compatible() {
+        bail!("Encoding is not ASCII-compatible: {label}");
+    }
+    Ok(encoding)
+}
+
+// How to decode lines of base64
+#[cfg(feature = "base64")]
+struct Base64Decoding {
+    on_error: Base64ErrorPolicy,
+    // Write the decoded lines back to back, with nothing between them
+    concat: bool,
+}
+
+// What to do with a selected line that isn't valid base64
+#[cfg(feature = "base64")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum Base64ErrorPolicy {
+    /// Show the line as it is
+    Keep,
+    /// Leave the line out
+    Skip,
+    /// Stop with an error
+    #[default]
+    Fail,
+}
+
+// Decode standard base64, as in RFC 4648, with or without `=` padding
+#[cfg(feature = "base64")]
+fn decode_base64(encoded: &[u8]) -> Result<Vec<u8>> {
+    let digits = match encoded.iter().rposition(|&byte| byte != b'=') {
+        Some(end) => &encoded[..=end],
+        None => encoded,
+    };
+    let padding = encoded.len() - digits.len();
+    if padding > 2 || (padding > 0 && !encoded.len().is_multiple_of(4)) {
+        bail!("Bad padding");
+    }
+    if digits.len() % 4 == 1 {
+        bail!("Wrong length");
+    }
+    let mut decoded = Vec::with_capacity(digits.len() * 3 / 4);
+    for group in digits.chunks(4) {
+        let mut bits = 0u32;
+        for &byte in group {
+            let value = match byte {
+                b'A'..=b'Z' => byte - b'A',
+                b'a'..=b'z' => byte - b'a' + 26,
+                b'0'..=b'9' => byte - b'0' + 52,
+                b'+' => 62,
+                b'/' => 63,
+                _ => bail!("Unexpected {:?}", char::from(byte)),
+            };
+            bits = bits << 6 | u32::from(value);
+        }
+        // A short group holds fewer bytes, with its unused low bits left over
+        bits <<= 6 * (4 - group.len());
+        let bytes = bits.to_be_bytes();
+        decoded.extend_from_slice(&bytes[1..group.len()]);
+    }
+    Ok(decoded)
+}
+
+// What to do with input that isn't valid in the given encoding
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum DecodeErrorPolicy {
+    /// Replace invalid sequences with U+FFFD
+    Replace,
+    /// Stop with an error
+    #[default]
+    Fail,
+}
+
+// What to do with a line that isn't JSON when reformatting JSON
+#[cfg(feature = "pretty-json")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum InvalidJsonPolicy {
+    /// Show the line as it is
+    Keep,
+    /// Stop with an error
+    #[default]
+    Fail,
+}
+
+// What to do with input that looks binary
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum BinaryPolicy {
+    /// Leave the file out, with a note
+    Skip,
+    /// Show the file anyway
+    #[default]
+    Print,
+    /// Show the file, but warn that it looks binary
+    Warn,
+}
+
+// What to do when reading the input fails partway through
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum ReadErrorPolicy {
+    /// Report the error and carry on reading
+    Skip,
+    /// Stop with an error
+    #[default]
+    Fail,
+}
+
+// How to write line numbers in the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum NumberRadix {
+    /// Decimal
+    #[default]
+    Dec,
+    /// Hexadecimal, like `0x1f`
+    Hex,
+    /// Octal, like `0o37`
+    Oct,
+    /// Binary, like `0b11111`
+    Bin,
+}
+
+impl NumberRadix {
+    // Written so it could be given back as a line number
+    fn format(self, number: usize) -> String {
+        match self {
+            Self::Dec => number.to_string(),
+            Self::Hex => format!("{number:#x}"),
+            Self::Oct => format!("{number:#o}"),
+            Self::Bin => format!("{number:#b}"),
+        }
+    }
+}
+
+// When to color the output
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+enum ColorWhen {
+    /// Only when writing to a terminal
+    Auto,
+    /// Even when writing to a file or pipe
+    Always,
+    /// Not at all
+    #[default]
+    Never,
+}
+
+// The colors of the parts of the output that aren't the lines themselves, as SGR codes
+// Customized with `DAGAN_LINE_COLORS`, e.g. `number=cyan:label=red`
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Theme {
+    number: u8,
+    label: u8,
+    separator: u8,
+}
+
+impl Default for Theme {
+    // The same colors grep uses
+    fn default() -> Self {
+        Self {
+            number: 32,
+            label: 35,
+            separator: 36,
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let mut theme = Self::default();
+        for entry in spec.split(':').filter(|entry| !entry.is_empty()) {
+            let Some((element, color)) = entry.split_once('=') else {
+                bail!("Expected `ELEMENT=COLOR`, got: {entry}");
+            };
+            let colors = [
+                "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+            ];
+            let Some(code) = colors.iter().position(|name| *name == color) else {
+                bail!("Unknown color: {color}");
+            };
+            let code = 30 + code as u8;
+            match element {
+                "number" => theme.number = code,
+                "label" => theme.label = code,
+                "separator" => theme.separator = code,
+                _ => bail!("Unknown element: {element}"),
+            }
+        }
+        Ok(theme)
+    }
+}
+
+// The theme given by `DAGAN_LINE_COLORS`, or the default one if it isn't set or can't be used
+fn theme_from_env(spec: Option<&str>, mut errors: impl Write) -> Result<Theme> {
+    let Some(spec) = spec else {
+        return Ok(Theme::default());
+    };
+    match spec.parse() {
+        Ok(theme) => Ok(theme),
+        Err(err) => {
+            writeln!(errors, "Warning: ignoring DAGAN_LINE_COLORS: {err}")?;
+            Ok(Theme::default())
+        }
+    }
+}
+
+// Which number to show next to non-blank lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum NonblankNumbering {
+    // Count only non-blank lines, like `cat -b`
+    Count,
+    // Show the line's real position in the file
+    LineNumber,
+}
+
+// Which ends of each line to strip whitespace from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Trim {
+    Both,
+    Start,
+    End,
+}
+
+impl Trim {
+    fn apply(self, line: &[u8]) -> &[u8] {
+        match self {
+            Self::Both => line.trim_ascii(),
+            Self::Start => line.trim_ascii_start(),
+            Self::End => line.trim_ascii_end(),
+        }
+    }
+}
+
+// Line numbers and lines lined up in columns
+#[derive(Debug, Clone, Copy)]
+struct Table {
+    // Cut rows off after this many characters
+    max_width: Option<NonZeroUsize>,
+}
+
+// How to collapse repeated lines, like `uniq`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Uniq {
+    // Show each run of the same line once
+    Collapse,
+    // Also show how long each run was
+    Count,
+}
+
+// What to count with `--count-bytes`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ByteCounting {
+    // Only what's on each line
+    Content,
+    // Also the `\n` written after each line
+    WithTerminators,
+}
+
+// The most recent `count` distinct lines, oldest first
+// Seeing a line again makes it the most recent
+struct LastUnique {
+    count: NonZeroUsize,
+    lines: VecDeque<(NonZeroUsize, Vec<u8>)>,
+    // The contents of `lines`, so most lines don't need a search
+    contents: HashSet<Vec<u8>>,
+}
+
+impl LastUnique {
+    fn new(count: NonZeroUsize) -> Self {
+        Self {
+            count,
+            lines: VecDeque::new(),
+            contents: HashSet::new(),
+        }
+    }
+
+    fn push(&mut self, number: NonZeroUsize, line: &[u8]) {
+        if self.contents.contains(line) {
+            let index = self
+                .lines
+                .iter()
+                .position(|(_, seen)| seen == line)
+                .expect("Line should be in window");
+            self.lines.remove(index);
+        } else {
+            self.contents.insert(line.to_vec());
+            if self.lines.len() == self.count.get()
+                && let Some((_, evicted)) = self.lines.pop_front()
+            {
+                self.contents.remove(&evicted);
+            }
+        }
+        self.lines.push_back((number, line.to_vec()));
+    }
+}
+
+// How to write the selection as JSON
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum JsonOutput {
+    // `["line", ...]`
+    Array,
+    // `{"count": N, "lines": ["line", ...]}`
+    Meta,
+}
+
+// Splitting of the output every `size` lines
+#[derive(Debug)]
+struct Chunk {
+    size: NonZeroUsize,
+    // Write each chunk to a numbered file starting with this, instead of separating them with `--`
+    prefix: Option<String>,
+    // The file the current chunk is being written to
+    file: RefCell<Option<BufWriter<File>>>,
+}
+
+// Breaking long lines into several
+#[derive(Debug)]
+struct Wrap {
+    width: NonZeroUsize,
+    // How many spaces to put before each piece of a line after the first
+    indent: usize,
+}
+
+// How to order the selection
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Sort {
+    numeric: bool,
+    reverse: bool,
+}
+
+impl Sort {
+    // Sort lines in place. Lines that compare equal stay in the order they were selected
+    fn apply(self, lines: &mut [(NonZeroUsize, Vec<u8>)]) {
+        lines.sort_by(|(_, a), (_, b)| {
+            let (a, b) = (trim_terminator(a), trim_terminator(b));
+            let ordering = if self.numeric {
+                leading_number(a).total_cmp(&leading_number(b))
+            } else {
+                a.cmp(b)
+            };
+            if self.reverse {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        });
+    }
+}
+
+// The number a line starts with, after any whitespace, like `sort -n` uses. A line that doesn't
+// start with a number counts as 0
+fn leading_number(line: &[u8]) -> f64 {
+    let line = line.trim_ascii_start();
+    let mut end = usize::from(line.first() == Some(&b'-'));
+    let mut seen_point = false;
+    while let Some(&byte) = line.get(end) {
+        if byte == b'.' && !seen_point {
+            seen_point = true;
+        } else if !byte.is_ascii_digit() {
+            break;
+        }
+        end += 1;
+    }
+    str::from_utf8(&line[..end])
+        .ok()
+        .and_then(|number| number.parse().ok())
+        .unwrap_or(0.0)
+}
+
+// Which selected lines to leave out of the output
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Skip {
+    // Lines with nothing on them
+    Empty,
+    // Lines with nothing but whitespace on them
+    Blank,
+}
+
+impl Skip {
+    fn matches(self, line: &[u8]) -> bool {
+        match self {
+            Skip::Empty => line.is_empty(),
+            Skip::Blank => line.trim_ascii().is_empty(),
+        }
+    }
+}
+
+// The last `count` selected lines, ending `offset` lines before the end
+// Parsed from `N` or `N+M`
+#[derive(Debug, Clone, PartialEq)]
+struct Tail {
+    count: usize,
+    offset: usize,
+}
+
+impl Tail {
+    // How many lines need to be kept around to find the tail
+    fn window(&self) -> usize {
+        self.count + self.offset
+    }
+}
+
+impl FromStr for Tail {
+    type Err = anyhow::Error;
+
+    fn from_str(tail: &str) -> Result<Self> {
+        let (count, offset) = tail.split_once('+').unwrap_or((tail, "0"));
+        let count: usize = count
+            .parse()
+            .map_err(|_| anyhow!("Could not interpret tail: {tail}"))?;
+        let offset: usize = offset
+            .parse()
+            .map_err(|_| anyhow!("Could not interpret tail: {tail}"))?;
+        if count == 0 {
+            bail!("Tail must contain at least one line");
+        }
+        Ok(Self { count, offset })
+    }
+}
+
+// What stdin is called in messages unless `--stdin-name` says otherwise
+const DEFAULT_STDIN_NAME: &str = "(standard input)";
+
+#[derive(Default)]
+struct Options {
+    show_line_number: bool,
+    // Named line numbers that may be used in patterns
+    anchors: HashMap<String, String>,
+    // Error out on lines longer than this many bytes
+    max_line_length: Option<usize>,
+    // Only show the end of the selection
+    tail: Option<Tail>,
+    // Number non-blank lines, overriding `show_line_number`
+    number_nonblank: Option<NonblankNumbering>,
+    // Set when we should stop reading, e.g. on Ctrl-C
+    stop: Arc<AtomicBool>,
+    // Where to write the lines that weren't selected
+    complement: Option<RefCell<Box<dyn Write>>>,
+    // Where to write the selection with the lines around it
+    context: Option<Context>,
+    // Decode input from some other encoding
+    #[cfg(feature = "encoding")]
+    transcoding: Option<Transcoding>,
+    // Only show some fields of each line
+    fields: Option<Fields>,
+    // Strip whitespace from the selected lines
+    trim: Option<Trim>,
+    // Decode each line from base64
+    #[cfg(feature = "base64")]
+    base64: Option<Base64Decoding>,
+    // Reformat each line as indented JSON, and what to do when it isn't JSON
+    #[cfg(feature = "pretty-json")]
+    pretty_json: Option<InvalidJsonPolicy>,
+    // Make this substitution in each line
+    substitution: Option<Substitution>,
+    // Write this in place of each tab
+    tab_replacement: Option<String>,
+    // Cut each line off after this many characters
+    truncate: Option<NonZeroUsize>,
+    // Break lines longer than this into several
+    wrap: Option<Wrap>,
+    // Stop after writing this many lines, from every file
+    max_lines: Option<NonZeroUsize>,
+    // Stop before the lines written, from every file, add up to more than this many bytes
+    // Each counts as it's written, decorations and all
+    byte_budget: Option<usize>,
+    // Only show lines with a column that passes a test
+    column_filter: Option<ColumnFilter>,
+    // Drop empty or blank lines from the selection
+    skip: Option<Skip>,
+    // Drop everything but empty or blank lines from the selection
+    select_empty: Option<Skip>,
+    // Drop lines shorter or longer than this many bytes from the selection
+    min_content_length: Option<usize>,
+    max_content_length: Option<usize>,
+    // Drop lines with any non-ASCII bytes from the selection
+    ascii_only: bool,
+    // Remove non-ASCII bytes from each line
+    strip_non_ascii: bool,
+    // Where to write a copy of the output
+    tee: Option<RefCell<Box<dyn Write>>>,
+    // Where to write copies of the whole output once it's done
+    repeat: Option<Repeat>,
+    // Where to write the runs of lines that were shown
+    collapse_ranges: Option<RefCell<Box<dyn Write>>>,
+    // Where to write warnings
+    diagnostics: Diagnostics,
+    // Start each block of consecutive lines with a diff-style header
+    hunk_headers: bool,
+    // Patterns are offsets from the first line containing this text
+    after: Option<String>,
+    // Lay out each line with a template, overriding line numbering
+    format: Option<Format>,
+    // Show how many bytes were selected instead of the lines themselves
+    count_bytes: Option<ByteCounting>,
+    // Show how many times this appears in the selected lines instead of the lines themselves
+    count_matches: Option<String>,
+    // Show how many different lines were selected instead of the lines themselves
+    count_distinct: bool,
+    // Show how many lines were selected instead of the lines themselves
+    count_lines: bool,
+    // Show the numbers of the selected lines instead of the lines themselves
+    numbers_only: bool,
+    // Only show the most recent distinct selected lines
+    last_unique: Option<NonZeroUsize>,
+    // Sort the selection before showing it
+    sort: Option<Sort>,
+    // Show a pattern's label before the first line it selects
+    show_labels: bool,
+    // Color line numbers, labels, and separators with these colors
+    theme: Option<Theme>,
+    // Only select lines whose hash is a multiple of this
+    hash_sample: Option<NonZeroUsize>,
+    // Only select lines whose hash, in hex, starts with this
+    hash_prefix: Option<String>,
+    // Write the selection as JSON
+    json: Option<JsonOutput>,
+    // Separate patterns with this instead of a comma
+    pattern_separator: Option<char>,
+    // How to write line numbers
+    number_radix: NumberRadix,
+    // Add this to line numbers when showing them, without changing which lines are selected
+    number_shift: usize,
+    // Show each line's number from this map next to its own with `show_line_number`
+    renumber_map: Option<HashMap<usize, usize>>,
+    // Show at most this many lines from each pattern
+    limit_per_range: Option<NonZeroUsize>,
+    // Only show lines in this window, and treat the patterns as lines to leave out of it
+    within: Option<Pattern>,
+    // Collapse runs of the same line
+    uniq: Option<Uniq>,
+    // Only write the first of each set of identical lines
+    unique: bool,
+    // Leave out this many of the lines that would otherwise be written first
+    offset: usize,
+    // Only write the lines whose position among those that would otherwise be written is in
+    // this range
+    nth: Option<Pattern>,
+    // Separate lines that aren't next to each other in the input with a blank line
+    group_by_gap: bool,
+    // End each line the way it was ended in the input
+    line_terminator_auto: bool,
+    // Join the selected lines into one
+    one_line: bool,
+    // Skip ahead to the first line at or after this byte in each file
+    start_at_byte: Option<u64>,
+    // Show line numbers and lines in aligned columns
+    table: Option<Table>,
+    // What to do when reading fails
+    on_read_error: ReadErrorPolicy,
+    // How long to wait for a named pipe to have a writer
+    open_timeout: Option<Duration>,
+    // How many inputs may be open at once
+    open_files: Option<Arc<OpenFiles>>,
+    // What to call stdin in messages, instead of `DEFAULT_STDIN_NAME`
+    stdin_name: Option<String>,
+    // Split the output into chunks of so many lines
+    chunk: Option<Chunk>,
+    // What to do with files that look binary
+    binary: BinaryPolicy,
+    // Flush after every line
+    unbuffered: bool,
+    // Set until the UTF-8 byte order mark has been written
+    bom: Cell<bool>,
+    // Set once any line has been written, from any file
+    selected_any: Cell<bool>,
+    // How many lines have been written, from every file
+    emitted: Cell<usize>,
+    // How many bytes of the byte budget have been used, from every file
+    budget_used: Cell<usize>,
+}
+
+impl Options {
+    // What to call an input in messages
+    fn input_name<'a>(&'a self, path: &'a Path) -> Cow<'a, str> {
+        if path.to_str() == Some("-") {
+            Cow::Borrowed(self.stdin_name.as_deref().unwrap_or(DEFAULT_STDIN_NAME))
+        } else {
+            path.to_string_lossy()
+        }
+    }
+
+    // True once `max_lines` lines have been written, or the byte budget has run out
+    fn at_limit(&self) -> bool {
+        self.max_lines
+            .is_some_and(|max_lines| self.emitted.get() >= max_lines.get())
+            || self
+                .byte_budget
+                .is_some_and(|budget| self.budget_used.get() > budget)
+    }
+}
+
+/// Display selected lines from a file or stdin
+#[derive(Parser)]
+struct Args {
+    /// Show line numbers
+    #[clap(short = 'n')]
+    show_line_number: bool,
+    /// Number non-blank lines, counting only the non-blank lines. Overrides `-n`
+    ///
+    /// "Foo", "", "Bar" is displayed as "1 Foo", "", "2 Bar"
+    #[clap(short = 'b', long, verbatim_doc_comment)]
+    number_nonblank: bool,
+    /// With `-b`, show each line's real line number instead of the non-blank count
+    ///
+    /// "Foo", "", "Bar" is displayed as "1 Foo", "", "3 Bar"
+    #[clap(long, requires = "number_nonblank", verbatim_doc_comment)]
+    true_numbers: bool,
+    /// Instead of showing the selected lines, show how many bytes they add up to
+    #[clap(long, conflicts_with_all = ["ranges_only", "summary"])]
+    count_bytes: bool,
+    /// With `--count-bytes`, also count the line terminator written after each line
+    #[clap(long, requires = "count_bytes")]
+    with_terminators: bool,
+    /// Instead of showing the selected lines, show how many times TEXT appears in them, like
+    /// `grep -o | wc -l`. A line with TEXT in it twice counts twice. Matches don't overlap
+    #[clap(
+        long,
+        value_name = "TEXT",
+        conflicts_with_all = [
+            "count_bytes",
+            "json_array",
+            "json_meta",
+            "format",
+            "table",
+            "show_labels",
+            "group_by_gap",
+            "ranges_only",
+            "summary",
+        ]
+    )]
+    count_matches: Option<String>,
+    /// Instead of showing the selected lines, show how many different ones there are. With
+    /// `--fields`, counts different values of those fields. Keeps a copy of each different line,
+    /// so memory use grows with the count
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "count_bytes",
+            "count_matches",
+            "json_array",
+            "json_meta",
+            "format",
+            "table",
+            "show_labels",
+            "group_by_gap",
+            "ranges_only",
+            "summary",
+        ]
+    )]
+    count_distinct: bool,
+    /// Instead of showing the selected lines, show how many of them aren't empty. Empty lines are
+    /// left out just as `--skip-empty` leaves them out, so the count always matches the number of
+    /// lines `--skip-empty` would show. With `--skip-blank`, lines of only whitespace aren't
+    /// counted either
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "count_bytes",
+            "count_matches",
+            "count_distinct",
+            "select_empty",
+            "json_array",
+            "json_meta",
+            "format",
+            "table",
+            "show_labels",
+            "group_by_gap",
+            "ranges_only",
+            "summary",
+        ]
+    )]
+    count_nonempty: bool,
+    /// Instead of showing the selected lines, show their line numbers, one per line. Lines left
+    /// out by filters like `--col` aren't listed
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "show_line_number",
+            "number_nonblank",
+            "count_bytes",
+            "count_matches",
+            "count_distinct",
+            "json_array",
+            "json_meta",
+            "format",
+            "table",
+            "one_line",
+            "ranges_only",
+            "summary",
+        ]
+    )]
+    numbers_only: bool,
+    /// Reverse each file before selecting from it, so line 1 is the last line. Line numbers
+    /// shown with `-n` count from the end too. Reads each file in full before showing anything
+    #[clap(long, conflicts_with = "summary")]
+    reverse_file: bool,
+    /// Use the line index at PATH to jump straight to the first selected line, instead of reading
+    /// every line before it. The index is only used if the file hasn't changed since it was built
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "reverse_file",
+            "start_at_byte",
+            "zip",
+            "watch",
+            "summary",
+            "ranges_only",
+            "between",
+            "around",
+            "split_ranges",
+        ]
+    )]
+    index_file: Option<PathBuf>,
+    /// With `--index-file`, build the index if it's missing or out of date
+    #[clap(long, requires = "index_file")]
+    build_index: bool,
+    /// Keep the file open and answer requests for lines from CONTROL, such as a named pipe, until
+    /// it ends. Use `-` for stdin. Each request is a line holding a pattern list like LINES. Each
+    /// answer is `ok N`, a newline, and the N bytes of the selection, or `error MESSAGE` and a
+    /// newline. Takes exactly one file, which is indexed so each request can jump straight to its
+    /// lines
+    #[clap(
+        long,
+        value_name = "CONTROL",
+        conflicts_with_all = [
+            "watch",
+            "zip",
+            "summary",
+            "ranges_only",
+            "between",
+            "around",
+            "bytes",
+            "split_ranges",
+            "out_template",
+            "index_file",
+            "reverse_file",
+            "start_at_byte",
+            "patterns_from_stdin",
+            "repeat_file",
+            "paginate",
+            "bom",
+        ]
+    )]
+    server: Option<PathBuf>,
+    /// Skip ahead to the first line that starts at or after byte OFFSET of each file, without
+    /// reading what comes before. Line numbers then count from that line, not the start of the
+    /// file. Can't be used with stdin
+    #[clap(long, value_name = "OFFSET", conflicts_with = "watch")]
+    start_at_byte: Option<u64>,
+    /// What to do if reading a file fails partway through. With `skip`, the line being read is
+    /// lost, but the rest of the file is still read. `skip` can't be used with `--bytes`
+    #[clap(long, value_enum, value_name = "POLICY", default_value_t)]
+    on_error: ReadErrorPolicy,
+    /// If a file is a named pipe, fail if nothing has opened it to write to after SECONDS,
+    /// instead of waiting forever
+    #[clap(long, value_name = "SECONDS", value_parser = parse_interval)]
+    open_timeout: Option<Duration>,
+    /// What to call stdin, given as `-`, in messages that name a file
+    #[clap(long, value_name = "NAME", default_value = DEFAULT_STDIN_NAME)]
+    stdin_name: String,
+    /// Lay out each line with a template, where `{line}` is the line and the letter `n` in braces
+    /// is the line number. Use `{{` and `}}` for literal braces. Overrides `-n` and `-b`
+    #[clap(long, value_name = "TEMPLATE")]
+    format: Option<Format>,
+    /// How to write line numbers shown with `-n`, `-b`, or `--format`
+    #[clap(long, value_enum, default_value_t)]
+    number_radix: NumberRadix,
+    /// Show line numbers as if the first line of the file were line N, e.g. when the file is part
+    /// of a larger document. Only the numbers shown change: LINES still counts from line 1
+    #[clap(long, value_name = "N")]
+    numbered_from: Option<NonZeroUsize>,
+    /// File of `OLD NEW` line number pairs, one per line, e.g. from a diff. With `-n`, each line's
+    /// number in the map is shown in a second column after its own, or `-` if it has none
+    #[clap(long, value_name = "FILE", requires = "show_line_number")]
+    renumber_map: Option<PathBuf>,
+    /// File of `NAME=LINE` definitions, one per line. Names may be used in place of line numbers
+    #[clap(long)]
+    anchors: Option<PathBuf>,
+    /// Fail if a line is longer than this many bytes, not counting the line terminator
+    #[clap(long)]
+    max_line_length: Option<usize>,
+    /// Stop after showing N lines in total
+    #[clap(long, value_name = "N")]
+    max_lines: Option<NonZeroUsize>,
+    /// Stop before the output would grow past N bytes, without cutting a line short. Each line
+    /// counts as it's written, with its terminator and any line number or other decoration
+    #[clap(long, value_name = "N")]
+    byte_budget: Option<usize>,
+    /// Cut each line off after N characters
+    #[clap(long, value_name = "N")]
+    truncate: Option<NonZeroUsize>,
+    /// Break lines longer than N characters into several, each at most N characters long. With
+    /// `-n`, only the first gets the number, and the rest line up with it
+    #[clap(long, value_name = "N", conflicts_with_all = ["format", "table", "one_line"])]
+    wrap: Option<NonZeroUsize>,
+    /// With `--wrap`, indent each piece of a line after the first by N more spaces
+    #[clap(long, value_name = "N", requires = "wrap", default_value_t = 0)]
+    wrap_indent: usize,
+    /// Take a quick look: show at most 20 lines, each cut off at the width of the terminal.
+    /// `--max-lines` and `--truncate` override these limits
+    #[clap(long)]
+    preview: bool,
+    /// Only show the last N selected lines. `N+M` shows the N lines that end M lines before the
+    /// last selected line
+    #[clap(long, value_name = "N[+M]")]
+    tail: Option<Tail>,
+    /// Only show the last N distinct selected lines, in the order they were last seen. A repeated
+    /// line counts as seen at its latest position
+    #[clap(long, value_name = "N", conflicts_with = "tail")]
+    last_unique: Option<NonZeroUsize>,
+    /// Sort the selected lines from each file by their contents. The whole selection is held in
+    /// memory until the file has been read. With `-n`, each line keeps its own number
+    #[clap(long, conflicts_with_all = ["tail", "last_unique"])]
+    sort: bool,
+    /// Like `--sort`, but by the number each line starts with, like `sort -n`. Lines that don't
+    /// start with a number count as 0
+    #[clap(long, conflicts_with_all = ["tail", "last_unique"])]
+    numeric_sort: bool,
+    /// Like `--sort`, but from last to first. Can be combined with `--numeric-sort`
+    #[clap(long, conflicts_with_all = ["tail", "last_unique"])]
+    reverse_sort: bool,
+    /// Show a pattern's label, given after a `#`, before the first line it selects
+    #[clap(long, conflicts_with_all = ["tail", "last_unique", "count_bytes", "zip"])]
+    show_labels: bool,
+    /// Color line numbers, labels, and `--` separators. The colors can be changed with
+    /// `DAGAN_LINE_COLORS`, like `number=cyan:label=red:separator=blue`
+    #[clap(
+        long,
+        value_enum,
+        value_name = "WHEN",
+        default_value_t,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "auto"
+    )]
+    color: ColorWhen,
+    /// Only select lines whose contents hash to a multiple of N, for a repeatable sample of
+    /// about 1 in N lines. Identical lines are always sampled together
+    #[clap(long, value_name = "N")]
+    hash_sample: Option<NonZeroUsize>,
+    /// Only select lines whose contents hash to a hex number starting with HEX, for a repeatable
+    /// sample that stays the same across files. Each digit makes the sample 16 times smaller
+    #[clap(long, value_name = "HEX", value_parser = parse_hash_prefix)]
+    hash_prefix: Option<String>,
+    /// Write the selected lines as a JSON array of strings
+    #[clap(
+        long,
+        conflicts_with_all = ["format", "count_bytes", "show_labels", "ranges_only", "summary"]
+    )]
+    json_array: bool,
+    /// Write the selected lines as a JSON object, with the number of lines under "count" and the
+    /// lines themselves under "lines"
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "json_array",
+            "format",
+            "count_bytes",
+            "show_labels",
+            "ranges_only",
+            "summary",
+        ]
+    )]
+    json_meta: bool,
+    /// Clear the screen and show the selection again whenever the file changes, until Ctrl-C.
+    /// If the file is replaced, e.g. when a log is rotated, the new file is shown
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "zip",
+            "summary",
+            "ranges_only",
+            "reverse_file",
+            "complement_to",
+            "tee",
+        ]
+    )]
+    watch: bool,
+    /// With `--watch`, how many seconds to wait between checks for changes
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = parse_interval,
+        default_value = "1",
+        requires = "watch"
+    )]
+    interval: Duration,
+    /// Separate the patterns in LINES with this character instead of a comma
+    #[clap(long, value_name = "CHAR", value_parser = parse_pattern_separator)]
+    pattern_separator: Option<char>,
+    /// Also read the files named in this file, one per line. Use `-` to read the names from stdin
+    #[clap(short = 'T', long, value_name = "PATH")]
+    files_from: Option<PathBuf>,
+    /// Like `--files-from`, but names end with a NUL byte, as from `find -print0`
+    #[clap(long, value_name = "PATH", conflicts_with = "files_from")]
+    files_from0: Option<PathBuf>,
+    /// Exit with this code if no lines were selected, like `grep` does
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    exit_code_on_empty: u8,
+    /// If a file can't be read, report it and carry on with the rest instead of stopping. Exits
+    /// with an error at the end if any file failed
+    #[clap(long)]
+    keep_going: bool,
+    /// Show at most the first N lines that each pattern selects
+    #[clap(long, value_name = "N")]
+    limit_per_range: Option<NonZeroUsize>,
+    /// Show a selected line only once when it comes up several times in a row, like `uniq`
+    #[clap(long)]
+    uniq: bool,
+    /// Like `--uniq`, but prefix each line with how many times in a row it came up, like `uniq -c`
+    #[clap(long, conflicts_with_all = ["format", "count_bytes", "json_array", "json_meta"])]
+    uniq_count: bool,
+    /// Show each different line only the first time it comes up, even if the repeats aren't in a
+    /// row. With `-n`, each line shows where it first came up. Every different line shown is
+    /// kept in memory until the file has been read
+    #[clap(long)]
+    unique: bool,
+    /// Only show lines in this range, and leave out the ones LINES selects. For example,
+    /// `--within 1..=100 40..=50` shows lines 1 to 39 and 51 to 100. With `--around`, only look
+    /// in this range
+    #[clap(
+        long,
+        value_name = "RANGE",
+        value_parser = parse_window,
+        conflicts_with_all = [
+            "after",
+            "zip",
+            "show_labels",
+            "limit_per_range",
+            "ranges_only",
+            "summary",
+        ]
+    )]
+    within: Option<Pattern>,
+    /// Write the output to this file instead of stdout
+    #[clap(short, long, value_name = "FILE", conflicts_with = "watch")]
+    output: Option<PathBuf>,
+    /// Compress the output with gzip
+    #[cfg(feature = "gzip")]
+    #[clap(
+        long,
+        conflicts_with_all = ["watch", "split_ranges", "paginate", "out_template", "server"]
+    )]
+    gzip_output: bool,
+    /// Show the output through `$PAGER`, or `less -R` if it isn't set, so it doesn't scroll off
+    /// the screen. If the pager can't be started, the output goes to stdout as usual
+    #[clap(long, conflicts_with_all = ["output", "watch", "split_ranges"])]
+    paginate: bool,
+    /// How many bytes of output to hold before writing them out, like `64k`
+    #[clap(long, value_name = "BYTES", value_parser = parse_size)]
+    output_buffer_size: Option<NonZeroUsize>,
+    /// Write out each line as soon as it's selected, for watching the output live
+    #[clap(long, conflicts_with = "output_buffer_size")]
+    unbuffered: bool,
+    /// Write each file's output to a file of its own, named by TEMPLATE. `{dir}` is the directory
+    /// the input is in, `{stem}` is its name without the extension, and `{ext}` is the extension
+    /// without the dot, e.g. `{dir}/{stem}.sliced.{ext}`. Use `{{` and `}}` for literal braces
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with_all = [
+            "output",
+            "paginate",
+            "watch",
+            "split_ranges",
+            "zip",
+            "between",
+            "around",
+            "bytes",
+            "summary",
+            "bom",
+            "repeat_file",
+        ]
+    )]
+    out_template: Option<OutputTemplate>,
+    /// With `--out-template`, create any missing directories for the output files, instead of
+    /// failing
+    #[clap(long, requires = "out_template")]
+    create_dirs: bool,
+    /// Start the output with a UTF-8 byte order mark, for tools that expect one
+    #[clap(
+        long,
+        conflicts_with_all = ["watch", "split_ranges", "chunk_prefix", "summary", "ranges_only"]
+    )]
+    bom: bool,
+    /// What to do with a file that contains a NUL byte near its start. Defaults to `warn` when
+    /// writing to a terminal, and `print` otherwise
+    #[clap(long, value_enum, value_name = "POLICY")]
+    binary: Option<BinaryPolicy>,
+    /// Split the output every N lines, with a `--` line between each chunk
+    #[clap(
+        long,
+        value_name = "N",
+        conflicts_with_all = ["count_bytes", "json_array", "json_meta", "ranges_only", "summary"]
+    )]
+    chunk: Option<NonZeroUsize>,
+    /// With `--chunk`, write each chunk to its own file instead, named PREFIX followed by the
+    /// chunk's index, starting at 0
+    #[clap(long, value_name = "PREFIX", requires = "chunk")]
+    chunk_prefix: Option<String>,
+    /// Write the lines that were NOT selected to this file
+    #[clap(long, value_name = "FILE")]
+    complement_to: Option<PathBuf>,
+    /// Once each file is done, write the runs of consecutive lines that were shown to stderr,
+    /// like `emitted: 1-3, 7-7, 20-25`, to check what a set of patterns picked out
+    #[clap(long)]
+    collapse_ranges: bool,
+    /// Write warnings and other messages, like those from `--collapse-ranges` and `--keep-going`,
+    /// to PATH instead of stderr. An error that stops `line` is still written to stderr
+    #[clap(long, value_name = "PATH")]
+    errors_to: Option<PathBuf>,
+    /// Start each block of consecutive lines with a header like `@@ -12,4 @@`, giving its first
+    /// line number and how many lines it has, like the hunks of a unified diff
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "count_bytes",
+            "count_matches",
+            "count_distinct",
+            "count_nonempty",
+            "numbers_only",
+            "json_array",
+            "json_meta",
+            "table",
+            "one_line",
+            "uniq",
+            "uniq_count",
+            "show_labels",
+            "group_by_gap",
+            "chunk",
+        ]
+    )]
+    hunk_headers: bool,
+    /// Also write the selected lines to this file, along with the lines around them. The usual
+    /// output is unchanged
+    #[clap(long, value_name = "FILE")]
+    context_file: Option<PathBuf>,
+    /// How many lines before and after each selected line to write to the context file, or to
+    /// show with `--around`
+    #[clap(short = 'C', long, value_name = "N", default_value_t = 3)]
+    context: usize,
+    /// Also write the output to this file
+    #[clap(long, value_name = "FILE")]
+    tee: Option<PathBuf>,
+    /// Once everything has been shown, write `--repeat-count` copies of it to this file
+    #[clap(long, value_name = "FILE", conflicts_with_all = ["watch", "split_ranges"])]
+    repeat_file: Option<PathBuf>,
+    /// How many copies to write with `--repeat-file`
+    #[clap(long, value_name = "N", default_value = "1", requires = "repeat_file")]
+    repeat_count: NonZeroUsize,
+    /// Find the first line containing TEXT, and treat LINES as offsets from it, like `+1..=+5`.
+    /// An open start means the matching line itself
+    #[clap(long, value_name = "TEXT")]
+    after: Option<String>,
+    /// Decode input from an ASCII-compatible encoding, such as `latin1` or `shift_jis`. Output is
+    /// UTF-8 unless `--reencode` is given
+    #[cfg(feature = "encoding")]
+    #[clap(long, value_name = "LABEL", value_parser = parse_encoding)]
+    encoding: Option<&'static encoding_rs::Encoding>,
+    /// What to do with input that is invalid in the given encoding
+    #[cfg(feature = "encoding")]
+    #[clap(long, value_enum, default_value_t, requires = "encoding")]
+    on_decode_error: DecodeErrorPolicy,
+    /// Write output in the same encoding as the input
+    #[cfg(feature = "encoding")]
+    #[clap(long, requires = "encoding", conflicts_with = "bom")]
+    reencode: bool,
+    /// Decode each selected line from base64 and write the raw bytes
+    #[cfg(feature = "base64")]
+    #[clap(long)]
+    base64_decode: bool,
+    /// With `--base64-decode`, what to do with a selected line that isn't valid base64
+    #[cfg(feature = "base64")]
+    #[clap(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        default_value_t,
+        requires = "base64_decode"
+    )]
+    on_base64_error: Base64ErrorPolicy,
+    /// With `--base64-decode`, write the decoded lines back to back, without a newline after each
+    #[cfg(feature = "base64")]
+    #[clap(long, requires = "base64_decode")]
+    base64_concat: bool,
+    /// Treat each line as a JSON value, as in NDJSON, and show the selected ones indented over
+    /// several lines
+    #[cfg(feature = "pretty-json")]
+    #[clap(long)]
+    pretty_json: bool,
+    /// With `--pretty-json`, what to do with a selected line that isn't valid JSON
+    #[cfg(feature = "pretty-json")]
+    #[clap(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        default_value_t,
+        requires = "pretty_json"
+    )]
+    on_invalid_json: InvalidJsonPolicy,
+    /// Only show these fields of each selected line, using the same syntax as LINES
+    #[clap(long, value_name = "FIELDS")]
+    fields: Option<String>,
+    /// The string that separates fields
+    #[clap(long, value_name = "SEP", default_value = "\t", requires = "fields")]
+    field_sep: String,
+    /// Only show lines whose Nth column passes `--equals` or `--contains`. Columns are separated
+    /// by whitespace unless `--col-sep` is given. Lines without an Nth column are left out
+    #[clap(long, value_name = "N", requires = "column_test")]
+    col: Option<NonZeroUsize>,
+    /// The string that separates columns for `--col`
+    #[clap(long, value_name = "SEP", requires = "col")]
+    col_sep: Option<String>,
+    /// With `--col`, only show lines whose column is exactly VALUE
+    #[clap(long, value_name = "VALUE", group = "column_test", requires = "col")]
+    equals: Option<String>,
+    /// With `--col`, only show lines whose column contains TEXT
+    #[clap(long, value_name = "TEXT", group = "column_test", requires = "col")]
+    contains: Option<String>,
+    /// Strip whitespace from both ends of each selected line
+    #[clap(long, conflicts_with_all = ["trim_start", "trim_end"])]
+    trim: bool,
+    /// Strip whitespace from the start of each selected line
+    #[clap(long, conflicts_with = "trim_end")]
+    trim_start: bool,
+    /// Strip whitespace from the end of each selected line
+    #[clap(long)]
+    trim_end: bool,
+    /// Replace each tab in the output with STR, like `, `. This is a plain replacement, so it
+    /// doesn't line anything up the way tab stops would
+    #[clap(long, value_name = "STR")]
+    replace_tabs_with: Option<String>,
+    /// Replace text in each line shown, like sed's `s/PATTERN/REPLACEMENT/`. `&` in REPLACEMENT
+    /// stands for the matched text, and a `g` at the end replaces every match instead of the
+    /// first. Use `\` to escape `/`, `&`, or `\`. PATTERN is plain text, not a regular expression
+    #[clap(long, value_name = "/PATTERN/REPLACEMENT/")]
+    replace: Option<Substitution>,
+    /// Leave empty lines out of the output
+    #[clap(long)]
+    skip_empty: bool,
+    /// Leave lines that are empty or contain only whitespace out of the output
+    #[clap(long)]
+    skip_blank: bool,
+    /// Only show the selected lines that are empty. With `-n`, this lists where the empty lines
+    /// are
+    #[clap(long, conflicts_with_all = ["skip_empty", "skip_blank"])]
+    select_empty: bool,
+    /// With `--select-empty`, also show lines that contain only whitespace
+    #[clap(long, requires = "select_empty")]
+    with_whitespace: bool,
+    /// Leave lines shorter than N bytes out of the output, not counting the line terminator.
+    /// Bytes are counted after any `--encoding` is decoded, so a character may count as several
+    #[clap(long, value_name = "N")]
+    min_content_length: Option<usize>,
+    /// Leave lines longer than N bytes out of the output, counted as with
+    /// `--min-content-length`. Unlike `--max-line-length`, longer lines are quietly dropped
+    /// instead of being an error
+    #[clap(long, value_name = "N")]
+    max_content_length: Option<usize>,
+    /// Leave lines with any non-ASCII bytes out of the output, e.g. to clean up a log with lines
+    /// in mixed encodings
+    #[clap(long, conflicts_with = "strip_non_ascii")]
+    ascii_only: bool,
+    /// Remove non-ASCII bytes from each line, keeping the rest of the line
+    #[clap(long)]
+    strip_non_ascii: bool,
+    /// Leave out the first N lines that would otherwise be shown. Line numbers are unaffected
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    skip: usize,
+    /// Only show the Nth of the lines that would otherwise be shown, e.g. the 3rd line that
+    /// passes `--col`. Takes a range like LINES does, such as `2..=4` or `..:2`, counted before
+    /// `--skip`. Warns if there are fewer than N such lines
+    #[clap(long, value_name = "N", value_parser = parse_window)]
+    nth: Option<Pattern>,
+    /// Put a blank line between groups of lines that aren't next to each other in the input
+    #[clap(long, conflicts_with_all = ["count_bytes", "json_array", "json_meta"])]
+    group_by_gap: bool,
+    /// End each line with the same `\n` or `\r\n` it had in the input, instead of always `\n`.
+    /// A last line with neither gets whichever is more common near the start of the file
+    #[clap(long)]
+    line_terminator_auto: bool,
+    /// Show the selected lines of each file run together on a single line, with nothing between
+    /// them, e.g. to put back together text that was wrapped
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "show_line_number",
+            "number_nonblank",
+            "format",
+            "table",
+            "count_bytes",
+            "count_matches",
+            "count_nonempty",
+            "json_array",
+            "json_meta",
+            "show_labels",
+            "uniq_count",
+            "group_by_gap",
+            "chunk",
+        ]
+    )]
+    one_line: bool,
+    /// Show each line after its line number, with the numbers lined up in a column. Nothing is
+    /// shown until the selection has been read in full
+    #[clap(
+        long,
+        conflicts_with_all = [
+            "show_line_number",
+            "number_nonblank",
+            "format",
+            "count_bytes",
+            "json_array",
+            "json_meta",
+            "uniq_count",
+            "show_labels",
+            "chunk",
+            "group_by_gap",
+            "around",
+        ]
+    )]
+    table: bool,
+    /// With `--table`, cut each row off after N characters, so it fits in the terminal
+    #[clap(long, value_name = "N", requires = "table")]
+    max_width: Option<NonZeroUsize>,
+    /// The lines or ranges of lines to display, separated by a comma
+    ///
+    /// # Examples
+    ///
+    /// "5" - show line 5  
+    /// "1,6,7" - show lines 1, 6, and 7
+    /// "5..7" - Show lines 5 and 6  
+    /// "5..=7" - Show lines 5, 6, and 7  
+    /// "1,5..7" - Show lines 1, 5, and 6
+    /// ".." - Show all lines
+    /// "5.." - Show all after and including 5
+    /// "..7" - Show all lines up to 7, excluding 7
+    /// "..=7" - Show all lines up to 7, including 7
+    /// "1_000..2_000" - Underscores may separate digits
+    /// "0x10..0x20" - Numbers may be hexadecimal, or octal or binary with `0o` or `0b`
+    /// "HEADER,BODY.." - Show lines using names defined with `--anchors`
+    /// "+1..=+5" - With `--after`, show the 5 lines after the matching line
+    /// "1..=3#header,10..#body" - Anything after a `#` is a label, and is otherwise ignored
+    /// "1..=9:2" - Show every other line from 1 to 9
+    /// "10..=2:-2" - Show lines 10, 8, 6, 4, and 2, in that order
+    /// "$" - Show the last line. `5..$` shows line 5 through the last line
+    ///
+    /// # Note
+    ///
+    /// Lines must be specified in order. This restriction might be lifted in the future.
+    #[clap(
+        verbatim_doc_comment,
+        required_unless_present_any = [
+            "summary",
+            "patterns_from_stdin",
+            "between",
+            "bytes",
+            "server",
+        ]
+    )]
+    lines: Option<String>,
+    /// The files to read, each in turn. Use `-` or nothing to read from stdin. Put `--` before
+    /// LINES to pass a file whose name starts with `-`, e.g. `line -- 1..3 -notes.txt`
+    files: Vec<PathBuf>,
+    /// With multiple files, show the first line of each file, then the second line of each, and
+    /// so on
+    #[clap(long, conflicts_with_all = ["tail", "complement_to", "ranges_only", "summary", "after"])]
+    zip: bool,
+    /// Hold at most N input files open at once, so a large number of files doesn't run out of
+    /// file descriptors. Files are normally read one at a time anyway, but `--zip` reads them all
+    /// together. Past the limit, it opens files again for each read instead, so these must be
+    /// regular files, not stdin or a pipe
+    #[clap(long, value_name = "N", default_value = "256")]
+    max_open_files: NonZeroUsize,
+    /// Instead of selecting lines, describe the shape of the input. Takes no LINES argument, so
+    /// the file may be given in its place
+    #[clap(long)]
+    summary: bool,
+    /// Read LINES from stdin instead of from the command line, so the file may be given in its
+    /// place. Patterns may be separated by newlines as well as commas
+    #[clap(long, conflicts_with = "summary")]
+    patterns_from_stdin: bool,
+    /// Expand braces in LINES like a shell does, so `{1..3}` means `1,2,3` and `1{0,5}..` means
+    /// `10..,15..`
+    #[clap(long)]
+    brace_expand: bool,
+    /// Instead of selecting by line number, show the lines from the first one containing START
+    /// to the next one containing END. Takes no LINES argument, so the file may be given in its
+    /// place
+    #[clap(
+        long,
+        num_args = 2,
+        value_names = ["START", "END"],
+        conflicts_with_all = [
+            "summary",
+            "patterns_from_stdin",
+            "zip",
+            "ranges_only",
+            "watch",
+            "after",
+            "complement_to",
+        ]
+    )]
+    between: Option<Vec<String>>,
+    /// With `--between`, include the START and END lines. This is the default
+    #[clap(long, overrides_with = "exclusive", requires = "between")]
+    inclusive: bool,
+    /// With `--between`, leave out the START and END lines
+    #[clap(long, overrides_with = "inclusive", requires = "between")]
+    exclusive: bool,
+    /// With `--between`, fail if no line after START contains END. Otherwise, show everything
+    /// up to the end of the file
+    #[clap(long, requires = "between")]
+    require_end: bool,
+    /// Only show the lines LINES selects that contain TEXT, along with `--context` lines around
+    /// them, whether or not those are selected. `--within` limits where to look, context included
+    #[clap(
+        long,
+        value_name = "TEXT",
+        conflicts_with_all = [
+            "summary",
+            "patterns_from_stdin",
+            "between",
+            "zip",
+            "ranges_only",
+            "watch",
+            "after",
+            "tail",
+            "last_unique",
+            "complement_to",
+            "context_file",
+        ]
+    )]
+    around: Option<String>,
+    /// Instead of selecting lines, copy out the bytes in RANGE, which is written like LINES but
+    /// counts bytes from 1. Takes no LINES argument, so the file may be given in its place
+    #[clap(
+        long,
+        value_name = "RANGE",
+        value_parser = parse_byte_range,
+        conflicts_with_all = [
+            "summary",
+            "patterns_from_stdin",
+            "between",
+            "around",
+            "zip",
+            "ranges_only",
+            "watch",
+            "after",
+            "within",
+        ]
+    )]
+    bytes: Option<Pattern>,
+    /// With `--bytes`, write the selected bytes from last to first. This is a plain reversal of
+    /// bytes that knows nothing of endianness or characters, unlike `--reverse-file`, which
+    /// reverses lines
+    #[clap(long, requires = "bytes")]
+    reverse_bytes: bool,
+    /// Instead of showing lines, show the ranges the pattern selects as `START-END` pairs, with
+    /// open ends resolved against the input
+    #[clap(long, conflicts_with_all = ["summary", "after"])]
+    ranges_only: bool,
+    /// Instead of showing the selected lines, write the lines each pattern in LINES selects to a
+    /// file of its own. `%d` in TEMPLATE is replaced with the pattern's index, starting at 0, and
+    /// `%%` with `%`. Lines selected by more than one pattern go to each of their files
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with_all = [
+            "zip",
+            "watch",
+            "summary",
+            "ranges_only",
+            "between",
+            "around",
+            "tail",
+            "last_unique",
+            "within",
+            "after",
+            "show_labels",
+            "chunk",
+            "output",
+            "complement_to",
+            "context_file",
+        ]
+    )]
+    split_ranges: Option<String>,
+}
+
+// Fill in the limits `--preview` implies, unless they were given. The terminal width comes from
+// `columns`, as in the `COLUMNS` environment variable
+fn apply_preview(args: &mut Args, columns: Option<&str>) {
+    const PREVIEW_LINES: NonZeroUsize = NonZeroUsize::new(20).unwrap();
+    const DEFAULT_WIDTH: NonZeroUsize = NonZeroUsize::new(80).unwrap();
+
+    if !args.preview {
+        return;
+    }
+    args.max_lines.get_or_insert(PREVIEW_LINES);
+    args.truncate.get_or_insert_with(|| {
+        columns
+            .and_then(|columns| columns.trim().parse().ok())
+            .unwrap_or(DEFAULT_WIDTH)
+    });
+}
+
+fn main() -> Result<()> {
+    let mut args = Args::parse();
+    apply_preview(&mut args, env::var("COLUMNS").ok().as_deref());
+    // Bytes aren't read as lines, so there's no line to skip
+    if args.bytes.is_some() && args.on_error == ReadErrorPolicy::Skip {
+        bail!("--on-error=skip can't be used with --bytes");
+    }
+    let anchors = if let Some(anchors) = args.anchors {
+        parse_anchors(&fs::read_to_string(anchors)?)?
+    } else {
+        HashMap::new()
+    };
+
+    // On the first Ctrl-C, stop reading and flush. On the second, give up immediately
+    let stop = Arc::new(AtomicBool::new(false));
+    #[cfg(feature = "signal-hook")]
+    {
+        use signal_hook::{consts::SIGINT, flag};
+        flag::register_conditional_shutdown(SIGINT, 130, Arc::clone(&stop))?;
+        flag::register(SIGINT, Arc::clone(&stop))?;
+    }
+
+    if args.count_matches.as_deref() == Some("") {
+        bail!("Text to count must not be empty");
+    }
+
+    // Whatever would go to stderr along the way goes here instead. Lines are written whole, so
+    // nothing is lost if we exit early
+    let errors_to = args.errors_to.as_deref().map(File::create).transpose()?;
+    let diagnostics_sink = || -> Result<Box<dyn Write>> {
+        Ok(match &errors_to {
+            Some(file) => Box::new(LineWriter::new(file.try_clone()?)),
+            None => Box::new(io::stderr()),
+        })
+    };
+    let diagnostics = Diagnostics(RefCell::new(diagnostics_sink()?));
+
+    let options = Options {
+        show_line_number: args.show_line_number,
+        anchors,
+        max_line_length: args.max_line_length,
+        tail: args.tail,
+        number_nonblank: match (args.number_nonblank, args.true_numbers) {
+            (false, _) => None,
+            (true, false) => Some(NonblankNumbering::Count),
+            (true, true) => Some(NonblankNumbering::LineNumber),
+        },
+        stop: Arc::clone(&stop),
+        complement: if let Some(path) = args.complement_to {
+            Some(RefCell::new(Box::new(BufWriter::new(File::create(path)?))))
+        } else {
+            None
+        },
+        context: if let Some(path) = args.context_file {
+            Some(Context {
+                lines: args.context,
+                sink: RefCell::new(Box::new(BufWriter::new(File::create(path)?))),
+            })
+        } else {
+            None
+        },
+        #[cfg(feature = "encoding")]
+        transcoding: args.encoding.map(|encoding| Transcoding {
+            encoding,
+            on_error: args.on_decode_error,
+            reencode: args.reencode,
+        }),
+        fields: args
+            .fields
+            .map(|fields| Fields::new(&fields, &args.field_sep))
+            .transpose()?,
+        trim: match (args.trim, args.trim_start, args.trim_end) {
+            (true, _, _) => Some(Trim::Both),
+            (false, true, _) => Some(Trim::Start),
+            (false, false, true) => Some(Trim::End),
+            (false, false, false) => None,
+        },
+        #[cfg(feature = "base64")]
+        base64: args.base64_decode.then_some(Base64Decoding {
+            on_error: args.on_base64_error,
+            concat: args.base64_concat,
+        }),
+        #[cfg(feature = "pretty-json")]
+        pretty_json: args.pretty_json.then_some(args.on_invalid_json),
+        substitution: args.replace,
+        tab_replacement: args.replace_tabs_with,
+        truncate: args.truncate,
+        wrap: args.wrap.map(|width| Wrap {
+            width,
+            indent: args.wrap_indent,
+        }),
+        max_lines: args.max_lines,
+        byte_budget: args.byte_budget,
+        column_filter: args.col.map(|column| ColumnFilter {
+            column,
+            separator: args.col_sep.map(String::into_bytes),
+            test: match (args.equals, args.contains) {
+                (Some(value), _) => ColumnTest::Equals(value),
+                (None, Some(text)) => ColumnTest::Contains(text),
+                (None, None) => unreachable!("Clap requires a test with `--col`"),
+            },
+        }),
+        min_content_length: args.min_content_length,
+        max_content_length: args.max_content_length,
+        ascii_only: args.ascii_only,
+        strip_non_ascii: args.strip_non_ascii,
+        skip: match (args.skip_empty || args.count_nonempty, args.skip_blank) {
+            (_, true) => Some(Skip::Blank),
+            (true, false) => Some(Skip::Empty),
+            (false, false) => None,
+        },
+        select_empty: match (args.select_empty, args.with_whitespace) {
+            (true, true) => Some(Skip::Blank),
+            (true, false) => Some(Skip::Empty),
+            (false, _) => None,
+        },
+        tee: if let Some(path) = args.tee {
+            Some(RefCell::new(Box::new(BufWriter::new(File::create(path)?))))
+        } else {
+            None
+        },
+        collapse_ranges: if args.collapse_ranges {
+            Some(RefCell::new(diagnostics_sink()?))
+        } else {
+            None
+        },
+        hunk_headers: args.hunk_headers,
+        repeat: if let Some(path) = args.repeat_file {
+            Some(Repeat {
+                count: args.repeat_count,
+                sink: RefCell::new(Box::new(BufWriter::new(File::create(path)?))),
+                selection: RefCell::default(),
+            })
+        } else {
+            None
+        },
+        after: args.after,
+        format: args.format,
+        count_bytes: match (args.count_bytes, args.with_terminators) {
+            (false, _) => None,
+            (true, false) => Some(ByteCounting::Content),
+            (true, true) => Some(ByteCounting::WithTerminators),
+        },
+        count_matches: args.count_matches,
+        count_distinct: args.count_distinct,
+        count_lines: args.count_nonempty,
+        numbers_only: args.numbers_only,
+        last_unique: args.last_unique,
+        sort: (args.sort || args.numeric_sort || args.reverse_sort).then_some(Sort {
+            numeric: args.numeric_sort,
+            reverse: args.reverse_sort,
+        }),
+        show_labels: args.show_labels,
+        theme: match args.color {
+            ColorWhen::Auto => args.output.is_none() && io::stdout().is_terminal(),
+            ColorWhen::Always => true,
+            ColorWhen::Never => false,
+        }
+        .then(|| theme_from_env(env::var("DAGAN_LINE_COLORS").ok().as_deref(), &diagnostics))
+        .transpose()?,
+        hash_sample: args.hash_sample,
+        hash_prefix: args.hash_prefix,
+        pattern_separator: args.pattern_separator,
+        number_radix: args.number_radix,
+        number_shift: args.numbered_from.map_or(0, |first| first.get() - 1),
+        renumber_map: args
+            .renumber_map
+            .map(|path| parse_renumber_map(&fs::read_to_string(path)?))
+            .transpose()?,
+        limit_per_range: args.limit_per_range,
+        within: args.within,
+        offset: args.skip,
+        nth: args.nth,
+        group_by_gap: args.group_by_gap,
+        line_terminator_auto: args.line_terminator_auto,
+        one_line: args.one_line,
+        start_at_byte: args.start_at_byte,
+        table: args.table.then_some(Table {
+            max_width: args.max_width,
+        }),
+        on_read_error: args.on_error,
+        open_timeout: args.open_timeout,
+        open_files: Some(OpenFiles::new(args.max_open_files)),
+        stdin_name: Some(args.stdin_name),
+        unique: args.unique,
+        uniq: match (args.uniq, args.uniq_count) {
+            (_, true) => Some(Uniq::Count),
+            (true, false) => Some(Uniq::Collapse),
+            (false, false) => None,
+        },
+        chunk: args.chunk.map(|size| Chunk {
+            size,
+            prefix: args.chunk_prefix,
+            file: RefCell::new(None),
+        }),
+        selected_any: Cell::new(false),
+        emitted: Cell::new(0),
+        budget_used: Cell::new(0),
+        unbuffered: args.unbuffered,
+        bom: Cell::new(args.bom),
+        binary: args
+            .binary
+            .unwrap_or(if args.output.is_none() && io::stdout().is_terminal() {
+                BinaryPolicy::Warn
+            } else {
+                BinaryPolicy::Print
+            }),
+        json: match (args.json_array, args.json_meta) {
+            (_, true) => Some(JsonOutput::Meta),
+            (true, false) => Some(JsonOutput::Array),
+            (false, false) => None,
+        },
+        diagnostics,
+    };
+
+    // Without a LINES argument, all positionals are files
+    let (lines, mut files) = if args.summary
+        || args.patterns_from_stdin
+        || args.between.is_some()
+        || args.bytes.is_some()
+        || args.server.is_some()
+    {
+        let mut files = args.files;
+        if let Some(lines) = args.lines {
+            files.insert(0, lines.into());
+        }
+        if !args.patterns_from_stdin {
+            (None, files)
+        } else {
+            if files.is_empty() || files.iter().any(|path| path.to_str() == Some("-")) {
+                bail!("A file must be given when reading patterns from stdin");
+            }
+            (
+                Some(read_patterns(
+                    io::stdin().lock(),
+                    options.pattern_separator(),
+                )?),
+                files,
+            )
+        }
+    } else {
+        (args.lines, args.files)
+    };
+    let lines = if args.brace_expand {
+        lines
+            .map(|lines| expand_braces(&lines, options.pattern_separator()))
+            .transpose()?
+    } else {
+        lines
+    };
+    let file_list = match (&args.files_from, &args.files_from0) {
+        (Some(list), _) => Some((list, b'\n')),
+        (_, Some(list)) => Some((list, b'\0')),
+        (None, None) => None,
+    };
+    if let Some((list, separator)) = file_list {
+        let listed = if list.to_str() == Some("-") {
+            read_file_list(io::stdin().lock(), separator)?
+        } else {
+            read_file_list(File::open(list)?, separator)?
+        };
+        if list.to_str() == Some("-") && listed.iter().any(|path| path.to_str() == Some("-")) {
+            bail!("Can't read stdin as a file when the file list comes from stdin");
+        }
+        files.extend(listed);
+    } else if files.is_empty() {
+        files.push(PathBuf::from("-"));
+    }
+
+    if args.watch {
+        let ([path], Some(lines)) = (&files[..], &lines) else {
+            bail!("Watching needs exactly one file");
+        };
+        if path.to_str() == Some("-") {
+            bail!("Can't watch stdin");
+        }
+        watch(path, io::stdout().lock(), lines, &options, args.interval)?;
+        process::exit(130);
+    }
+
+    if let Some(template) = &args.split_ranges {
+        let ([path], Some(lines)) = (&files[..], &lines) else {
+            bail!("Splitting ranges into files needs exactly one file");
+        };
+        let fin = open_input(path, &options, args.reverse_file)?;
+        return split_ranges(fin, lines, template, &options);
+    }
+
+    #[cfg(feature = "gzip")]
+    let gzip_output = args.gzip_output;
+    #[cfg(not(feature = "gzip"))]
+    let gzip_output = false;
+    let pager = args.paginate.then(|| pager_command(env::var("PAGER").ok()));
+    let (fout, pager) = open_paged_output(
+        pager.as_deref(),
+        args.output.as_deref(),
+        gzip_output,
+        &options,
+    )?;
+    let mut fout = match args.output_buffer_size {
+        Some(size) => BufWriter::with_capacity(size.get(), fout),
+        None => BufWriter::new(fout),
+    };
+    // Quitting the pager can cut any of this short
+    let mut write_output = || -> Result<bool> {
+        if let Some(control) = &args.server {
+            let [path] = &files[..] else {
+                bail!("Serving lines needs exactly one file");
+            };
+            if path.to_str() == Some("-") {
+                bail!("Can't serve lines from stdin");
+            }
+            let control: Box<dyn BufRead> = if control.to_str() == Some("-") {
+                Box::new(io::stdin().lock())
+            } else {
+                Box::new(BufReader::new(open_file(control, options.open_timeout)?))
+            };
+            return serve(path, control, &mut fout, &options).map(|()| true);
+        }
+        let open = |path: &Path| open_input(path, &options, args.reverse_file);
+        let all_ok = if let Some(lines) = &lines {
+            if args.zip {
+                let mut fins = Vec::new();
+                let all_ok = each_input(
+                    &files,
+                    args.keep_going,
+                    &options,
+                    &options.diagnostics,
+                    |path| {
+                        fins.push(open_for_zip(path, &options, args.reverse_file)?);
+                        Ok(())
+                    },
+                )?;
+                zip_lines(fins, &mut fout, lines, &options)?;
+                all_ok
+            } else if let Some(around) = &args.around {
+                each_input(
+                    &files,
+                    args.keep_going,
+                    &options,
+                    &options.diagnostics,
+                    |path| {
+                        write_around(
+                            open(path)?,
+                            &mut fout,
+                            lines,
+                            around,
+                            args.context,
+                            &options,
+                        )
+                    },
+                )?
+            } else {
+                if args.index_file.is_some() && (files.len() != 1 || files[0].to_str() == Some("-"))
+                {
+                    bail!("An index can only be used with exactly one file");
+                }
+                each_input(
+                    &files,
+                    args.keep_going,
+                    &options,
+                    &options.diagnostics,
+                    |path| {
+                        // Each file's output may go to a file of its own
+                        let mut own_output;
+                        let fout: &mut dyn Write = match &args.out_template {
+                            Some(template) => {
+                                own_output =
+                                    create_templated_output(path, template, args.create_dirs)?;
+                                &mut own_output
+                            }
+                            None => &mut fout,
+                        };
+                        if args.ranges_only {
+                            write_ranges(open(path)?, fout, lines, &options)
+                        } else if let Some(index) = &args.index_file {
+                            write_indexed(path, index, args.build_index, fout, lines, &options)
+                        } else {
+                            write_lines(open(path)?, fout, lines, &options)
+                        }
+                    },
+                )?
+            }
+        } else if let Some(between) = &args.between {
+            let [start, end] =
+                <[String; 2]>::try_from(between.clone()).expect("Clap takes two values");
+            let between = Between {
+                start,
+                end,
+                exclusive: args.exclusive,
+                require_end: args.require_end,
+            };
+            each_input(
+                &files,
+                args.keep_going,
+                &options,
+                &options.diagnostics,
+                |path| write_between(open(path)?, &mut fout, &between, &options),
+            )?
+        } else if let Some(range) = &args.bytes {
+            each_input(
+                &files,
+                args.keep_going,
+                &options,
+                &options.diagnostics,
+                |path| write_bytes(open(path)?, &mut fout, range, args.reverse_bytes, &options),
+            )?
+        } else {
+            each_input(
+                &files,
+                args.keep_going,
+                &options,
+                &options.diagnostics,
+                |path| write_summary(open(path)?, &mut fout, &options),
+            )?
+        };
+
+        if let Some(repeat) = &options.repeat {
+            repeat.finish()?;
+        }
+        fout.flush()?;
+        Ok(all_ok)
+    };
+    let written = write_output();
+    // Exiting skips destructors, so the output has to be finished first, even on Ctrl-C.
+    // Closing the pipe lets the pager know there's nothing more to show
+    let finished = fout
+        .into_inner()
+        .map_err(io::IntoInnerError::into_error)
+        .and_then(OutputFile::finish);
+    let written = written.and_then(|all_ok| {
+        finished?;
+        Ok(all_ok)
+    });
+    let all_ok = match pager {
+        Some(pager) => {
+            let (status, all_ok) = wait_for_pager(pager, written)?;
+            if !status.success() {
+                process::exit(status.code().unwrap_or(1));
+            }
+            all_ok
+        }
+        None => written?,
+    };
+
+    // Conventional exit code for SIGINT
+    if stop.load(Ordering::Relaxed) {
+        process::exit(130);
+    }
+    if !all_ok {
+        process::exit(1);
+    }
+    if !options.selected_any.get() && args.exit_code_on_empty != 0 {
+        process::exit(args.exit_code_on_empty.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_util;
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+    use std::{io::Cursor, rc::Rc};
+
+    use super::*;
+    use crate::test_util::TempDir;
+
+    // A writer whose contents can still be inspected after it's been handed off
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl SharedBuffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Give a file new contents all at once, so something watching it never sees it half written
+    fn replace_file(path: &Path, contents: &str) -> io::Result<()> {
+        let staged = path.with_extension("staged");
+        fs::write(&staged, contents)?;
+        fs::rename(&staged, path)
+    }
+
+    #[test]
+    fn dash_file_names() -> Result<()> {
+        let dir = TempDir::new("dash")?;
+        let path = dir.join("-weird-name");
+        fs::write(&path, "a\nb\nc\n")?;
+
+        // Everything after `--` is positional, even if it looks like a flag or a range
+        let tvs: &[(&[&str], &str, &[&str])] = &[
+            (&["--", "2", "-weird-name"], "2", &["-weird-name"]),
+            (
+                &["2", "--", "-weird-name", "5..7"],
+                "2",
+                &["-weird-name", "5..7"],
+            ),
+            (&["--", "-1", "-"], "-1", &["-"]),
+        ];
+        for tv in tvs {
+            let args = Args::try_parse_from([&["line"], tv.0].concat())?;
+            assert_eq!(args.lines.as_deref(), Some(tv.1), "{tv:?}");
+            assert_eq!(
+                args.files,
+                tv.2.iter().map(PathBuf::from).collect::<Vec<_>>()
+            );
+        }
+        assert!(Args::try_parse_from(["line", "2", "-weird-name"]).is_err());
+
+        // Only `-` itself means stdin
+        let options = Options::default();
+        let mut fout = Vec::new();
+        write_lines(
+            open_input(&path, &options, false)?,
+            &mut fout,
+            "2",
+            &options,
+        )?;
+        assert_eq!(String::from_utf8(fout)?, "b\n");
+        Ok(())
+    }
+
+    #[test]
+    fn pager_command_default() {
+        assert_eq!(pager_command(None), "less -R");
+        assert_eq!(pager_command(Some(" ".into())), "less -R");
+        assert_eq!(pager_command(Some("more".into())), "more");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn paginate() -> Result<()> {
+        let dir = TempDir::new("paginate")?;
+        let paged = dir.join("paged");
+        let output = dir.join("output");
+        let options = Options::default();
+
+        // A stand-in pager that saves what it's given, instead of the output file
+        let command = format!("cp /dev/stdin {}", paged.display());
+        let (fout, pager) = open_paged_output(Some(&command), Some(&output), false, &options)?;
+        let pager = pager.expect("Could not start stub pager");
+        let written = write_lines(Cursor::new("a\nb\nc\n"), fout, "2..", &options);
+        let (status, all_ok) = wait_for_pager(pager, written.map(|()| true))?;
+        assert!(status.success() && all_ok);
+        assert_eq!(fs::read_to_string(&paged)?, "b\nc\n");
+        assert!(!output.exists());
+
+        // Quitting before the end isn't an error, however much is left to write
+        for (command, success) in [("true", true), ("false", false)] {
+            let (fout, pager) = open_paged_output(Some(command), None, false, &options)?;
+            let pager = pager.expect("Could not start stub pager");
+            let written = write_lines(io::repeat(b'\n'), fout, "..", &options);
+            let (status, all_ok) = wait_for_pager(pager, written.map(|()| true))?;
+            assert_eq!(status.success(), success, "{command}");
+            assert!(all_ok, "{command}");
+        }
+
+        // Without a pager, the output goes where it would have anyway
+        let errors = SharedBuffer::default();
+        let options = Options {
+            diagnostics: Diagnostics(RefCell::new(Box::new(errors.clone()))),
+            ..Default::default()
+        };
+        let (fout, pager) = open_paged_output(
+            Some("/nonexistent/pager -R"),
+            Some(&output),
+            false,
+            &options,
+        )?;
+        assert!(pager.is_none());
+        write_lines(Cursor::new("a\nb\n"), fout, "2", &options)?;
+        assert_eq!(fs::read_to_string(&output)?, "b\n");
+        assert!(errors.contents().contains("could not start pager"));
+
+        assert!(spawn_pager("").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn lines_must_be_specified_in_order() {
+        let failing_patterns = [
+            ("4,4", false),
+            ("4,5", false),
+            ("5,4", true),
+            ("1..9,4", true),
+            ("8..9,4", true),
+            ("2..4,1", true),
+            ("2..4,4", false),
+            ("2..=4,4", false),
+        ];
+
+        for tv in failing_patterns {
+            let fin = Cursor::new(String::from("Foo\nBar\nBaz"));
+            let mut fout = Vec::new();
+            let patterns = tv.0;
+            let should_error = tv.1;
+            assert_eq!(
+                should_error,
+                write_lines(fin, &mut fout, patterns, &Default::default()).is_err()
+            );
+        }
+    }
+
+    #[test]
+    fn select_lines() -> Result<()> {
+        let tvs: &[(&str, &str, &[&str])] = &[
+            ("", "1,2,2", &[]),
+            ("Foo\nBar", "1,2,2", &["Foo", "Bar", "Bar"]),
+            ("Foo\nBar\nBaz", "..", &["Foo", "Bar", "Baz"]),
+            ("Foo\nBar\nBaz", "1..", &["Foo", "Bar", "Baz"]),
+            ("Foo\nBar\nBaz", "2..", &["Bar", "Baz"]),
+            ("Foo\nBar\nBaz", "2..3", &["Bar"]),
+            ("Foo\nBar\nBaz", "2..=3", &["Bar", "Baz"]),
+            ("Foo\nBar\nBaz", "..=3", &["Foo", "Bar", "Baz"]),
+            ("Foo\nBar\nBaz", "..3", &["Foo", "Bar"]),
+            ("Foo\nBar\nBaz", "..3,3,3", &["Foo", "Bar", "Baz", "Baz"]),
+            ("Foo\nBar", "..", &["Foo", "Bar"]),
+            ("Foo\nBar\n", "..", &["Foo", "Bar"]),
+            ("Foo\nBar\n\n", "..", &["Foo", "Bar", ""]),
+            ("Foo\nBar\nBaz", "1,2..", &["Foo", "Bar", "Baz"]),
+        ];
+
+        for tv in tvs {
+            let fin = Cursor::new(String::from(tv.0));
+            let mut fout = Vec::new();
+            let patterns = tv.1;
+            write_lines(fin, &mut fout, patterns, &Default::default())?;
+
+            let actual_lines = String::from_utf8(fout)?;
+            let actual_lines = actual_lines.lines().collect::<Vec<_>>();
+            for (expected_line, actual_line) in tv.2.iter().zip(actual_lines.iter()) {
+                assert_eq!(expected_line, actual_line);
+            }
+            assert_eq!(tv.2.len(), actual_lines.len());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn labels_are_ignored() -> Result<()> {
+        let options = Options::default();
+        let patterns = parse_patterns("1..=3#header,5#,7..# the body ", &options)?;
+        assert_eq!(patterns.len(), 3);
+        assert_eq!(patterns[0].start.unwrap().get(), 1);
+        assert_eq!(patterns[0].end.unwrap().get(), 3);
+        assert_eq!(patterns[0].label.as_deref(), Some("header"));
+        assert_eq!(patterns[1].start.unwrap().get(), 5);
+        assert_eq!(patterns[1].label, None);
+        assert_eq!(patterns[2].end, None);
+        assert_eq!(patterns[2].label.as_deref(), Some("the body"));
+
+        let fin = Cursor::new(String::from("Foo\nBar\nBaz"));
+        let mut fout = Vec::new();
+        write_lines(fin, &mut fout, "1#first,3#last", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "Foo\nBaz\n");
+
+        assert!(parse_patterns("#header", &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn show_labels() -> Result<()> {
+        let tvs = [
+            (
+                "1..=2#head,4..#tail",
+                "==> head <==\nFoo\nBar\n==> tail <==\nQux\nQuux\n",
+            ),
+            ("1#head,3", "==> head <==\nFoo\nBaz\n"),
+            ("2,2#again", "==> again <==\nBar\nBar\n"),
+            ("9..#missing", ""),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from("Foo\nBar\nBaz\nQux\nQuux\n"));
+            let mut fout = Vec::new();
+            let options = Options {
+                show_labels: true,
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn color_theme() -> Result<()> {
+        let theme = "number=cyan:label=red".parse::<Theme>()?;
+        assert_eq!(
+            theme,
+            Theme {
+                number: 36,
+                label: 31,
+                ..Default::default()
+            }
+        );
+        assert_eq!("".parse::<Theme>()?, Theme::default());
+        assert!("number".parse::<Theme>().is_err());
+        assert!("number=teal".parse::<Theme>().is_err());
+        assert!("line=red".parse::<Theme>().is_err());
+
+        // A bad spec falls back to the default colors
+        assert_eq!(theme_from_env(None, io::sink())?, Theme::default());
+        assert_eq!(
+            theme_from_env(Some("number=teal"), io::sink())?,
+            Theme::default()
+        );
+        assert_eq!(theme_from_env(Some("label=red"), io::sink())?.label, 31);
+
+        let mut fout = Vec::new();
+        let options = Options {
+            show_line_number: true,
+            show_labels: true,
+            theme: Some(theme),
+            ..Default::default()
+        };
+        write_lines(Cursor::new("a\nb\nc\n"), &mut fout, "1#top,3", &options)?;
+        assert_eq!(
+            String::from_utf8(fout)?,
+            "\x1b[31m==> top <==\x1b[0m\n\x1b[36m1\x1b[0m\ta\n\x1b[36m3\x1b[0m\tc\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn steps() -> Result<()> {
+        let input = (1..=12).map(|i| format!("{i}\n")).collect::<String>();
+        let tvs: &[(&str, &[&str])] = &[
+            ("1..=9:2", &["1", "3", "5", "7", "9"]),
+            ("2..:5", &["2", "7", "12"]),
+            ("10..=2:-2", &["10", "8", "6", "4", "2"]),
+            ("10..2:-2", &["10", "8", "6", "4"]),
+            ("5..=1:-3", &["5", "2"]),
+            ("1,4..=2:-1,4..=6", &["1", "4", "3", "2", "4", "5", "6"]),
+            ("3..=1:-1,5..=4:-1", &["3", "2", "1", "5", "4"]),
+            ("11..=20:3,20", &["11"]),
+            ("20..=11:-3", &["11"]),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(&input);
+            let mut fout = Vec::new();
+            write_lines(fin, &mut fout, tv.0, &Default::default())?;
+            let actual_lines = String::from_utf8(fout)?;
+            assert_eq!(actual_lines.lines().collect::<Vec<_>>(), tv.1, "{}", tv.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn number_radix() -> Result<()> {
+        let input = (1..=20).map(|i| format!("{i}\n")).collect::<String>();
+        let tvs = [
+            (NumberRadix::Dec, "9\t9\n10\t10\n"),
+            (NumberRadix::Hex, "0x9\t9\n0xa\t10\n"),
+            (NumberRadix::Oct, "0o11\t9\n0o12\t10\n"),
+            (NumberRadix::Bin, "0b1001\t9\n0b1010\t10\n"),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(&input);
+            let mut fout = Vec::new();
+            let options = Options {
+                show_line_number: true,
+                number_radix: tv.0,
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, "0x9..=0xa", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn numbered_from() -> Result<()> {
+        let tvs = [
+            (1, "3\tc\n5\te\n"),
+            (1000, "1002\tc\n1004\te\n"),
+            (usize::MAX, &format!("{0}\tc\n{0}\te\n", usize::MAX)),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new("a\nb\nc\nd\ne\n");
+            let mut fout = Vec::new();
+            let options = Options {
+                show_line_number: true,
+                number_shift: tv.0 - 1,
+                ..Default::default()
+            };
+            // Lines are still selected by their position in the file
+            write_lines(fin, &mut fout, "3,5", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+
+        // The real line numbers are shifted for `-b` too, but not the non-blank count
+        let input = "a\n\nb\n";
+        let tvs = [
+            (NonblankNumbering::Count, "1\ta\n\n2\tb\n"),
+            (NonblankNumbering::LineNumber, "10\ta\n\n12\tb\n"),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                number_nonblank: Some(tv.0),
+                number_shift: 9,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, "..", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn renumber_map() -> Result<()> {
+        let map = parse_renumber_map("# old new\n1 1\n3\t2\n\n 5  7 \n")?;
+        let tvs = [
+            ("..", 0, "1\t1\ta\n2\t-\tb\n3\t2\tc\n4\t-\td\n5\t7\te\n"),
+            ("3..", 0, "3\t2\tc\n4\t-\td\n5\t7\te\n"),
+            // Lines are looked up by their true number, not the one shown
+            ("3,5", 9, "12\t2\tc\n14\t7\te\n"),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                show_line_number: true,
+                number_shift: tv.1,
+                renumber_map: Some(map.clone()),
+                ..Default::default()
+            };
+            write_lines(Cursor::new("a\nb\nc\nd\ne\n"), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+
+        assert!(parse_renumber_map("1").is_err());
+        assert!(parse_renumber_map("1 2 3").is_err());
+        assert!(parse_renumber_map("1 x").is_err());
+        assert!(parse_renumber_map("1 2\n1 3").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn max_line_length() -> Result<()> {
+        let options = || Options {
+            max_line_length: Some(4),
+            ..Default::default()
+        };
+
+        let fin = Cursor::new(String::from("Foo\nBarr\r\nBaz"));
+        let mut fout = Vec::new();
+        write_lines(fin, &mut fout, "..", &options())?;
+        assert_eq!(String::from_utf8(fout)?, "Foo\nBarr\nBaz\n");
+
+        let fin = Cursor::new(String::from("Foo\nBarrr\nBaz"));
+        let mut fout = Vec::new();
+        let err = write_lines(fin, &mut fout, "..", &options()).unwrap_err();
+        assert_eq!(err.to_string(), "Line exceeds maximum length of 4 bytes");
+
+        // Even with no line terminator at all
+        let fin = Cursor::new(vec![b'x'; 100_000]);
+        let mut fout = Vec::new();
+        assert!(write_lines(fin, &mut fout, "..", &options()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn tail_parsing() {
+        assert_eq!(
+            "3".parse::<Tail>().unwrap(),
+            Tail {
+                count: 3,
+                offset: 0
+            }
+        );
+        assert_eq!(
+            "3+2".parse::<Tail>().unwrap(),
+            Tail {
+                count: 3,
+                offset: 2
+            }
+        );
+        assert_eq!(
+            "1+0".parse::<Tail>().unwrap(),
+            Tail {
+                count: 1,
+                offset: 0
+            }
+        );
+
+        for tv in [
+            "", "0", "0+5", "+", "3+", "+2", "3+2+1", "a+b", "-3", "3+-2",
+        ] {
+            assert!(tv.parse::<Tail>().is_err(), "{tv}");
+        }
+    }
+
+    #[test]
+    fn tail_window() -> Result<()> {
+        let tvs: &[(&str, &str, &[&str])] = &[
+            ("3", "..", &["8", "9", "10"]),
+            ("3+2", "..", &["6", "7", "8"]),
+            ("1+9", "..", &["1"]),
+            ("3+8", "..", &["1", "2"]),
+            ("3+10", "..", &[]),
+            (
+                "20",
+                "..",
+                &["1", "2", "3", "4", "5", "6", "7", "8", "9", "10"],
+            ),
+            ("2+1", "2..=6", &["4", "5"]),
+        ];
+
+        for tv in tvs {
+            let fin = Cursor::new(String::from("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n"));
+            let mut fout = Vec::new();
+            let options = Options {
+                tail: Some(tv.0.parse()?),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.1, &options)?;
+            let actual_lines = String::from_utf8(fout)?;
+            assert_eq!(actual_lines.lines().collect::<Vec<_>>(), tv.2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn number_nonblank() -> Result<()> {
+        let tvs = [
+            (
+                NonblankNumbering::Count,
+                "..",
+                "1\tFoo\n\n2\tBar\n\n3\tBaz\n",
+            ),
+            (
+                NonblankNumbering::LineNumber,
+                "..",
+                "1\tFoo\n\n3\tBar\n\n5\tBaz\n",
+            ),
+            (NonblankNumbering::Count, "2..", "\n1\tBar\n\n2\tBaz\n"),
+            (NonblankNumbering::LineNumber, "2..", "\n3\tBar\n\n5\tBaz\n"),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from("Foo\n\nBar\n\nBaz"));
+            let mut fout = Vec::new();
+            let options = Options {
+                show_line_number: true,
+                number_nonblank: Some(tv.0),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.1, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2);
+        }
+        Ok(())
+    }
+
+    // Hands out one line per read, raising the stop flag after a given line
+    struct StopAfter {
+        lines: VecDeque<&'static str>,
+        remaining: usize,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl Read for StopAfter {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some(line) = self.lines.pop_front() else {
+                return Ok(0);
+            };
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                self.stop.store(true, Ordering::Relaxed);
+            }
+            buf[..line.len()].copy_from_slice(line.as_bytes());
+            Ok(line.len())
+        }
+    }
+
+    // Hands out one piece per read, failing wherever there's no piece
+    struct FailingReads(VecDeque<Option<&'static str>>);
+
+    impl Read for FailingReads {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                None => Ok(0),
+                Some(None) => Err(io::Error::other("Bad read")),
+                Some(Some(piece)) => {
+                    buf[..piece.len()].copy_from_slice(piece.as_bytes());
+                    Ok(piece.len())
+                }
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn open_timeout() -> Result<()> {
+        let dir = TempDir::new("fifo")?;
+        let mkfifo = |name| -> Result<PathBuf> {
+            let fifo = dir.join(name);
+            let status = process::Command::new("mkfifo").arg(&fifo).status()?;
+            assert!(status.success());
+            Ok(fifo)
+        };
+        let timeout = Some(Duration::from_millis(100));
+
+        // Nothing's writing
+        let fifo = mkfifo("quiet")?;
+        assert!(open_file(&fifo, timeout).is_err());
+        // Let the abandoned open finish
+        drop(fs::OpenOptions::new().write(true).open(&fifo)?);
+
+        let fifo = mkfifo("busy")?;
+        let writer = thread::spawn({
+            let fifo = fifo.clone();
+            move || fs::write(fifo, "Foo\nBar\n")
+        });
+        let mut fout = Vec::new();
+        let fin = open_file(&fifo, Some(Duration::from_secs(60)))?;
+        write_lines(fin, &mut fout, "2", &Default::default())?;
+        writer.join().unwrap()?;
+        assert_eq!(String::from_utf8(fout)?, "Bar\n");
+
+        // Regular files are opened as usual
+        let file = dir.join("file");
+        fs::write(&file, "Foo\n")?;
+        open_file(&file, timeout)?;
+        Ok(())
+    }
+
+    #[test]
+    fn read_errors() -> Result<()> {
+        let reads = || {
+            FailingReads(VecDeque::from([
+                Some("Foo\n"),
+                None,
+                Some("Bar\n"),
+                Some("Baz\nQu"),
+                None,
+                None,
+                Some("ux\n"),
+            ]))
+        };
+        let options = Options::default();
+        assert!(write_lines(reads(), &mut Vec::new(), "..", &options).is_err());
+
+        let options = Options {
+            on_read_error: ReadErrorPolicy::Skip,
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(reads(), &mut fout, "..", &options)?;
+        // What was read of a line before an error is lost
+        assert_eq!(String::from_utf8(fout)?, "Foo\nBar\nBaz\nux\n");
+
+        // Other modes skip the same way
+        let between = Between {
+            start: "Bar".into(),
+            end: "ux".into(),
+            exclusive: false,
+            require_end: true,
+        };
+        assert!(write_between(reads(), &mut Vec::new(), &between, &Default::default()).is_err());
+        let mut fout = Vec::new();
+        write_between(reads(), &mut fout, &between, &options)?;
+        assert_eq!(String::from_utf8(fout)?, "Bar\nBaz\nux\n");
+
+        let fins = || {
+            vec![
+                reads(),
+                FailingReads(VecDeque::from([Some("a\nb\nc\nd\n")])),
+            ]
+        };
+        assert!(zip_lines(fins(), &mut Vec::new(), "..", &Default::default()).is_err());
+        let mut fout = Vec::new();
+        zip_lines(fins(), &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "Foo\na\nBar\nb\nBaz\nc\nux\nd\n");
+
+        let mut errors = Vec::new();
+        let mut buf = Vec::new();
+        let mut fin = BufReader::new(reads());
+        read_line_or_skip(&mut fin, &mut buf, &options, 1, &mut errors)?;
+        read_line_or_skip(&mut fin, &mut buf, &options, 2, &mut errors)?;
+        assert_eq!(buf, b"Bar\n");
+        assert_eq!(
+            String::from_utf8(errors)?,
+            "Error reading line 2, skipping: Bad read\n"
+        );
+
+        // Input that only ever fails is eventually given up on
+        let fin = FailingReads(VecDeque::from(vec![None; 1000]));
+        assert!(write_lines(fin, &mut Vec::new(), "..", &options).is_err());
+
+        // Only read errors are skipped
+        let options = Options {
+            on_read_error: ReadErrorPolicy::Skip,
+            max_line_length: Some(2),
+            ..Default::default()
+        };
+        assert!(write_lines(reads(), &mut Vec::new(), "..", &options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stop_flushes_output() -> Result<()> {
+        let stop = Arc::new(AtomicBool::new(false));
+        let fin = StopAfter {
+            lines: ["Foo\n", "Bar\n", "Baz\n", "Qux\n"].into(),
+            remaining: 2,
+            stop: Arc::clone(&stop),
+        };
+        let mut fout = BufWriter::new(Vec::new());
+        let options = Options {
+            stop,
+            ..Default::default()
+        };
+        write_lines(fin, &mut fout, "..", &options)?;
+        assert!(fout.buffer().is_empty());
+        assert_eq!(String::from_utf8(fout.into_inner()?)?, "Foo\nBar\n");
+        Ok(())
+    }
+
+    #[test]
+    fn complement() -> Result<()> {
+        let input = "1\n2\n3\n4\n5\n6\n7\n8\n";
+        let tvs = [
+            ("..", input, ""),
+            ("3..=5", "3\n4\n5\n", "1\n2\n6\n7\n8\n"),
+            ("1,8", "1\n8\n", "2\n3\n4\n5\n6\n7\n"),
+            ("2..4,6", "2\n3\n6\n", "1\n4\n5\n7\n8\n"),
+            ("20..", "", input),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            let complement = SharedBuffer::default();
+            let options = Options {
+                complement: Some(RefCell::new(Box::new(complement.clone()))),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.0, &options)?;
+            let selected = String::from_utf8(fout)?;
+            assert_eq!(selected, tv.1);
+            assert_eq!(complement.contents(), tv.2);
+
+            // Together, they should make up the whole input
+            let mut lines = format!("{selected}{}", complement.contents())
+                .lines()
+                .map(str::parse)
+                .collect::<Result<Vec<usize>, _>>()?;
+            lines.sort();
+            assert_eq!(lines, (1..=8).collect::<Vec<_>>());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn brace_expansion() -> Result<()> {
+        let tvs = [
+            ("{1..3}", "1,2,3"),
+            ("{1..9..4}", "1,5,9"),
+            ("{3..1}", "3,2,1"),
+            ("1{0,5}..", "10..,15.."),
+            ("{1,5}..={2,6}", "1..=2,1..=6,5..=2,5..=6"),
+            ("{1,{3,5}},8..", "1,3,5,8.."),
+            ("{1..2}{0,5}", "10,15,20,25"),
+            ("2..=4#plain,9", "2..=4#plain,9"),
+        ];
+        for tv in tvs {
+            assert_eq!(expand_braces(tv.0, ',')?, tv.1, "{tv:?}");
+        }
+        assert_eq!(expand_braces("{1,2};{4..5}", ';')?, "1;2;4;5");
+
+        for patterns in ["{1,2", "{5}", "{}", "{1..b}", "{1..3..0}", "{1..2..3..4}"] {
+            assert!(expand_braces(patterns, ',').is_err(), "{patterns:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn uniq() -> Result<()> {
+        let input = "a\na\nb\na\nc\nc\nc\n";
+        let tvs = [
+            ("..", Uniq::Collapse, "a\nb\na\nc\n"),
+            (
+                "..",
+                Uniq::Count,
+                "      2 a\n      1 b\n      1 a\n      3 c\n",
+            ),
+            // Only selected lines are compared, so skipping line 3 joins the runs of `a`
+            ("1..=2,4..", Uniq::Collapse, "a\nc\n"),
+            ("1..=2,4..", Uniq::Count, "      3 a\n      3 c\n"),
+            // Repeating a line with the pattern list makes a run too
+            ("3,3,3", Uniq::Count, "      3 b\n"),
+            ("8..", Uniq::Count, ""),
+        ];
+        for tv in tvs {
+            let options = Options {
+                uniq: Some(tv.1),
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn within() -> Result<()> {
+        let input = (1..=12).map(|n| format!("{n}\n")).collect::<String>();
+        // Each is the same as showing the equivalent patterns without a window
+        let tvs = [
+            ("..", "3..=5", "1..=2,6.."),
+            ("2..=10", "4..=6", "2..=3,7..=10"),
+            ("2..=10", "1,10..", "2..=9"),
+            ("..=6", "7..", "..=6"),
+            ("3..", "..", "20"),
+            ("..", "..=12:2", "2..=12:2"),
+            ("1..=9:3", "4", "1,7"),
+            ("5..=8", "20", "5..=8"),
+        ];
+        for tv in tvs {
+            let options = Options {
+                within: Some(parse_window(tv.0)?),
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(&input), &mut fout, tv.1, &options)?;
+            let mut expected = Vec::new();
+            write_lines(
+                Cursor::new(&input),
+                &mut expected,
+                tv.2,
+                &Options::default(),
+            )?;
+            assert_eq!(
+                String::from_utf8(fout)?,
+                String::from_utf8(expected)?,
+                "{tv:?}"
+            );
+        }
+
+        assert!(parse_window("9..=3:-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn context_file() -> Result<()> {
+        let input = (1..=12).map(|n| format!("{n}\n")).collect::<String>();
+        let tvs = [
+            ("6", 2, "6\n", "4\n5\n6\n7\n8\n"),
+            ("1,12", 1, "1\n12\n", "1\n2\n--\n11\n12\n"),
+            // Overlapping context is only written once
+            ("4,6", 1, "4\n6\n", "3\n4\n5\n6\n7\n"),
+            ("4,8", 1, "4\n8\n", "3\n4\n5\n--\n7\n8\n9\n"),
+            ("5..=6,6", 0, "5\n6\n6\n", "5\n6\n"),
+            ("20", 2, "", ""),
+        ];
+        for tv in tvs {
+            let context = SharedBuffer::default();
+            let options = Options {
+                context: Some(Context {
+                    lines: tv.1,
+                    sink: RefCell::new(Box::new(context.clone())),
+                }),
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(&input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+            assert_eq!(context.contents(), tv.3, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn around() -> Result<()> {
+        let input = "ok\nok\nERROR 1\nok\nok\nERROR 2\nok\nok\nok\nok\nok\nERROR 3\nok\n";
+        let tvs = [
+            // The first two windows overlap, so they're merged into one block
+            (
+                1,
+                None,
+                "ok\nERROR 1\nok\nok\nERROR 2\nok\n--\nok\nERROR 3\nok\n",
+            ),
+            (
+                2,
+                None,
+                "ok\nok\nERROR 1\nok\nok\nERROR 2\nok\nok\n--\nok\nok\nERROR 3\nok\n",
+            ),
+            (0, None, "ERROR 1\n--\nERROR 2\n--\nERROR 3\n"),
+            // Windows that only touch are merged too
+            (
+                3,
+                None,
+                "ok\nok\nERROR 1\nok\nok\nERROR 2\nok\nok\nok\nok\nok\nERROR 3\nok\n",
+            ),
+            // Context doesn't reach outside `--within`
+            (2, Some("4..=7"), "ok\nok\nERROR 2\nok\n"),
+            (1, Some("..=3"), "ok\nERROR 1\n"),
+            (1, Some("..3"), ""),
+        ];
+        for tv in tvs {
+            let options = Options {
+                within: tv.1.map(parse_window).transpose()?,
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_around(Cursor::new(input), &mut fout, "..", "ERROR", tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+
+        // Line numbers are those of the input
+        let options = Options {
+            show_line_number: true,
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_around(Cursor::new(input), &mut fout, "..", "ERROR 3", 1, &options)?;
+        assert_eq!(String::from_utf8(fout)?, "11\tok\n12\tERROR 3\n13\tok\n");
+
+        // Only selected lines can match, but their context can come from anywhere
+        let tvs = [
+            ("4..=10", "ok\nERROR 2\nok\n"),
+            ("3,12", "ok\nERROR 1\nok\n--\nok\nERROR 3\nok\n"),
+            ("7..=11", ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            write_around(
+                Cursor::new(input),
+                &mut fout,
+                tv.0,
+                "ERROR",
+                1,
+                &Options::default(),
+            )?;
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn ranges_only() -> Result<()> {
+        let tvs = [
+            ("..=3,10", "1-3 10-10\n"),
+            ("..", "1-5\n"),
+            ("4..", "4-5\n"),
+            ("2..4,1", "1-1 2-3\n"),
+            ("7..", "\n"),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from("Foo\nBar\nBaz\nQux\nQuux\n"));
+            let mut fout = Vec::new();
+            write_ranges(fin, &mut fout, tv.0, &Default::default())?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn decode_latin1() -> Result<()> {
+        let transcoding = |on_error, reencode| {
+            Some(Transcoding {
+                encoding: parse_encoding("latin1").unwrap(),
+                on_error,
+                reencode,
+            })
+        };
+        let input = b"caf\xe9\nna\xefve\nr\xe9sum\xe9\n";
+
+        let fin = Cursor::new(input);
+        let mut fout = Vec::new();
+        let options = Options {
+            transcoding: transcoding(DecodeErrorPolicy::Fail, false),
+            ..Default::default()
+        };
+        write_lines(fin, &mut fout, "1,3", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "café\nrésumé\n");
+
+        let fin = Cursor::new(input);
+        let mut fout = Vec::new();
+        let options = Options {
+            transcoding: transcoding(DecodeErrorPolicy::Fail, true),
+            ..Default::default()
+        };
+        write_lines(fin, &mut fout, "..", &options)?;
+        assert_eq!(fout, input);
+        Ok(())
+    }
+
+    #[cfg(feature = "encoding")]
+    #[test]
+    fn decode_errors() -> Result<()> {
+        let transcoding = |on_error| {
+            Some(Transcoding {
+                encoding: parse_encoding("shift_jis").unwrap(),
+                on_error,
+                reencode: false,
+            })
+        };
+        // "日本" followed by a truncated character
+        let input = b"\x93\xfa\x96\x7b\n\x82\n";
+
+        let fin = Cursor::new(input);
+        let mut fout = Vec::new();
+        let options = Options {
+            transcoding: transcoding(DecodeErrorPolicy::Replace),
+            ..Default::default()
+        };
+        write_lines(fin, &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "日本\n\u{fffd}\n");
+
+        let fin = Cursor::new(input);
+        let mut fout = Vec::new();
+        let options = Options {
+            transcoding: transcoding(DecodeErrorPolicy::Fail),
+            ..Default::default()
+        };
+        let err = write_lines(fin, &mut fout, "..", &options).unwrap_err();
+        assert_eq!(err.to_string(), "Line 2 is not valid Shift_JIS");
+
+        assert!(parse_encoding("utf-16le").is_err());
+        assert!(parse_encoding("klingon").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn zip() -> Result<()> {
+        let tvs: &[(&str, &[&str])] = &[
+            ("..", &["a1", "b1", "a2", "b2", "a3", "b3", "b4"]),
+            ("2..", &["a2", "b2", "a3", "b3", "b4"]),
+            ("1,3..", &["a1", "b1", "a3", "b3", "b4"]),
+            ("4", &["b4"]),
+            ("2,2", &["a2", "a2", "b2", "b2"]),
+        ];
+        for tv in tvs {
+            let fins = vec![
+                Cursor::new(String::from("a1\na2\na3\n")),
+                Cursor::new(String::from("b1\nb2\nb3\nb4\n")),
+            ];
+            let mut fout = Vec::new();
+            zip_lines(fins, &mut fout, tv.0, &Default::default())?;
+            let actual_lines = String::from_utf8(fout)?;
+            assert_eq!(actual_lines.lines().collect::<Vec<_>>(), tv.1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_open_files() -> Result<()> {
+        let dir = TempDir::new("max-open")?;
+        let paths = (0..50)
+            .map(|i| {
+                let path = dir.join(format!("{i}.txt"));
+                fs::write(&path, format!("{i}a\n{i}b\n"))?;
+                Ok(path)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let open_files = OpenFiles::new(NonZeroUsize::new(3).unwrap());
+        let free = || *open_files.free.lock().unwrap();
+        let options = Options {
+            open_files: Some(Arc::clone(&open_files)),
+            ..Default::default()
+        };
+
+        // One at a time, each file gives its place back for the next
+        let mut fout = Vec::new();
+        each_input(&paths, false, &options, io::sink(), |path| {
+            let fin = open_input(path, &options, false)?;
+            assert_eq!(free(), 2);
+            write_lines(fin, &mut fout, "1", &options)
+        })?;
+        let expected = (0..50).map(|i| format!("{i}a\n")).collect::<String>();
+        assert_eq!(String::from_utf8(fout)?, expected);
+        assert_eq!(free(), 3);
+
+        // Zipped, only two are held open, and the rest take turns with the last place
+        let mut fins = Vec::new();
+        for path in &paths {
+            fins.push(open_for_zip(path, &options, false)?);
+        }
+        assert_eq!(free(), 1);
+        // Files that are held open keep reading what they had, but the rest are read afresh,
+        // and they weren't read ahead of time either
+        for path in &paths[1..=2] {
+            replace_file(path, "new\nnewer\n")?;
+        }
+        let mut fout = Vec::new();
+        zip_lines(fins, &mut fout, "2", &options)?;
+        let expected = (0..50)
+            .map(|i| match i {
+                2 => "newer\n".into(),
+                i => format!("{i}b\n"),
+            })
+            .collect::<String>();
+        assert_eq!(String::from_utf8(fout)?, expected);
+        assert_eq!(free(), 3);
+
+        // Files past the limit need to be opened again, which stdin and directories can't be
+        let _held = [open_files.acquire(), open_files.acquire()];
+        for path in [Path::new("-"), &dir] {
+            assert!(open_for_zip(path, &options, false).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn fields() -> Result<()> {
+        let input = "name,age,city,country\nalice,30,paris,france\nbob,25,,spain\n";
+        let tvs: &[(&str, &str, &str, &[&str])] = &[
+            ("2..", "1,3", ",", &["alice,paris", "bob,"]),
+            ("..", "2..4", ",", &["age,city", "30,paris", "25,"]),
+            ("1", "3..", ",", &["city,country"]),
+            ("2", "4,1", ",", &["france,alice"]),
+            ("3", "1,1", ",", &["bob,bob"]),
+            ("3", "9", ",", &[""]),
+            ("2", "2..", "a", &["lice,30,paris,france"]),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            let options = Options {
+                fields: Some(Fields::new(tv.1, tv.2)?),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.0, &options)?;
+            let actual_lines = String::from_utf8(fout)?;
+            assert_eq!(actual_lines.lines().collect::<Vec<_>>(), tv.3);
+        }
+        assert!(Fields::new("1", "").is_err());
+        assert!(Fields::new("0", ",").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn skip_lines() -> Result<()> {
+        let input = "Foo\n\n  \nBar\n\t\n\nBaz\n";
+        let tvs = [
+            (Skip::Empty, "..", "1\tFoo\n3\t  \n4\tBar\n5\t\t\n7\tBaz\n"),
+            (Skip::Blank, "..", "1\tFoo\n4\tBar\n7\tBaz\n"),
+            (Skip::Empty, "2..=3,3", "3\t  \n3\t  \n"),
+            (Skip::Blank, "2..=3,3", ""),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            let complement = SharedBuffer::default();
+            let options = Options {
+                show_line_number: true,
+                skip: Some(tv.0),
+                complement: Some(RefCell::new(Box::new(complement.clone()))),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.1, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2);
+            // Skipped lines were selected, so they aren't part of the complement
+            if tv.1 == ".." {
+                assert_eq!(complement.contents(), "");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn tee() -> Result<()> {
+        let tvs = ["..", "2..=3", "3,3", "9.."];
+        for tv in tvs {
+            let fin = Cursor::new(String::from("Foo\nBar\nBaz\nQux\n"));
+            let mut fout = Vec::new();
+            let tee = SharedBuffer::default();
+            let options = Options {
+                show_line_number: true,
+                tee: Some(RefCell::new(Box::new(tee.clone()))),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tee.contents());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn collapse_ranges() -> Result<()> {
+        let input = (1..=30).map(|i| format!("{i}\n")).collect::<String>();
+        let tvs = [
+            ("1..=3,7,20..=25", "emitted: 1-3, 7-7, 20-25\n"),
+            ("1..=3,4,4..=5", "emitted: 1-5\n"),
+            ("..10:3", "emitted: 1-1, 4-4, 7-7\n"),
+            ("5..=3:-1", "emitted: 5-5, 4-4, 3-3\n"),
+            ("29..", "emitted: 29-30\n"),
+            ("31..", "emitted: none\n"),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let runs = SharedBuffer::default();
+            let options = Options {
+                collapse_ranges: Some(RefCell::new(Box::new(runs.clone()))),
+                ..Default::default()
+            };
+            write_lines(Cursor::new(&input), &mut fout, tv.0, &options)?;
+            assert_eq!(runs.contents(), tv.1, "{}", tv.0);
+        }
+
+        // Only the lines that were written count
+        let mut fout = Vec::new();
+        let runs = SharedBuffer::default();
+        let options = Options {
+            collapse_ranges: Some(RefCell::new(Box::new(runs.clone()))),
+            skip: Some(Skip::Empty),
+            ..Default::default()
+        };
+        write_lines(Cursor::new("a\nb\n\nc\nd\n"), &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "a\nb\nc\nd\n");
+        assert_eq!(runs.contents(), "emitted: 1-2, 4-5\n");
+        Ok(())
+    }
+
+    #[test]
+    fn hunk_headers() -> Result<()> {
+        let input = (1..=30).map(|i| format!("{i}\n")).collect::<String>();
+        let tvs = [
+            (
+                "1..=3,7,20..=22",
+                "@@ -1,3 @@\n1\n2\n3\n@@ -7,1 @@\n7\n@@ -20,3 @@\n20\n21\n22\n",
+            ),
+            ("1..=2,3", "@@ -1,3 @@\n1\n2\n3\n"),
+            ("2,2", "@@ -2,1 @@\n2\n@@ -2,1 @@\n2\n"),
+            ("5..=4:-1", "@@ -5,1 @@\n5\n@@ -4,1 @@\n4\n"),
+            ("29..", "@@ -29,2 @@\n29\n30\n"),
+            ("31..", ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                hunk_headers: true,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(&input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{}", tv.0);
+        }
+
+        // Blocks are made of the lines that were written, numbered as they're shown
+        let mut fout = Vec::new();
+        let options = Options {
+            hunk_headers: true,
+            show_line_number: true,
+            skip: Some(Skip::Empty),
+            number_shift: 10,
+            ..Default::default()
+        };
+        write_lines(Cursor::new("a\nb\n\nc\n"), &mut fout, "..", &options)?;
+        assert_eq!(
+            String::from_utf8(fout)?,
+            "@@ -11,2 @@\n11\ta\n12\tb\n@@ -14,1 @@\n14\tc\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn repeat_file() -> Result<()> {
+        let tvs = [
+            (1, "..", "1\tFoo\n2\tBar\n"),
+            (3, "2", "2\tBar\n"),
+            (2, "9..", ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let copies = SharedBuffer::default();
+            let options = Options {
+                show_line_number: true,
+                repeat: Some(Repeat {
+                    count: NonZeroUsize::new(tv.0).unwrap(),
+                    sink: RefCell::new(Box::new(copies.clone())),
+                    selection: RefCell::default(),
+                }),
+                ..Default::default()
+            };
+            // The copies come from every file
+            for _ in 0..2 {
+                write_lines(Cursor::new("Foo\nBar\n"), &mut fout, tv.1, &options)?;
+            }
+            assert_eq!(copies.contents(), "");
+            options.repeat.as_ref().unwrap().finish()?;
+            let expected = tv.2.repeat(2);
+            assert_eq!(String::from_utf8(fout)?, expected);
+            assert_eq!(copies.contents(), expected.repeat(tv.0));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn after_match() -> Result<()> {
+        let input = "Foo\nBar\nFOO 1\nBaz\nQux\nFOO 2\nQuux\n";
+        let tvs: &[(&str, &str, &[&str])] = &[
+            ("FOO", "+1..=+2", &["Baz", "Qux"]),
+            ("FOO", "+1..+3", &["Baz", "Qux"]),
+            ("FOO", "..=+1", &["FOO 1", "Baz"]),
+            ("FOO", "+0,+3..", &["FOO 1", "FOO 2", "Quux"]),
+            ("FOO 2", "+1", &["Quux"]),
+            ("Bar", "+10", &[]),
+            ("Nope", "..", &[]),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            let options = Options {
+                after: Some(tv.0.into()),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.1, &options)?;
+            let actual_lines = String::from_utf8(fout)?;
+            assert_eq!(actual_lines.lines().collect::<Vec<_>>(), tv.2);
+        }
+
+        // Offsets must be marked as such
+        let options = Options {
+            after: Some("FOO".into()),
+            ..Default::default()
+        };
+        for tv in ["1..+3", "+1..3", "+3,+1"] {
+            let fin = Cursor::new(String::from(input));
+            assert!(
+                write_lines(fin, &mut Vec::new(), tv, &options).is_err(),
+                "{tv}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn format() -> Result<()> {
+        let tvs = [
+            ("{n}: {line}", "2: Bar\n3: Baz\n"),
+            ("{line}", "Bar\nBaz\n"),
+            ("> {line} <", "> Bar <\n> Baz <\n"),
+            ("{{{n}}} {line}{line}", "{2} BarBar\n{3} BazBaz\n"),
+            ("line {n}", "line 2\nline 3\n"),
+            ("", "\n\n"),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from("Foo\nBar\nBaz\n"));
+            let mut fout = Vec::new();
+            let options = Options {
+                show_line_number: true,
+                format: Some(tv.0.parse()?),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, "2..", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+
+        for tv in ["{", "}", "{n", "{file}", "{line}}", "{{n}"] {
+            assert!(tv.parse::<Format>().is_err(), "{tv}");
+        }
+
+        // Clap turns `{n}` in help text into a line break, so it mustn't appear there
+        let help = Args::command().render_long_help().to_string();
+        let (_, format_help) = help
+            .split_once("--format <TEMPLATE>")
+            .expect("Has --format");
+        assert!(format_help.contains("where `{line}` is the line and the letter `n` in braces"));
+        Ok(())
+    }
+
+    #[test]
+    fn reverse_file() -> Result<()> {
+        let input = "1\n2\n3\n4\n5\r\n";
+        let tvs = [
+            (
+                "..",
+                "1\t5\n2\t4\n3\t3\n4\t2\n5\t1\n",
+                "1\t1\n2\t2\n3\t3\n4\t4\n5\t5\n",
+            ),
+            ("1..=2", "1\t5\n2\t4\n", "1\t1\n2\t2\n"),
+            ("4..", "4\t2\n5\t1\n", "4\t4\n5\t5\n"),
+            ("2,5", "2\t4\n5\t1\n", "2\t2\n5\t5\n"),
+        ];
+        let options = Options {
+            show_line_number: true,
+            ..Default::default()
+        };
+        for tv in tvs {
+            let reversed = reverse_lines(Cursor::new(input), &options)?;
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(reversed), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+
+            // Without reversing, the same pattern selects from the other end
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2);
+        }
+
+        assert!(reverse_lines(Cursor::new(""), &options)?.is_empty());
+        assert_eq!(reverse_lines(Cursor::new("a\n\nb"), &options)?, b"b\n\na\n");
+        Ok(())
+    }
+
+    #[test]
+    fn count_bytes() -> Result<()> {
+        let input = "Foo\nBarbaz\r\n\nQux\n";
+        let tvs = [
+            ("..", ByteCounting::Content, "12\n"),
+            ("..", ByteCounting::WithTerminators, "16\n"),
+            ("2..=3", ByteCounting::Content, "6\n"),
+            ("2..=3", ByteCounting::WithTerminators, "8\n"),
+            ("3", ByteCounting::WithTerminators, "1\n"),
+            ("1,1", ByteCounting::Content, "6\n"),
+            ("9..", ByteCounting::WithTerminators, "0\n"),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            let options = Options {
+                show_line_number: true,
+                count_bytes: Some(tv.1),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn select_empty() -> Result<()> {
+        let input = "a\n\nb\n  \n\t\nc\n\n";
+        let tvs = [
+            (Skip::Empty, false, "..", "\n\n"),
+            (Skip::Blank, false, "..", "\n  \n\t\n\n"),
+            (Skip::Empty, true, "..", "2\t\n7\t\n"),
+            (Skip::Blank, true, "..", "2\t\n4\t  \n5\t\t\n7\t\n"),
+            (Skip::Blank, true, "3..=6", "4\t  \n5\t\t\n"),
+            (Skip::Empty, true, "1,3,6", ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                select_empty: Some(tv.0),
+                show_line_number: tv.1,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.2, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.3, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn length_filter() -> Result<()> {
+        let input = "\nab\nabcd\r\nabcdef\nabcdefgh\n日本\n";
+        let tvs = [
+            (None, None, "..", "\nab\nabcd\nabcdef\nabcdefgh\n日本\n"),
+            (Some(4), None, "..", "abcd\nabcdef\nabcdefgh\n日本\n"),
+            (None, Some(4), "..", "\nab\nabcd\n"),
+            (Some(2), Some(6), "..", "ab\nabcd\nabcdef\n日本\n"),
+            (Some(2), Some(6), "3..", "abcd\nabcdef\n日本\n"),
+            (Some(5), Some(5), "..", ""),
+            (Some(7), Some(3), "..", ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                min_content_length: tv.0,
+                max_content_length: tv.1,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.2, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.3, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn non_ascii() -> Result<()> {
+        let input = b"plain\ncaf\xc3\xa9\n\xff\xfe\nlatin \xe9t\xe9\n\nend\n";
+        let tvs = [
+            (
+                false,
+                false,
+                &b"plain\ncaf\xc3\xa9\n\xff\xfe\nlatin \xe9t\xe9\n\nend\n"[..],
+            ),
+            (true, false, b"plain\n\nend\n"),
+            (false, true, b"plain\ncaf\n\nlatin t\n\nend\n"),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                ascii_only: tv.0,
+                strip_non_ascii: tv.1,
+                ..Default::default()
+            };
+            write_lines(&input[..], &mut fout, "..", &options)?;
+            assert_eq!(fout, tv.2, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn numbers_only() -> Result<()> {
+        let input = "foo 1\nbar 2\nfood 3\nbaz 4\nfoo 5\n";
+        let tvs = [
+            ("..", None, "1\n2\n3\n4\n5\n"),
+            ("2..=3,3", None, "2\n3\n3\n"),
+            ("..", Some("foo"), "1\n3\n5\n"),
+            ("2..", Some("foo"), "3\n5\n"),
+            ("..", Some("qux"), ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                numbers_only: true,
+                column_filter: tv.1.map(|text| ColumnFilter {
+                    column: NonZeroUsize::MIN,
+                    separator: None,
+                    test: ColumnTest::Contains(text.into()),
+                }),
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn nth() -> Result<()> {
+        let input = "ok 1\nERROR a\nok 2\nERROR b\nERROR c\nok 3\nERROR d\n";
+        let tvs = [
+            ("..", "3", 0, "ERROR c\n"),
+            ("..", "1", 0, "ERROR a\n"),
+            ("..", "2..=3", 0, "ERROR b\nERROR c\n"),
+            ("..", "..:2", 0, "ERROR a\nERROR c\n"),
+            ("..", "2..", 1, "ERROR c\nERROR d\n"),
+            // Only the selected lines are counted
+            ("3..", "1", 0, "ERROR b\n"),
+            ("..", "5", 0, ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                column_filter: Some(ColumnFilter {
+                    column: NonZeroUsize::MIN,
+                    separator: None,
+                    test: ColumnTest::Equals("ERROR".into()),
+                }),
+                nth: Some(parse_window(tv.1)?),
+                offset: tv.2,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.3, "{tv:?}");
+        }
+
+        // Nothing past the last occurrence asked for is read
+        for tv in [("2", "\n"), ("2..=3", "\n\n"), ("..=5:2", "\n\n\n")] {
+            let mut fout = Vec::new();
+            let options = Options {
+                nth: Some(parse_window(tv.0)?),
+                ..Default::default()
+            };
+            write_lines(io::repeat(b'\n'), &mut fout, "..", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{}", tv.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn count_distinct() -> Result<()> {
+        let input = "a,1\nb,2\na,3\nc,1\na,1\nb,2\n";
+        let tvs = [
+            ("..", None, "4\n"),
+            ("..=3", None, "3\n"),
+            ("5..", None, "2\n"),
+            ("1,1,5", None, "1\n"),
+            ("9..", None, "0\n"),
+            ("..", Some("1"), "3\n"),
+            ("..", Some("2"), "3\n"),
+            ("2..=3", Some("1"), "2\n"),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                count_distinct: true,
+                fields: tv.1.map(|fields| Fields::new(fields, ",")).transpose()?,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{}", tv.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn errors_to() -> Result<()> {
+        let tvs = [
+            (
+                Options {
+                    after: Some("Qux".into()),
+                    ..Default::default()
+                },
+                "+1",
+                "Warning: no line contains `Qux`\n",
+            ),
+            (
+                Options {
+                    nth: Some(parse_window("5")?),
+                    ..Default::default()
+                },
+                "..",
+                "Warning: asked for occurrence 5, but only 2 lines came up\n",
+            ),
+        ];
+        for tv in tvs {
+            let errors = SharedBuffer::default();
+            let options = Options {
+                diagnostics: Diagnostics(RefCell::new(Box::new(errors.clone()))),
+                ..tv.0
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new("Foo\nBar\n"), &mut fout, tv.1, &options)?;
+            assert!(fout.is_empty());
+            assert_eq!(errors.contents(), tv.2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn last_line() -> Result<()> {
+        let input = "Foo\nBar\nBaz\n";
+        let tvs = [
+            ("$", "Baz\n"),
+            ("$..$", "Baz\n"),
+            ("2..$", "Bar\nBaz\n"),
+            ("2..=$", "Bar\nBaz\n"),
+            ("..$", "Foo\nBar\nBaz\n"),
+            ("1,$", "Foo\nBaz\n"),
+            ("$,$", "Baz\nBaz\n"),
+            ("..=3,$", "Foo\nBar\nBaz\nBaz\n"),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(input), &mut fout, tv.0, &Options::default())?;
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{}", tv.0);
+        }
+
+        // Nothing to show without a last line
+        let mut fout = Vec::new();
+        write_lines(Cursor::new(""), &mut fout, "$", &Options::default())?;
+        assert!(fout.is_empty());
+
+        // `$` comes after everything else, so nothing can follow it
+        assert!(
+            write_lines(
+                Cursor::new(input),
+                &mut Vec::new(),
+                "$,1",
+                &Options::default()
+            )
+            .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn count_nonempty() -> Result<()> {
+        let input = "a\n\nb\n \n\n\nc\n";
+        let tvs = [
+            ("..", None, "7\n"),
+            ("..", Some(Skip::Empty), "4\n"),
+            ("..", Some(Skip::Blank), "3\n"),
+            ("2..=3", None, "2\n"),
+            ("2..=3", Some(Skip::Empty), "1\n"),
+            ("5,6", Some(Skip::Empty), "0\n"),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                count_lines: true,
+                skip: tv.1,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+
+            // Agrees with how many lines are shown without counting
+            let mut fout = Vec::new();
+            let options = Options {
+                skip: tv.1,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            let shown = fout.iter().filter(|&&byte| byte == b'\n').count();
+            assert_eq!(format!("{shown}\n"), tv.2, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "pretty-json")]
+    #[test]
+    fn pretty_json_lines() -> Result<()> {
+        let tvs = [
+            ("null", Some("null")),
+            (" -1.5e+3 ", Some("-1.5e+3")),
+            ("[]", Some("[]")),
+            ("{ }", Some("{}")),
+            ("[1,\"a\"]", Some("[\n  1,\n  \"a\"\n]")),
+            (
+                "{\"a\": {\"b\": [true, {}]}, \"c\":\"\\\"x\\u00e9\"}",
+                Some(
+                    "{\n  \"a\": {\n    \"b\": [\n      true,\n      {}\n    ]\n  },\n  \"c\": \"\\\"x\\u00e9\"\n}",
+                ),
+            ),
+            // Keys keep their order, and numbers keep their text
+            (
+                "{\"b\": 1E2, \"a\": 12345678901234567890.10}",
+                Some("{\n  \"b\": 1E2,\n  \"a\": 12345678901234567890.10\n}"),
+            ),
+            ("", None),
+            ("nul", None),
+            ("[1,]", None),
+            ("{1: 2}", None),
+            ("{\"a\" 2}", None),
+            ("01", None),
+            ("1.", None),
+            ("\"a", None),
+            ("\"\\x\"", None),
+            ("\"\t\"", None),
+            ("[1] 2", None),
+        ];
+        for tv in tvs {
+            let pretty = pretty_json(tv.0.as_bytes())
+                .ok()
+                .map(String::from_utf8)
+                .transpose()?;
+            assert_eq!(pretty.as_deref(), tv.1, "{}", tv.0);
+        }
+
+        let input = "{\"id\":1}\n{\"id\":2,\"tags\":[\"x\"]}\nnot json\n";
+        let tvs = [
+            (
+                InvalidJsonPolicy::Fail,
+                "2",
+                Some("{\n  \"id\": 2,\n  \"tags\": [\n    \"x\"\n  ]\n}\n"),
+            ),
+            (InvalidJsonPolicy::Fail, "..", None),
+            (
+                InvalidJsonPolicy::Keep,
+                "1,3",
+                Some("{\n  \"id\": 1\n}\nnot json\n"),
+            ),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                pretty_json: Some(tv.0),
+                ..Default::default()
+            };
+            let result = write_lines(Cursor::new(input), &mut fout, tv.1, &options);
+            match tv.2 {
+                Some(expected) => {
+                    result?;
+                    assert_eq!(String::from_utf8(fout)?, expected);
+                }
+                None => assert!(result.is_err()),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "base64")]
+    #[test]
+    fn base64_decode() -> Result<()> {
+        let tvs = [
+            ("", Some("")),
+            ("Zm9v", Some("foo")),
+            ("Zm9vYg==", Some("foob")),
+            ("Zm9vYmE=", Some("fooba")),
+            ("Zm9vYmE", Some("fooba")),
+            ("8J+Riw==", Some("👋")),
+            ("Zm9vY", None),
+            ("Zm9vYmE==", None),
+            ("Zm9vYg=", None),
+            ("Zm9v Yg==", None),
+            ("Zm=9v", None),
+            ("====", None),
+        ];
+        for tv in tvs {
+            let decoded = decode_base64(tv.0.as_bytes())
+                .ok()
+                .map(String::from_utf8)
+                .transpose()?;
+            assert_eq!(decoded.as_deref(), tv.1, "{}", tv.0);
+        }
+
+        let input = "aGVsbG8=\nnot base64!\nd29ybGQ=\n";
+        let tvs = [
+            (Base64ErrorPolicy::Fail, false, "1", Some("hello\n")),
+            (Base64ErrorPolicy::Fail, false, "..", None),
+            (Base64ErrorPolicy::Skip, false, "..", Some("hello\nworld\n")),
+            (
+                Base64ErrorPolicy::Keep,
+                false,
+                "..",
+                Some("hello\nnot base64!\nworld\n"),
+            ),
+            (Base64ErrorPolicy::Skip, true, "..", Some("helloworld")),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                base64: Some(Base64Decoding {
+                    on_error: tv.0,
+                    concat: tv.1,
+                }),
+                ..Default::default()
+            };
+            let result = write_lines(Cursor::new(input), &mut fout, tv.2, &options);
+            match tv.3 {
+                Some(expected) => {
+                    result?;
+                    assert_eq!(String::from_utf8(fout)?, expected, "{tv:?}");
+                }
+                None => assert!(result.is_err(), "{tv:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn truncate() -> Result<()> {
+        let tvs = [
+            ("", 3, ""),
+            ("Foo", 3, "Foo"),
+            ("Foobar", 3, "Foo"),
+            ("日本語です", 2, "日本"),
+            ("a\u{301}bc", 2, "a\u{301}"),
+        ];
+        for tv in tvs {
+            assert_eq!(truncate_chars(tv.0.as_bytes(), tv.1), tv.2.as_bytes());
+        }
+        assert_eq!(truncate_chars(b"\xff\xfeab", 3), b"\xff\xfea");
+        Ok(())
+    }
+
+    #[test]
+    fn byte_budget() -> Result<()> {
+        let input = "aaa\nbb\ncccc\nd\neeeeeeeeee\nf\n";
+        let tvs = [
+            (0, ""),
+            (3, ""),
+            (4, "aaa\n"),
+            (6, "aaa\n"),
+            (7, "aaa\nbb\n"),
+            (14, "aaa\nbb\ncccc\nd\n"),
+            // Stops at the long line, even though the short one after it would fit
+            (20, "aaa\nbb\ncccc\nd\n"),
+            (usize::MAX, input),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                byte_budget: Some(tv.0),
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, "..", &options)?;
+            assert!(fout.len() <= tv.0);
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{}", tv.0);
+        }
+
+        // The budget is shared between files
+        let options = Options {
+            byte_budget: Some(10),
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(Cursor::new("abc\ndef\n"), &mut fout, "..", &options)?;
+        write_lines(Cursor::new("gh\nij\n"), &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "abc\ndef\n");
+
+        // Line numbers count as part of the line
+        for tv in [(5, ""), (8, "1\taaa\n"), (11, "1\taaa\n2\tbb\n")] {
+            let mut fout = Vec::new();
+            let options = Options {
+                byte_budget: Some(tv.0),
+                show_line_number: true,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, "..", &options)?;
+            assert!(fout.len() <= tv.0);
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{}", tv.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn wrap() -> Result<()> {
+        let input = "abcdefghij\n\nabc\n日本語です\n";
+        let tvs = [
+            (4, 0, false, "abcd\nefgh\nij\n\nabc\n日本語で\nす\n"),
+            (5, 0, false, "abcde\nfghij\n\nabc\n日本語です\n"),
+            (4, 2, false, "abcd\n  efgh\n  ij\n\nabc\n日本語で\n  す\n"),
+            (
+                4,
+                0,
+                true,
+                "1\tabcd\n\tefgh\n\tij\n2\t\n3\tabc\n4\t日本語で\n\tす\n",
+            ),
+            (
+                4,
+                1,
+                true,
+                "1\tabcd\n\t efgh\n\t ij\n2\t\n3\tabc\n4\t日本語で\n\t す\n",
+            ),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                wrap: Some(Wrap {
+                    width: NonZeroUsize::new(tv.0).unwrap(),
+                    indent: tv.1,
+                }),
+                show_line_number: tv.2,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, "..", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.3, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn max_lines() -> Result<()> {
+        let input = "1\n2\n3\n4\n5\n";
+        let tvs = [(1, "1\n"), (3, "1\n2\n3\n"), (9, input)];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                max_lines: NonZeroUsize::new(tv.0),
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, "..", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn preview() -> Result<()> {
+        let input = "x".repeat(100) + "\n";
+        let input = input.repeat(30);
+        let tvs: &[(&[&str], Option<&str>, usize, usize)] = &[
+            (&["--preview"], None, 20, 80),
+            (&["--preview"], Some("40"), 20, 40),
+            (&["--preview"], Some("wide"), 20, 80),
+            (&["--preview", "--max-lines", "5"], Some("40"), 5, 40),
+            (&["--preview", "--truncate", "90"], Some("40"), 20, 90),
+            (&[], Some("40"), 30, 100),
+        ];
+        for tv in tvs {
+            let mut args = Args::try_parse_from([&["line"], tv.0, &[".."]].concat())?;
+            apply_preview(&mut args, tv.1);
+            let options = Options {
+                max_lines: args.max_lines,
+                truncate: args.truncate,
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(&input), &mut fout, "..", &options)?;
+            let fout = String::from_utf8(fout)?;
+            assert_eq!(fout.lines().count(), tv.2);
+            assert!(fout.lines().all(|line| line.len() == tv.3));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn count_matches() -> Result<()> {
+        let input = "foo foo\nbar\nfoofoo foo\nfoooo\n";
+        let tvs = [
+            ("..", "foo", "6\n"),
+            ("2", "foo", "0\n"),
+            ("3", "foo", "3\n"),
+            ("1,1", "foo", "4\n"),
+            ("4", "oo", "2\n"),
+            ("..", " ", "2\n"),
+            ("9..", "foo", "0\n"),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(input);
+            let mut fout = Vec::new();
+            let options = Options {
+                count_matches: Some(tv.1.into()),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn patterns_from_stdin() -> Result<()> {
+        let tvs = [
+            ("2..=3\n", "Bar\nBaz\n"),
+            ("1,4", "Foo\nQux\n"),
+            ("1\r\n\n  3..\n", "Foo\nBaz\nQux\n"),
+        ];
+        for tv in tvs {
+            let patterns = read_patterns(Cursor::new(tv.0), ',')?;
+            let fin = Cursor::new(String::from("Foo\nBar\nBaz\nQux\n"));
+            let mut fout = Vec::new();
+            write_lines(fin, &mut fout, &patterns, &Default::default())?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+        assert!(read_patterns(Cursor::new(""), ',').is_err());
+        assert!(read_patterns(Cursor::new("\n \n"), ',').is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn last_unique() -> Result<()> {
+        let input = "a\nb\na\nc\nb\nb\nd\na\n";
+        let tvs: &[(usize, &str, &[&str])] = &[
+            (1, "..", &["8\ta"]),
+            (2, "..", &["7\td", "8\ta"]),
+            (3, "..", &["6\tb", "7\td", "8\ta"]),
+            (10, "..", &["4\tc", "6\tb", "7\td", "8\ta"]),
+            (2, "..=6", &["4\tc", "6\tb"]),
+            (3, "1..=3", &["2\tb", "3\ta"]),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            let options = Options {
+                show_line_number: true,
+                last_unique: NonZeroUsize::new(tv.0),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.1, &options)?;
+            let actual_lines = String::from_utf8(fout)?;
+            assert_eq!(actual_lines.lines().collect::<Vec<_>>(), tv.2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn hash_sample() -> Result<()> {
+        let input = (1..=1000).map(|i| format!("{i}\n")).collect::<String>();
+        let sample = |patterns, every| -> Result<Vec<String>> {
+            let mut fout = Vec::new();
+            let options = Options {
+                hash_sample: NonZeroUsize::new(every),
+                ..Default::default()
+            };
+            write_lines(Cursor::new(&input), &mut fout, patterns, &options)?;
+            Ok(String::from_utf8(fout)?.lines().map(String::from).collect())
+        };
+
+        let first = sample("..", 10)?;
+        assert_eq!(first, sample("..", 10)?);
+        assert!((50..150).contains(&first.len()), "{}", first.len());
+
+        // Patterns are applied first
+        let later = sample("501..", 10)?;
+        assert!(later.iter().all(|line| first.contains(line)));
+        assert!(
+            later
+                .iter()
+                .all(|line| line.parse::<usize>().unwrap() > 500)
+        );
+
+        assert_eq!(sample("..", 1)?.len(), 1000);
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        Ok(())
+    }
+
+    #[test]
+    fn hash_prefix() -> Result<()> {
+        let input = (1..=5000).map(|i| format!("{i}\n")).collect::<String>();
+        let sample = |patterns, prefix: &str| -> Result<Vec<String>> {
+            let mut fout = Vec::new();
+            let options = Options {
+                hash_prefix: Some(parse_hash_prefix(prefix)?),
+                ..Default::default()
+            };
+            write_lines(Cursor::new(&input), &mut fout, patterns, &options)?;
+            Ok(String::from_utf8(fout)?.lines().map(String::from).collect())
+        };
+
+        let short = sample("..", "a")?;
+        assert_eq!(short, sample("..", "A")?);
+        assert!((200..450).contains(&short.len()), "{}", short.len());
+        let long = sample("..", "a7")?;
+        assert!(long.len() < short.len());
+        assert!(long.iter().all(|line| short.contains(line)));
+
+        // Patterns must match too
+        let later = sample("2501..", "a")?;
+        assert!(later.iter().all(|line| short.contains(line)));
+        assert!(
+            later
+                .iter()
+                .all(|line| line.parse::<usize>().unwrap() > 2500)
+        );
+
+        // The same line is sampled wherever it is
+        let mut fout = Vec::new();
+        let options = Options {
+            hash_prefix: Some("a".into()),
+            ..Default::default()
+        };
+        write_lines(
+            Cursor::new(format!("x\n{}", short[0])),
+            &mut fout,
+            "..",
+            &options,
+        )?;
+        assert_eq!(String::from_utf8(fout)?, format!("{}\n", short[0]));
+
+        for prefix in ["", "g", "0123456789abcdef0"] {
+            assert!(parse_hash_prefix(prefix).is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn json() -> Result<()> {
+        let input = "Foo\n\"Bar\"\nC:\\Baz\n\ttab\x01\n";
+        let tvs = [
+            (JsonOutput::Array, "..=2", "[\"Foo\",\"\\\"Bar\\\"\"]\n"),
+            (
+                JsonOutput::Meta,
+                "3..",
+                "{\"count\":2,\"lines\":[\"C:\\\\Baz\",\"\\ttab\\u0001\"]}\n",
+            ),
+            (
+                JsonOutput::Meta,
+                "1,1",
+                "{\"count\":2,\"lines\":[\"Foo\",\"Foo\"]}\n",
+            ),
+            (JsonOutput::Array, "9..", "[]\n"),
+            (JsonOutput::Meta, "9..", "{\"count\":0,\"lines\":[]}\n"),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            let options = Options {
+                show_line_number: true,
+                json: Some(tv.0),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.1, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn watch_recomputes() -> Result<()> {
+        let dir = TempDir::new("watch")?;
+        let path = dir.join("watched.txt");
+        fs::write(&path, "Foo\nBar\n")?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let changer = {
+            let (path, stop) = (path.clone(), Arc::clone(&stop));
+            thread::spawn(move || -> io::Result<()> {
+                thread::sleep(Duration::from_millis(100));
+                replace_file(&path, "Foo\nBaz\nQux\n")?;
+                thread::sleep(Duration::from_millis(100));
+                stop.store(true, Ordering::Relaxed);
+                Ok(())
+            })
+        };
+
+        let mut fout = Vec::new();
+        let options = Options {
+            stop,
+            ..Default::default()
+        };
+        watch(&path, &mut fout, "2", &options, Duration::from_millis(5))?;
+        changer.join().unwrap()?;
+
+        assert_eq!(
+            String::from_utf8(fout)?,
+            "\x1b[2J\x1b[HBar\n\x1b[2J\x1b[HBaz\n"
+        );
+
+        assert_eq!(parse_interval("0.5")?, Duration::from_millis(500));
+        assert!(parse_interval("-1").is_err());
+        assert!(parse_interval("soon").is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn watch_rotation() -> Result<()> {
+        let dir = TempDir::new("rotate")?;
+        let path = dir.join("app.log");
+        fs::write(&path, "Foo\nBar\n")?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let rotator = {
+            let (dir, path, stop) = (dir.to_path_buf(), path.clone(), Arc::clone(&stop));
+            thread::spawn(move || -> io::Result<()> {
+                thread::sleep(Duration::from_millis(100));
+                // Swap in a new file that only its inode tells apart from the old one
+                let modified = fs::metadata(&path)?.modified()?;
+                let replacement = dir.join("app.log.new");
+                fs::write(&replacement, "Foo\nBaz\n")?;
+                File::options()
+                    .write(true)
+                    .open(&replacement)?
+                    .set_modified(modified)?;
+                fs::rename(&replacement, &path)?;
+                thread::sleep(Duration::from_millis(100));
+                // Leave a gap before the next file shows up, like logrotate does
+                fs::rename(&path, dir.join("app.log.1"))?;
+                thread::sleep(Duration::from_millis(100));
+                replace_file(&path, "Foo\nQux\n")?;
+                thread::sleep(Duration::from_millis(100));
+                stop.store(true, Ordering::Relaxed);
+                Ok(())
+            })
+        };
+
+        let mut fout = Vec::new();
+        let options = Options {
+            stop,
+            ..Default::default()
+        };
+        watch(&path, &mut fout, "2", &options, Duration::from_millis(5))?;
+        rotator.join().unwrap()?;
+        drop(dir);
+
+        assert_eq!(
+            String::from_utf8(fout)?,
+            "\x1b[2J\x1b[HBar\n\x1b[2J\x1b[HBaz\n\x1b[2J\x1b[HQux\n"
+        );
+
+        // The file has to be there to begin with, though
+        let options = Options::default();
+        assert!(watch(&path, io::sink(), "2", &options, Duration::ZERO).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn keep_going() -> Result<()> {
+        let dir = TempDir::new("keep-going")?;
+        fs::write(dir.join("a.txt"), "a1\na2\n")?;
+        fs::write(dir.join("c.txt"), "c1\nc2\n")?;
+        let paths = ["a.txt", "missing.txt", "c.txt"].map(|name| dir.join(name));
+
+        let run = |keep_going| {
+            let mut fout = Vec::new();
+            let mut errors = Vec::new();
+            let result = each_input(
+                &paths,
+                keep_going,
+                &Default::default(),
+                &mut errors,
+                |path| {
+                    let fin = open_input(path, &Default::default(), false)?;
+                    write_lines(fin, &mut fout, "2", &Default::default())
+                },
+            );
+            (result, String::from_utf8(fout), String::from_utf8(errors))
+        };
+
+        let (result, fout, errors) = run(false);
+        assert!(result.is_err());
+        assert_eq!(fout?, "a2\n");
+        assert_eq!(errors?, "");
+
+        let (result, fout, errors) = run(true);
+        assert!(!result?);
+        assert_eq!(fout?, "a2\nc2\n");
+        assert!(errors?.starts_with(&format!("{}: ", paths[1].display())));
+        Ok(())
+    }
+
+    #[test]
+    fn stdin_name() -> Result<()> {
+        let paths = ["-", "foo.txt"].map(PathBuf::from);
+        let tvs = [
+            (None, "(standard input): Bad\nfoo.txt: Bad\n"),
+            (Some("piped"), "piped: Bad\nfoo.txt: Bad\n"),
+        ];
+        for tv in tvs {
+            let options = Options {
+                stdin_name: tv.0.map(String::from),
+                ..Default::default()
+            };
+            let mut errors = Vec::new();
+            each_input(&paths, true, &options, &mut errors, |_| bail!("Bad"))?;
+            assert_eq!(String::from_utf8(errors)?, tv.1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn pattern_separator() -> Result<()> {
+        let tvs = [
+            ("1;3..", "Foo\nBaz\nQux\n"),
+            ("2..=3#two, three;4", "Bar\nBaz\nQux\n"),
+            ("4", "Qux\n"),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from("Foo\nBar\nBaz\nQux\n"));
+            let mut fout = Vec::new();
+            let options = Options {
+                pattern_separator: Some(parse_pattern_separator(";")?),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+
+        // Commas are no longer special
+        let fin = Cursor::new(String::from("Foo\nBar\n"));
+        let options = Options {
+            pattern_separator: Some(';'),
+            ..Default::default()
+        };
+        assert!(write_lines(fin, &mut Vec::new(), "1,2", &options).is_err());
+
+        assert_eq!(read_patterns(Cursor::new("1\n3..\n"), ';')?, "1;3..");
+        for tv in ["", ";;", "1", ".", "=", "#", "-", "{", "}", "$"] {
+            assert!(parse_pattern_separator(tv).is_err(), "{tv}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn between() -> Result<()> {
+        let input = "a\nSTART 1\nb\nc\nEND 1\nd\nSTART 2\ne\nEND 2\n";
+        let tvs: &[(&str, &str, bool, &[&str])] = &[
+            ("START", "END", false, &["START 1", "b", "c", "END 1"]),
+            ("START", "END", true, &["b", "c"]),
+            ("START 2", "END", false, &["START 2", "e", "END 2"]),
+            ("START 2", "END", true, &["e"]),
+            ("b", "c", true, &[]),
+            ("b", "c", false, &["b", "c"]),
+            // The start line can't also be the end
+            (
+                "START",
+                "START",
+                false,
+                &["START 1", "b", "c", "END 1", "d", "START 2"],
+            ),
+            ("e", "nowhere", true, &["END 2"]),
+            ("nowhere", "END", false, &[]),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            let between = Between {
+                start: tv.0.into(),
+                end: tv.1.into(),
+                exclusive: tv.2,
+                require_end: false,
+            };
+            write_between(fin, &mut fout, &between, &Default::default())?;
+            let actual_lines = String::from_utf8(fout)?;
+            assert_eq!(actual_lines.lines().collect::<Vec<_>>(), tv.3, "{:?}", tv);
+        }
+
+        let between = |start: &str, end: &str| Between {
+            start: start.into(),
+            end: end.into(),
+            exclusive: false,
+            require_end: true,
+        };
+        let run = |between| {
+            let fin = Cursor::new(String::from(input));
+            write_between(fin, &mut Vec::new(), &between, &Default::default())
+        };
+        assert!(run(between("e", "nowhere")).is_err());
+        assert!(run(between("START", "END")).is_ok());
+        assert!(run(between("nowhere", "END")).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn limit_per_range() -> Result<()> {
+        let input = (1..=300).map(|i| format!("{i}\n")).collect::<String>();
+        let tvs: &[(usize, &str, &[&str])] = &[
+            (2, "1..100,200..300", &["1", "2", "200", "201"]),
+            (1, "1..100,200..300", &["1", "200"]),
+            (
+                3,
+                "5,5,10..=11,20..=30:5",
+                &["5", "5", "10", "11", "20", "25", "30"],
+            ),
+            (2, "10..=1:-1,50..", &["10", "9", "50", "51"]),
+            (2, "299..", &["299", "300"]),
+            (5, "299..", &["299", "300"]),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(&input);
+            let mut fout = Vec::new();
+            let options = Options {
+                limit_per_range: NonZeroUsize::new(tv.0),
+                ..Default::default()
+            };
+            write_lines(fin, &mut fout, tv.1, &options)?;
+            let actual_lines = String::from_utf8(fout)?;
+            assert_eq!(actual_lines.lines().collect::<Vec<_>>(), tv.2, "{}", tv.1);
+        }
+
+        // Once every pattern has shown its share, the rest of the input doesn't matter
+        for tv in [("..", "\n\n"), ("3..=4,10..", "\n\n\n\n")] {
+            let mut fout = Vec::new();
+            let options = Options {
+                limit_per_range: NonZeroUsize::new(2),
+                ..Default::default()
+            };
+            write_lines(io::repeat(b'\n'), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{}", tv.0);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn files_from() -> Result<()> {
+        let dir = TempDir::new("files-from")?;
+        let paths = ["a.txt", "b.txt", "c.txt"].map(|name| dir.join(name));
+        for (path, contents) in paths.iter().zip(["a1\na2\n", "b1\nb2\n", "c1\nc2\n"]) {
+            fs::write(path, contents)?;
+        }
+
+        let names = paths
+            .iter()
+            .map(|path| path.to_str().unwrap())
+            .collect::<Vec<_>>();
+        let tvs = [
+            (format!("{}\n{}\n", names[0], names[2]), b'\n'),
+            (format!("{}\r\n\n{}", names[0], names[2]), b'\n'),
+            (format!("{}\0{}\0", names[0], names[2]), b'\0'),
+        ];
+        for (list, separator) in tvs {
+            let files = read_file_list(Cursor::new(list), separator)?;
+            assert_eq!(files, [paths[0].clone(), paths[2].clone()]);
+
+            let mut fout = Vec::new();
+            for file in files {
+                write_lines(File::open(file)?, &mut fout, "2", &Default::default())?;
+            }
+            assert_eq!(String::from_utf8(fout)?, "a2\nc2\n");
+        }
+        assert!(read_file_list(Cursor::new(""), b'\n')?.is_empty());
+        assert!(read_file_list(Cursor::new(b"\xff\n"), b'\n').is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn column_filter() -> Result<()> {
+        let input = "alice  30 paris\nbob 25\tberlin\ncarol 30 rome\n\ndave,30,oslo\n";
+        let tvs = [
+            (
+                2,
+                None,
+                ColumnTest::Equals("30".into()),
+                "..",
+                "alice  30 paris\ncarol 30 rome\n",
+            ),
+            (
+                3,
+                None,
+                ColumnTest::Equals("berlin".into()),
+                "..",
+                "bob 25\tberlin\n",
+            ),
+            (1, None, ColumnTest::Equals("ali".into()), "..", ""),
+            (
+                1,
+                None,
+                ColumnTest::Contains("ali".into()),
+                "..",
+                "alice  30 paris\n",
+            ),
+            (
+                3,
+                None,
+                ColumnTest::Contains("r".into()),
+                "..",
+                "alice  30 paris\nbob 25\tberlin\ncarol 30 rome\n",
+            ),
+            // The column filter only narrows down the selected lines
+            (
+                2,
+                None,
+                ColumnTest::Equals("30".into()),
+                "2..",
+                "carol 30 rome\n",
+            ),
+            // Too few columns is never a match, even for an empty test
+            (4, None, ColumnTest::Contains("".into()), "..", ""),
+            (
+                3,
+                Some(","),
+                ColumnTest::Equals("oslo".into()),
+                "..",
+                "dave,30,oslo\n",
+            ),
+            (
+                2,
+                Some(","),
+                ColumnTest::Contains("".into()),
+                "..",
+                "dave,30,oslo\n",
+            ),
+        ];
+        for tv in tvs {
+            let options = Options {
+                column_filter: Some(ColumnFilter {
+                    column: NonZeroUsize::new(tv.0).unwrap(),
+                    separator: tv.1.map(|separator: &str| separator.into()),
+                    test: tv.2,
+                }),
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(input), &mut fout, tv.3, &options)?;
+            assert_eq!(
+                String::from_utf8(fout)?,
+                tv.4,
+                "{:?}",
+                options.column_filter
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn trim() -> Result<()> {
+        let input = "  Foo  \n\tBar\n Baz \t\r\n   \nQux\n";
+        let tvs = [
+            (Trim::Both, false, "Foo\nBar\nBaz\n\nQux\n"),
+            (Trim::Start, false, "Foo  \nBar\nBaz \t\n\nQux\n"),
+            (Trim::End, false, "  Foo\n\tBar\n Baz\n\nQux\n"),
+            // Line numbers come before the trimmed line, as usual
+            (Trim::Both, true, "1\tFoo\n2\tBar\n3\tBaz\n4\t\n5\tQux\n"),
+            (
+                Trim::End,
+                true,
+                "1\t  Foo\n2\t\tBar\n3\t Baz\n4\t\n5\tQux\n",
+            ),
+        ];
+        for tv in tvs {
+            let options = Options {
+                trim: Some(tv.0),
+                show_line_number: tv.1,
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(input), &mut fout, "..", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+
+        // Only the content is trimmed, so each line still has its terminator counted
+        let options = Options {
+            trim: Some(Trim::Both),
+            count_bytes: Some(ByteCounting::WithTerminators),
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(Cursor::new(input), &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "17\n");
+        Ok(())
+    }
+
+    #[test]
+    fn out_template() -> Result<()> {
+        let template = "{dir}/{stem}.sliced.{ext}".parse::<OutputTemplate>()?;
+        let tvs = [
+            ("logs/app.log", "logs/app.sliced.log"),
+            ("/var/log/syslog", "/var/log/syslog.sliced."),
+            ("notes.tar.gz", "./notes.tar.sliced.gz"),
+        ];
+        for tv in tvs {
+            assert_eq!(template.render(Path::new(tv.0)), Path::new(tv.1));
+        }
+        let template = "out/{{{stem}}}.txt".parse::<OutputTemplate>()?;
+        assert_eq!(
+            template.render(Path::new("a/b.c")),
+            Path::new("out/{b}.txt")
+        );
+        for template in ["{name}", "{stem", "stem}"] {
+            assert!(template.parse::<OutputTemplate>().is_err(), "{template}");
+        }
+
+        let dir = TempDir::new("out-template")?;
+        let input = dir.join("input.txt");
+        fs::write(&input, "a\nb\nc\n")?;
+        let template = format!("{}/sliced/{{stem}}.{{ext}}", dir.display()).parse()?;
+        // Directories are only made when asked for
+        assert!(create_templated_output(&input, &template, false).is_err());
+        let mut fout = create_templated_output(&input, &template, true)?;
+        write_lines(File::open(&input)?, &mut fout, "2..", &Options::default())?;
+        assert_eq!(fs::read_to_string(dir.join("sliced/input.txt"))?, "b\nc\n");
+
+        // An input is never overwritten with its own output
+        let template = "{dir}/{stem}.{ext}".parse()?;
+        assert!(create_templated_output(&input, &template, false).is_err());
+        assert_eq!(fs::read_to_string(&input)?, "a\nb\nc\n");
+        assert!(create_templated_output(Path::new("-"), &template, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn unique() -> Result<()> {
+        let input = "a\nb\na\nc\nb\nb\nd\na\n";
+        let tvs = [
+            ("..", false, "a\nb\nc\nd\n"),
+            ("..", true, "1\ta\n2\tb\n4\tc\n7\td\n"),
+            ("3..", true, "3\ta\n4\tc\n5\tb\n7\td\n"),
+            ("1,1,2", false, "a\nb\n"),
+            ("9..", false, ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                unique: true,
+                show_line_number: tv.1,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn sort() -> Result<()> {
+        let input = "pear\n10 figs\napple\n9 plums\n-2.5 limes\nbanana\n";
+        let tvs = [
+            (
+                false,
+                false,
+                "..",
+                "-2.5 limes\n10 figs\n9 plums\napple\nbanana\npear\n",
+            ),
+            (
+                false,
+                true,
+                "..",
+                "pear\nbanana\napple\n9 plums\n10 figs\n-2.5 limes\n",
+            ),
+            // Lines without a number count as 0, and stay in the order they were selected
+            (
+                true,
+                false,
+                "..",
+                "-2.5 limes\npear\napple\nbanana\n9 plums\n10 figs\n",
+            ),
+            (
+                true,
+                true,
+                "..",
+                "10 figs\n9 plums\npear\napple\nbanana\n-2.5 limes\n",
+            ),
+            (false, false, "1..=3", "10 figs\napple\npear\n"),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                sort: Some(Sort {
+                    numeric: tv.0,
+                    reverse: tv.1,
+                }),
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.2, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.3, "{tv:?}");
+        }
+
+        // Line numbers go along with their lines
+        let mut fout = Vec::new();
+        let options = Options {
+            sort: Some(Sort {
+                numeric: true,
+                reverse: false,
+            }),
+            show_line_number: true,
+            ..Default::default()
+        };
+        write_lines(Cursor::new("3\n1\n2\n"), &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "2\t1\n3\t2\n1\t3\n");
+
+        assert_eq!(leading_number(b"  42abc"), 42.0);
+        assert_eq!(leading_number(b"-0.5"), -0.5);
+        assert_eq!(leading_number(b"1.2.3"), 1.2);
+        assert_eq!(leading_number(b"-"), 0.0);
+        assert_eq!(leading_number(b""), 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn bom() -> Result<()> {
+        let options = Options {
+            bom: Cell::new(true),
+            show_line_number: true,
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        // Nothing is written for a file with nothing selected, not even the mark
+        write_lines(Cursor::new("a\n"), &mut fout, "2", &options)?;
+        assert!(fout.is_empty());
+        write_lines(Cursor::new("a\nb\n"), &mut fout, "2", &options)?;
+        write_lines(Cursor::new("c\n"), &mut fout, "1", &options)?;
+        assert!(fout.starts_with(&[0xef, 0xbb, 0xbf]));
+        assert_eq!(String::from_utf8(fout)?, "\u{feff}2\tb\n1\tc\n");
+
+        // It comes before a hunk's header, not inside the hunk
+        let options = Options {
+            bom: Cell::new(true),
+            hunk_headers: true,
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(Cursor::new("a\n"), &mut fout, "1", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "\u{feff}@@ -1,1 @@\na\n");
+        Ok(())
+    }
+
+    #[test]
+    fn substitution() -> Result<()> {
+        let input = "foo bar foo\nbaz\na/b/a\n";
+        let tvs = [
+            ("/foo/qux/", "qux bar foo\nbaz\na/b/a\n"),
+            ("/foo/qux/g", "qux bar qux\nbaz\na/b/a\n"),
+            ("/foo/[&]/g", "[foo] bar [foo]\nbaz\na/b/a\n"),
+            ("/foo/\\&&/", "&foo bar foo\nbaz\na/b/a\n"),
+            ("/a\\/b/&&/", "foo bar foo\nbaz\na/ba/b/a\n"),
+            ("|/|-|g", "foo bar foo\nbaz\na-b-a\n"),
+            ("/ba//g", "foo r foo\nz\na/b/a\n"),
+            ("/nope/x/", input),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                substitution: Some(tv.0.parse()?),
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, "..", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{}", tv.0);
+        }
+
+        for spec in ["", "s/a/b/", "/a/b", "//b/", "/a/b/x", "/a/b/gg"] {
+            assert!(spec.parse::<Substitution>().is_err(), "{spec}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn replace_tabs() -> Result<()> {
+        let input = "a\tb\tc\nno tabs\n\t\n";
+        let tvs = [
+            ("..", ", ", "a, b, c\nno tabs\n, \n"),
+            ("1", "", "abc\n"),
+            ("1", "\t\t", "a\t\tb\t\tc\n"),
+            ("3", "👋", "👋\n"),
+        ];
+        for tv in tvs {
+            let options = Options {
+                tab_replacement: Some(tv.1.into()),
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+
+        // Fields are picked out first, so tabs can still separate them
+        let options = Options {
+            fields: Some(Fields::new("1,3", "\t")?),
+            tab_replacement: Some(";".into()),
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(Cursor::new(input), &mut fout, "1", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "a;c\n");
+        Ok(())
+    }
+
+    #[test]
+    fn offset() -> Result<()> {
+        let input = "Foo\nBar\nBaz\nQux\nQuux\n";
+        let tvs = [
+            ("..", 2, false, "Baz\nQux\nQuux\n"),
+            ("..", 2, true, "3\tBaz\n4\tQux\n5\tQuux\n"),
+            ("2..", 1, true, "3\tBaz\n4\tQux\n5\tQuux\n"),
+            ("1,1,4", 2, false, "Qux\n"),
+            ("..", 0, false, input),
+            ("..", 5, false, ""),
+            ("..", 10, false, ""),
+        ];
+        for tv in tvs {
+            let options = Options {
+                offset: tv.1,
+                show_line_number: tv.2,
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.3, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn table() -> Result<()> {
+        let input = (1..=12).map(|n| format!("line {n}\n")).collect::<String>();
+        let tvs = [
+            (
+                "8..=11",
+                None,
+                " 8 | line 8\n 9 | line 9\n10 | line 10\n11 | line 11\n",
+            ),
+            ("2,4", None, "2 | line 2\n4 | line 4\n"),
+            ("9..=10", Some(8), " 9 | lin\n10 | lin\n"),
+            // Rows that already fit are left alone
+            ("1", Some(20), "1 | line 1\n"),
+            ("20..", None, ""),
+        ];
+        for tv in tvs {
+            let options = Options {
+                table: Some(Table {
+                    max_width: tv.1.and_then(NonZeroUsize::new),
+                }),
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(&input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn start_at_byte() -> Result<()> {
+        let input = "Foo\nBar\nBaz\nQux\n";
+        let tvs = [
+            (0, "1\tFoo\n2\tBar\n3\tBaz\n4\tQux\n"),
+            // Partway through a line skips to the next one
+            (1, "1\tBar\n2\tBaz\n3\tQux\n"),
+            (3, "1\tBar\n2\tBaz\n3\tQux\n"),
+            // Right at the start of a line begins with it
+            (4, "1\tBar\n2\tBaz\n3\tQux\n"),
+            (8, "1\tBaz\n2\tQux\n"),
+            (12, "1\tQux\n"),
+            (13, ""),
+            (100, ""),
+        ];
+        for tv in tvs {
+            let options = Options {
+                show_line_number: true,
+                ..Default::default()
+            };
+            let fin = seek_to_line(Cursor::new(input), tv.0)?;
+            let mut fout = Vec::new();
+            write_lines(fin, &mut fout, "..", &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{tv:?}");
+        }
+
+        // Files are seeked the same way, but stdin can't be
+        let dir = TempDir::new("seek")?;
+        let path = dir.join("seek.txt");
+        fs::write(&path, input)?;
+        let options = Options {
+            start_at_byte: Some(6),
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(
+            open_input(&path, &options, false)?,
+            &mut fout,
+            "1",
+            &options,
+        )?;
+        assert_eq!(String::from_utf8(fout)?, "Baz\n");
+        assert!(open_input(Path::new("-"), &options, false).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn line_index() -> Result<()> {
+        let dir = TempDir::new("index")?;
+        let path = dir.join("file.txt");
+        let index_path = dir.join("file.idx");
+        fs::write(&path, "Foo\nBar\r\n\nBaz")?;
+
+        let index = LineIndex::build(File::open(&path)?, &fs::metadata(&path)?)?;
+        assert_eq!(index.offsets, [0, 4, 9, 10]);
+        let mut written = Vec::new();
+        index.write(&mut written)?;
+        assert_eq!(LineIndex::read(Cursor::new(&written))?, index);
+        assert!(LineIndex::read(Cursor::new(&written[..written.len() - 1])).is_err());
+        assert!(LineIndex::read(Cursor::new("Foo\nBar\nBaz\nQux\n")).is_err());
+
+        let options = Options {
+            show_line_number: true,
+            ..Default::default()
+        };
+        let select = |patterns, build| -> Result<String> {
+            let mut fout = Vec::new();
+            write_indexed(&path, &index_path, build, &mut fout, patterns, &options)?;
+            Ok(String::from_utf8(fout)?)
+        };
+        let tvs = [
+            ("2..=3", "2\tBar\n3\t\n"),
+            ("4", "4\tBaz\n"),
+            ("5..", ""),
+            ("..", "1\tFoo\n2\tBar\n3\t\n4\tBaz\n"),
+        ];
+        for tv in tvs {
+            assert_eq!(select(tv.0, true)?, tv.1);
+        }
+        let built = LineIndex::read(File::open(&index_path)?)?;
+        assert_eq!(built, index);
+        assert!(built.is_current(&fs::metadata(&path)?));
+
+        // A file that's changed makes the index stale, so it's ignored unless rebuilt
+        fs::write(&path, "Qux\nQuux\nFoo\nBar\nBaz\n")?;
+        assert!(!built.is_current(&fs::metadata(&path)?));
+        assert_eq!(select("4", false)?, "4\tBar\n");
+        assert_eq!(LineIndex::read(File::open(&index_path)?)?, built);
+        assert_eq!(select("4", true)?, "4\tBar\n");
+        let rebuilt = LineIndex::read(File::open(&index_path)?)?;
+        assert_eq!(rebuilt.offsets, [0, 4, 9, 13, 17]);
+
+        // Same size, different time
+        let file = File::options().write(true).open(&path)?;
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(60))?;
+        assert!(!rebuilt.is_current(&fs::metadata(&path)?));
+        Ok(())
+    }
+
+    #[test]
+    fn server() -> Result<()> {
+        let dir = TempDir::new("server")?;
+        let path = dir.join("file.txt");
+        fs::write(&path, "Foo\nBar\nBaz\nQux\n")?;
+
+        let control = Cursor::new("2\n\n1..=2,4\n3,1\n9..\n");
+        let mut fout = Vec::new();
+        let options = Options {
+            show_line_number: true,
+            ..Default::default()
+        };
+        serve(&path, control, &mut fout, &options)?;
+        assert_eq!(
+            String::from_utf8(fout)?,
+            concat!(
+                "ok 6\n2\tBar\n",
+                "ok 18\n1\tFoo\n2\tBar\n4\tQux\n",
+                "error Lines currently must be given in order\n",
+                "ok 0\n",
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn split_ranges_into_files() -> Result<()> {
+        let dir = TempDir::new("split-ranges")?;
+        let template = dir.join("range_%d.txt");
+        let template = template.to_str().unwrap();
+        let input = "Foo\nBar\nBaz\nQux\nQuux\n";
+
+        split_ranges(
+            Cursor::new(input),
+            "1..=3,3..",
+            template,
+            &Default::default(),
+        )?;
+        assert_eq!(
+            fs::read_to_string(numbered_name(template, 0)?)?,
+            "Foo\nBar\nBaz\n"
+        );
+        assert_eq!(
+            fs::read_to_string(numbered_name(template, 1)?)?,
+            "Baz\nQux\nQuux\n"
+        );
+
+        // Each file is shown the way stdout would be
+        let options = Options {
+            show_line_number: true,
+            ..Default::default()
+        };
+        split_ranges(Cursor::new(input), "2,9..", template, &options)?;
+        assert_eq!(fs::read_to_string(numbered_name(template, 0)?)?, "2\tBar\n");
+        assert_eq!(fs::read_to_string(numbered_name(template, 1)?)?, "");
+
+        let single = dir.join("single.txt");
+        let single = single.to_str().unwrap();
+        split_ranges(Cursor::new(input), "4..", single, &Default::default())?;
+        assert_eq!(fs::read_to_string(single)?, "Qux\nQuux\n");
+        assert!(split_ranges(Cursor::new(input), "1,2", single, &Default::default()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn one_line() -> Result<()> {
+        let input = "The quick \nbrown fox\r\n jumped\nover\n";
+        let tvs = [
+            ("..=3", "The quick brown fox jumped\n"),
+            ("2", "brown fox\n"),
+            ("1,3", "The quick  jumped\n"),
+            ("9..", ""),
+        ];
+        for tv in tvs {
+            let mut fout = Vec::new();
+            let options = Options {
+                one_line: true,
+                ..Default::default()
+            };
+            write_lines(Cursor::new(input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1);
+        }
+
+        // The line ends the way the input's lines do
+        let mut fout = Vec::new();
+        let options = Options {
+            one_line: true,
+            line_terminator_auto: true,
+            ..Default::default()
+        };
+        write_lines(Cursor::new("a\r\nb\r\n"), &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "ab\r\n");
+        Ok(())
+    }
+
+    #[test]
+    fn line_terminator_auto() -> Result<()> {
+        let tvs = [
+            ("..", "Foo\nBar\nBaz", "Foo\nBar\nBaz\n"),
+            ("..", "Foo\r\nBar\r\nBaz", "Foo\r\nBar\r\nBaz\r\n"),
+            ("2..", "Foo\r\nBar\r\nBaz\r\n", "Bar\r\nBaz\r\n"),
+            // Mixed lines keep their own terminators
+            ("..", "Foo\r\nBar\nBaz\r\n", "Foo\r\nBar\nBaz\r\n"),
+            ("2,2", "Foo\r\nBar\nBaz\r\n", "Bar\nBar\n"),
+            ("3..=1:-1", "Foo\r\nBar\nBaz\r\n", "Baz\r\nBar\nFoo\r\n"),
+            // The last line gets whichever is more common
+            ("..", "Foo\r\nBar\r\nBaz\nQux", "Foo\r\nBar\r\nBaz\nQux\r\n"),
+            ("..", "Foo\r\nBar\nBaz\nQux", "Foo\r\nBar\nBaz\nQux\n"),
+            ("..", "Foo", "Foo\n"),
+        ];
+        for tv in tvs {
+            let options = Options {
+                line_terminator_auto: true,
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(tv.1), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+
+        // Terminators aren't part of the line, so they're kept out of the way of formatting
+        let options = Options {
+            line_terminator_auto: true,
+            show_line_number: true,
+            fields: Some(Fields::new("2", ",")?),
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(Cursor::new("a,b\r\nc,d\n"), &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "1\tb\r\n2\td\n");
+
+        let options = Options {
+            line_terminator_auto: true,
+            count_bytes: Some(ByteCounting::WithTerminators),
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(Cursor::new("Foo\r\nBar\n"), &mut fout, "..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "9\n");
+        Ok(())
+    }
+
+    #[test]
+    fn group_by_gap() -> Result<()> {
+        let input = (1..=10).map(|n| format!("{n}\n")).collect::<String>();
+        let tvs = [
+            ("..", "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n"),
+            ("2..=3,5,6", "2\n3\n\n5\n6\n"),
+            ("1,3,5", "1\n\n3\n\n5\n"),
+            // A repeated line isn't a gap
+            ("4,4,5", "4\n4\n5\n"),
+            // Neither is counting down
+            ("3..=1:-1,8..=7:-1", "3\n2\n1\n\n8\n7\n"),
+            ("1..=9:2", "1\n\n3\n\n5\n\n7\n\n9\n"),
+            ("11..", ""),
+        ];
+        for tv in tvs {
+            let options = Options {
+                group_by_gap: true,
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new(&input), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.1, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn selected_any() -> Result<()> {
+        let tvs = [
+            ("..", "Foo\nBar\n", true),
+            ("2", "Foo\nBar\n", true),
+            ("3..", "Foo\nBar\n", false),
+            ("..", "", false),
+            // A blank line still counts
+            ("2", "Foo\n\n", true),
+        ];
+        for tv in tvs {
+            let fin = Cursor::new(String::from(tv.1));
+            let options = Options::default();
+            write_lines(fin, &mut Vec::new(), tv.0, &options)?;
+            assert_eq!(options.selected_any.get(), tv.2, "{tv:?}");
+        }
+
+        // Any file selecting something is enough
+        let options = Options::default();
+        write_lines(Cursor::new("Foo\nBar\n"), &mut Vec::new(), "2", &options)?;
+        write_lines(Cursor::new("Foo\n"), &mut Vec::new(), "2", &options)?;
+        assert!(options.selected_any.get());
+        Ok(())
+    }
+
+    #[test]
+    fn sizes() -> Result<()> {
+        let tvs = [
+            ("1", 1),
+            ("4096", 4096),
+            ("64k", 64 << 10),
+            ("64K", 64 << 10),
+            ("2m", 2 << 20),
+            ("1g", 1 << 30),
+        ];
+        for tv in tvs {
+            assert_eq!(parse_size(tv.0)?.get(), tv.1, "{tv:?}");
+        }
+        for size in ["", "0", "0k", "k", "-1", "1.5k", "1kb", "1t"] {
+            assert!(parse_size(size).is_err(), "{size:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn output_file() -> Result<()> {
+        let dir = TempDir::new("output")?;
+        let path = dir.join("output.txt");
+        let mut fout = open_output(Some(&path), false)?;
+        write_lines(
+            Cursor::new("Foo\nBar\nBaz\n"),
+            &mut fout,
+            "2..",
+            &Options::default(),
+        )?;
+        fout.finish()?;
+        assert_eq!(fs::read_to_string(&path)?, "Bar\nBaz\n");
+
+        #[cfg(all(feature = "gzip", unix))]
+        {
+            let mut fout = open_output(Some(&path), true)?;
+            write_lines(
+                Cursor::new("Foo\nBar\nBaz\n"),
+                &mut fout,
+                "2..",
+                &Options::default(),
+            )?;
+            fout.finish()?;
+            let unzipped = process::Command::new("gzip")
+                .arg("-dc")
+                .arg(&path)
+                .output()?;
+            assert!(unzipped.status.success());
+            assert_eq!(String::from_utf8(unzipped.stdout)?, "Bar\nBaz\n");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn output_buffering() -> Result<()> {
+        #[derive(Default)]
+        struct FlushCounter {
+            written: Vec<u8>,
+            flushes: usize,
+        }
+
+        impl Write for FlushCounter {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.written.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                self.flushes += 1;
+                Ok(())
+            }
+        }
+
+        let input = "Foo\nBar\nBaz\nQux\n";
+        // A tiny buffer changes nothing about what's written
+        let mut fout = BufWriter::with_capacity(1, Vec::new());
+        write_lines(Cursor::new(input), &mut fout, "2..", &Options::default())?;
+        assert_eq!(String::from_utf8(fout.into_inner()?)?, "Bar\nBaz\nQux\n");
+
+        for (unbuffered, flushes) in [(false, 1), (true, 4)] {
+            let options = Options {
+                unbuffered,
+                ..Default::default()
+            };
+            let mut fout = FlushCounter::default();
+            write_lines(Cursor::new(input), &mut fout, "2..", &options)?;
+            assert_eq!(String::from_utf8(fout.written)?, "Bar\nBaz\nQux\n");
+            // One for each line, and one at the end
+            assert_eq!(fout.flushes, flushes, "{unbuffered}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn binary_files() -> Result<()> {
+        let binary = "Foo\n\0Bar\n";
+        let tvs = [
+            (BinaryPolicy::Print, binary, binary, ""),
+            (
+                BinaryPolicy::Warn,
+                binary,
+                binary,
+                "Warning: foo.bin looks like a binary file\n",
+            ),
+            (
+                BinaryPolicy::Skip,
+                binary,
+                "",
+                "Skipping binary file foo.bin\n",
+            ),
+            // Text is left alone under every policy
+            (BinaryPolicy::Warn, "Foo\nBar\n", "Foo\nBar\n", ""),
+            (BinaryPolicy::Skip, "Foo\nBar\n", "Foo\nBar\n", ""),
+        ];
+        for tv in tvs {
+            let mut errors = Vec::new();
+            let mut fin = screen_binary(Box::new(Cursor::new(tv.1)), "foo.bin", tv.0, &mut errors)?;
+            let mut contents = String::new();
+            fin.read_to_string(&mut contents)?;
+            assert_eq!(contents, tv.2, "{tv:?}");
+            assert_eq!(String::from_utf8(errors)?, tv.3, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn chunks() -> Result<()> {
+        let chunk = |size, prefix| {
+            Some(Chunk {
+                size: NonZeroUsize::new(size).unwrap(),
+                prefix,
+                file: RefCell::new(None),
+            })
+        };
+        let tvs = [
+            ("..", 2, "1\n2\n--\n3\n4\n--\n5\n"),
+            ("..", 5, "1\n2\n3\n4\n5\n"),
+            ("2..=5", 3, "2\n3\n4\n--\n5\n"),
+            ("1,5", 1, "1\n--\n5\n"),
+            ("6..", 1, ""),
+        ];
+        for tv in tvs {
+            let options = Options {
+                chunk: chunk(tv.1, None),
+                ..Default::default()
+            };
+            let mut fout = Vec::new();
+            write_lines(Cursor::new("1\n2\n3\n4\n5\n"), &mut fout, tv.0, &options)?;
+            assert_eq!(String::from_utf8(fout)?, tv.2, "{tv:?}");
+        }
+
+        let dir = TempDir::new("chunks")?;
+        let prefix = dir.join("out_").to_str().unwrap().to_owned();
+        let options = Options {
+            chunk: chunk(2, Some(prefix.clone())),
+            ..Default::default()
+        };
+        let mut fout = Vec::new();
+        write_lines(Cursor::new("1\n2\n3\n4\n5\n"), &mut fout, "..", &options)?;
+        // Nothing goes to the usual output
+        assert!(fout.is_empty());
+        assert_eq!(fs::read_dir(&dir)?.count(), 3);
+        assert_eq!(fs::read_to_string(format!("{prefix}0"))?, "1\n2\n");
+        assert_eq!(fs::read_to_string(format!("{prefix}1"))?, "3\n4\n");
+        assert_eq!(fs::read_to_string(format!("{prefix}2"))?, "5\n");
+        Ok(())
+    }
+
+    #[test]
+    fn bytes() -> Result<()> {
+        let input = b"\x01\x02\x03\x04\x05\x06";
+        let tvs: [(&str, bool, &[u8]); 7] = [
+            ("2..=5", false, b"\x02\x03\x04\x05"),
+            ("2..=5", true, b"\x05\x04\x03\x02"),
+            // A 32-bit little-endian value comes out big-endian, with no regard for its meaning
+            ("1..5", true, b"\x04\x03\x02\x01"),
+            ("5..", true, b"\x06\x05"),
+            ("..", true, b"\x06\x05\x04\x03\x02\x01"),
+            ("3", false, b"\x03"),
+            ("7..", true, b""),
+        ];
+        for tv in tvs {
+            let options = Options::default();
+            let mut fout = Vec::new();
+            write_bytes(
+                &input[..],
+                &mut fout,
+                &parse_byte_range(tv.0)?,
+                tv.1,
+                &options,
+            )?;
+            assert_eq!(fout, tv.2, "{tv:?}");
+            assert_eq!(options.selected_any.get(), !tv.2.is_empty(), "{tv:?}");
+        }
+
+        // Reversing isn't aware of characters, so it can break them apart
+        let mut fout = Vec::new();
+        let range = parse_byte_range("..")?;
+        write_bytes("é".as_bytes(), &mut fout, &range, true, &Options::default())?;
+        assert_eq!(fout, [0xa9, 0xc3]);
+
+        assert!(parse_byte_range("1..=9:2").is_err());
+        assert!(parse_byte_range("9..=1:-1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn summary() -> Result<()> {
+        let tvs = [
+            ("", [0, 0, 0, 0]),
+            ("Foo", [1, 3, 3, 0]),
+            ("Foo\n\nBarbaz\r\n\n", [4, 14, 6, 2]),
+            ("\n\n\n", [3, 3, 0, 3]),
+        ];
+        for (input, [lines, bytes, longest, blank]) in tvs {
+            let fin = Cursor::new(String::from(input));
+            let mut fout = Vec::new();
+            write_summary(fin, &mut fout, &Default::default())?;
+            assert_eq!(
+                String::from_utf8(fout)?,
+                format!(
+                    "lines: {lines}\nbytes: {bytes}\nlongest line: {longest}\nblank lines: {blank}\n"
+                )
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn anchor_substitution() -> Result<()> {
+        let anchors = parse_anchors("# Sections\nHEADER=1\n\n BODY = 3\n")?;
+        assert_eq!(substitute_anchors("HEADER", &anchors)?, "1");
+        assert_eq!(substitute_anchors("BODY..", &anchors)?, "3..");
+        assert_eq!(substitute_anchors("HEADER..=BODY", &anchors)?, "1..=3");
+        assert_eq!(substitute_anchors("2..BODY", &anchors)?, "2..3");
+
+        let fin = Cursor::new(String::from("Foo\nBar\nBaz\nQux"));
+        let mut fout = Vec::new();
+        let options = Options {
+            anchors,
+            ..Default::default()
+        };
+        write_lines(fin, &mut fout, "HEADER,BODY..", &options)?;
+        assert_eq!(String::from_utf8(fout)?, "Foo\nBaz\nQux\n");
+        Ok(())
+    }
+
+    #[test]
+    fn undefined_anchor() -> Result<()> {
+        let anchors = parse_anchors("HEADER=1")?;
+        let err = substitute_anchors("HEADER..FOOTER", &anchors).unwrap_err();
+        assert_eq!(err.to_string(), "Undefined anchor: FOOTER");
+
+        assert!(parse_anchors("HEADER").is_err());
+        assert!(parse_anchors("1HEADER=1").is_err());
+        assert!(parse_anchors("HEADER=").is_err());
+        Ok(())
     }
 }
@@ -0,0 +1,828 @@
+//! Picking lines out of a stream by line number, as `line` does
+use anyhow::{Result, anyhow, bail};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, BufRead, BufReader, Read},
+    num::NonZeroUsize,
+    str::FromStr,
+};
+
+/// `$` stands for the last line, which isn't known until the input ends. Until then, it's held as
+/// the largest possible line number, which no line reaches
+pub const LAST_LINE: NonZeroUsize = NonZeroUsize::MAX;
+
+/// Pattern that may have a starting and ending line number
+///
+/// Parsed from a Rust-like range pattern:
+/// `..`, `5..`, `6..=10`, etc.
+/// Ranges may be followed by a step, as in `1..=9:2` or `10..=2:-2`
+/// `$` is the last line, like in ed, so `$` and `$..$` select the last line, and `5..$` runs to
+/// the end
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    pub start: Option<NonZeroUsize>,
+    /// This end is INCLUSIVE
+    pub end: Option<NonZeroUsize>,
+    /// Only every `step`th line of the range is included
+    pub step: NonZeroUsize,
+    /// Lines are given from the end of the range back to the start.
+    /// Counting by `step` begins at the end, too
+    pub descending: bool,
+    /// Human-readable name, given after a `#`
+    pub label: Option<String>,
+}
+
+impl Pattern {
+    pub fn new(start: Option<NonZeroUsize>, end: Option<NonZeroUsize>) -> Self {
+        Self {
+            start,
+            end,
+            step: NonZeroUsize::MIN,
+            descending: false,
+            label: None,
+        }
+    }
+
+    /// Check if a line number would be included
+    pub fn is_included(&self, line: NonZeroUsize) -> bool {
+        if let Some(start) = self.start
+            && line < start
+        {
+            return false;
+        }
+        if let Some(end) = self.end
+            && line > end
+        {
+            return false;
+        }
+        let offset = if self.descending {
+            self.end.map_or(0, NonZeroUsize::get) - line.get()
+        } else {
+            line.get() - self.start.map_or(1, NonZeroUsize::get)
+        };
+        offset.is_multiple_of(self.step.get())
+    }
+
+    /// True if the pattern starts at `$`, so it can't select anything until the last line is
+    /// known
+    pub fn refers_to_last(&self) -> bool {
+        self.start == Some(LAST_LINE)
+    }
+
+    /// Put `last`, now known to be the last line, in place of `$`
+    pub fn resolve_last(&mut self, last: NonZeroUsize) {
+        if self.refers_to_last() {
+            self.start = Some(last);
+        }
+    }
+
+    /// Turn into a concrete, inclusive range, given the number of lines in the input.
+    /// Returns None if the range would be empty
+    pub fn resolve(&self, line_count: usize) -> Option<(usize, usize)> {
+        let start = match self.start {
+            Some(LAST_LINE) => line_count.max(1),
+            start => start.map_or(1, NonZeroUsize::get),
+        };
+        let end = self.end.map_or(line_count, NonZeroUsize::get);
+        (start <= end).then_some((start, end))
+    }
+
+    /// Construct a pattern from a string
+    pub fn parse(pattern: &str) -> Result<Self> {
+        fn try_nonzero(num: usize) -> Result<NonZeroUsize> {
+            NonZeroUsize::new(num).ok_or_else(|| anyhow!("Line numbers are 1-indexed"))
+        }
+
+        let (pattern, step) = match pattern.split_once(':') {
+            Some((pattern, step)) => (pattern, Some(parse_step(step)?)),
+            None => (pattern, None),
+        };
+
+        if let Some((start, end)) = pattern.split_once("..") {
+            let from_last = start == "$";
+            // Running to the last line is the same as running to the end
+            let end = match end {
+                "$" | "=$" => "",
+                end => end,
+            };
+            if from_last && !end.is_empty() {
+                bail!("A range from `$` can only end at `$`");
+            }
+            let start = if start.is_empty() {
+                None
+            } else if from_last {
+                Some(LAST_LINE)
+            } else {
+                Some(try_nonzero(parse_number(start)?)?)
+            };
+
+            // The raw end, and whether it's inclusive
+            let end = if end.is_empty() {
+                None
+            } else if let Some(end) = end.strip_prefix("=") {
+                Some((parse_number(end)?, true))
+            } else {
+                Some((parse_number(end)?, false))
+            };
+
+            let (step, descending) = step.unwrap_or((NonZeroUsize::MIN, false));
+            if descending {
+                let (Some(start), Some((end, inclusive))) = (start, end) else {
+                    bail!("Negative steps need both a start and an end, which can't be `$`");
+                };
+                if start.get() <= end {
+                    bail!("Negative steps need a start greater than the end");
+                }
+                // The end is the low side, so an exclusive end stops just above it
+                let low = if inclusive { end } else { end + 1 };
+                return Ok(Self {
+                    step,
+                    descending,
+                    ..Self::new(Some(try_nonzero(low)?), Some(start))
+                });
+            }
+
+            let end = match end {
+                None => None,
+                Some((end, true)) => Some(try_nonzero(end)?),
+                Some((end, false)) => {
+                    if end <= 1 {
+                        bail!("End of exclusive range must be greater than 1");
+                    }
+                    Some(try_nonzero(end - 1)?)
+                }
+            };
+
+            if let (Some(start), Some(end)) = (start, end)
+                && start > end
+            {
+                bail!("Reverse patterns need a negative step, like `10..=1:-1`");
+            }
+
+            Ok(Self {
+                step,
+                ..Self::new(start, end)
+            })
+        } else if step.is_some() {
+            bail!("Steps can only be used with ranges: {pattern}");
+        } else if pattern == "$" {
+            Ok(Self::new(Some(LAST_LINE), None))
+        } else if let Ok(start) = parse_number(pattern) {
+            let val = Some(try_nonzero(start)?);
+            Ok(Self::new(val, val))
+        } else {
+            bail!("Could not interpret line number pattern: {pattern}");
+        }
+    }
+}
+
+// Parse the step of a range, and whether it's negative
+fn parse_step(step: &str) -> Result<(NonZeroUsize, bool)> {
+    let (magnitude, descending) = match step.strip_prefix('-') {
+        Some(magnitude) => (magnitude, true),
+        None => (step, false),
+    };
+    let magnitude = NonZeroUsize::new(parse_number(magnitude)?)
+        .ok_or_else(|| anyhow!("Step must not be zero"))?;
+    Ok((magnitude, descending))
+}
+
+/// Parse a line number, which may use underscores as digit separators, as in `1_000_000`.
+/// Unlike Rust literals, underscores must fall between two digits.
+/// A `0x`, `0o`, or `0b` prefix means hexadecimal, octal, or binary
+pub fn parse_number(number: &str) -> Result<usize> {
+    let (digits, radix) = match number.get(..2) {
+        Some("0x") => (&number[2..], 16),
+        Some("0o") => (&number[2..], 8),
+        Some("0b") => (&number[2..], 2),
+        _ => (number, 10),
+    };
+    if digits.starts_with('_') || digits.ends_with('_') || digits.contains("__") {
+        bail!("Misplaced underscore in line number: {number}");
+    }
+    // `from_str_radix` would otherwise allow a sign
+    if digits.starts_with('+') {
+        bail!("Could not interpret line number: {number}");
+    }
+    Ok(usize::from_str_radix(&digits.replace('_', ""), radix)?)
+}
+
+/// Lines are consumed as they're read, so patterns must be given in order.
+/// In the future, this restriction could be lifted
+pub fn check_order(patterns: &[Pattern]) -> Result<()> {
+    patterns
+        .iter()
+        .try_fold(Pattern::new(None, None), |prev, this| {
+            if prev.start.is_some() || prev.end.is_some() {
+                let prev_end = prev.end.unwrap_or(NonZeroUsize::MAX);
+                let this_start = this.start.unwrap_or(NonZeroUsize::MIN);
+                if prev_end > this_start {
+                    return Err(anyhow!("Lines currently must be given in order"));
+                }
+            }
+            Ok(this.clone())
+        })?;
+    Ok(())
+}
+
+/// How many times the pattern list calls for a line
+pub fn times_selected(patterns: &[Pattern], number: NonZeroUsize) -> usize {
+    patterns
+        .iter()
+        .filter(|pattern| pattern.is_included(number))
+        .count()
+}
+
+/// True if no pattern can select any line after this one
+pub fn is_exhausted(patterns: &[Pattern], number: NonZeroUsize) -> bool {
+    patterns
+        .iter()
+        .all(|pattern| pattern.end.is_some_and(|end| end <= number))
+}
+
+/// Parse a list of patterns split by `separator`, like `1,5..=10#body,20..`
+///
+/// A pattern may be followed by a `#` and a label, and may use names from `anchors` in place of
+/// line numbers
+pub fn parse_patterns(
+    patterns: &str,
+    separator: char,
+    anchors: &HashMap<String, String>,
+) -> Result<Vec<Pattern>> {
+    patterns
+        .split(separator)
+        .map(|pattern| {
+            let (pattern, label) = split_label(pattern);
+            Ok(Pattern {
+                label,
+                ..Pattern::parse(&substitute_anchors(pattern, anchors)?)?
+            })
+        })
+        .collect()
+}
+
+/// Separate a pattern from its label, as in `1..10#header`
+pub fn split_label(pattern: &str) -> (&str, Option<String>) {
+    match pattern.split_once('#') {
+        Some((pattern, label)) => {
+            let label = label.trim();
+            (pattern, (!label.is_empty()).then(|| label.into()))
+        }
+        None => (pattern, None),
+    }
+}
+
+/// Replace anchor names in a pattern with the line numbers they stand for
+pub fn substitute_anchors(pattern: &str, anchors: &HashMap<String, String>) -> Result<String> {
+    let mut substituted = String::with_capacity(pattern.len());
+    let mut rest = pattern;
+    while let Some(c) = rest.chars().next() {
+        // Numbers may contain letters (e.g. radix prefixes), so skip them whole
+        let word_len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        if word_len == 0 {
+            substituted.push(c);
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+        let (word, tail) = rest.split_at(word_len);
+        if c.is_ascii_digit() {
+            substituted.push_str(word);
+        } else {
+            let value = anchors
+                .get(word)
+                .ok_or_else(|| anyhow!("Undefined anchor: {word}"))?;
+            substituted.push_str(value);
+        }
+        rest = tail;
+    }
+    Ok(substituted)
+}
+
+/// A list of patterns, checked to be in order
+///
+/// Parsed from a comma-separated list like `1,5..=10#body,20..`
+#[derive(Debug, Clone)]
+pub struct Selection {
+    patterns: Vec<Pattern>,
+}
+
+impl Selection {
+    pub fn new(patterns: Vec<Pattern>) -> Result<Self> {
+        check_order(&patterns)?;
+        Ok(Self { patterns })
+    }
+
+    /// Parse patterns split by `separator` instead of a comma, which may use names from
+    /// `anchors` in place of line numbers
+    pub fn parse(
+        patterns: &str,
+        separator: char,
+        anchors: &HashMap<String, String>,
+    ) -> Result<Self> {
+        Self::new(parse_patterns(patterns, separator, anchors)?)
+    }
+
+    pub fn patterns(&self) -> &[Pattern] {
+        &self.patterns
+    }
+}
+
+impl FromStr for Selection {
+    type Err = anyhow::Error;
+
+    fn from_str(patterns: &str) -> Result<Self> {
+        Self::parse(patterns, ',', &HashMap::new())
+    }
+}
+
+/// Strip a trailing `\n` or `\r\n` from a line
+pub fn trim_terminator(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Read a line, including its terminator, into `line`, failing if it's longer than
+/// `max_line_length` bytes without the terminator.
+/// Returns false if there are no more lines
+pub fn read_line(
+    fin: &mut impl BufRead,
+    line: &mut Vec<u8>,
+    max_line_length: Option<usize>,
+) -> Result<bool> {
+    line.clear();
+    loop {
+        let available = match fin.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if available.is_empty() {
+            return Ok(!line.is_empty());
+        }
+
+        let (chunk, done) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(index) => (&available[..=index], true),
+            None => (available, false),
+        };
+        line.extend_from_slice(chunk);
+        let consumed = chunk.len();
+        fin.consume(consumed);
+
+        // Check as we go, so a line with no end doesn't eat all our memory
+        if let Some(max_line_length) = max_line_length {
+            // A trailing `\r` might be the start of a `\r\n`
+            let content = if done {
+                trim_terminator(line)
+            } else {
+                line.strip_suffix(b"\r").unwrap_or(line)
+            };
+            if content.len() > max_line_length {
+                bail!("Line exceeds maximum length of {max_line_length} bytes");
+            }
+        }
+
+        if done {
+            return Ok(true);
+        }
+    }
+}
+
+/// Picks lines out as they're read, one at a time
+///
+/// Each line is handed on once for every pattern that calls for it. Lines from a descending range
+/// are held back until the range ends, since they're given from its end back to its start
+#[derive(Debug, Clone)]
+pub struct Selector {
+    // Owned, so `$` can be filled in once the last line is found
+    patterns: Vec<Pattern>,
+    // Lines from a descending range, waiting for the range to end, with the range's index
+    descending: Vec<(usize, NonZeroUsize, Vec<u8>)>,
+}
+
+impl Selector {
+    pub fn new(patterns: Vec<Pattern>) -> Self {
+        Self {
+            patterns,
+            descending: Vec::new(),
+        }
+    }
+
+    pub fn patterns(&self) -> &[Pattern] {
+        &self.patterns
+    }
+
+    /// Hand `line` to `select` once for each pattern that calls for it, along with the index of
+    /// the pattern. If `shown` is false, the line is passed over, but a descending range that
+    /// ends on it is still let go
+    pub fn push(
+        &mut self,
+        number: NonZeroUsize,
+        line: &[u8],
+        shown: bool,
+        mut select: impl FnMut(usize, NonZeroUsize, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            if !pattern.is_included(number) {
+                continue;
+            }
+            if shown {
+                if pattern.descending {
+                    self.descending.push((index, number, line.to_vec()));
+                } else {
+                    select(index, number, line)?;
+                }
+            }
+            // A descending range can't be given until its last line has been read
+            if pattern.descending && pattern.end == Some(number) {
+                for (index, number, line) in self.descending.drain(..).rev() {
+                    select(index, number, &line)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Let go of a descending range that the input ended partway through
+    pub fn finish(
+        &mut self,
+        mut select: impl FnMut(usize, NonZeroUsize, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        for (index, number, line) in self.descending.drain(..).rev() {
+            select(index, number, &line)?;
+        }
+        Ok(())
+    }
+
+    /// True if a pattern starts at `$`, so the last line has to be found before it can select
+    /// anything
+    pub fn refers_to_last(&self) -> bool {
+        self.patterns.iter().any(Pattern::refers_to_last)
+    }
+
+    /// Put `last`, now known to be the last line, in place of `$` in every pattern
+    pub fn resolve_last(&mut self, last: NonZeroUsize) {
+        for pattern in &mut self.patterns {
+            pattern.resolve_last(last);
+        }
+    }
+
+    /// How many times the patterns call for a line
+    pub fn times_selected(&self, number: NonZeroUsize) -> usize {
+        times_selected(&self.patterns, number)
+    }
+
+    /// True if no pattern can select any line after this one
+    pub fn is_exhausted(&self, number: NonZeroUsize) -> bool {
+        is_exhausted(&self.patterns, number)
+    }
+}
+
+/// Lazily read the lines that `selection` picks out of `reader`, as `(line_number, content)`
+/// pairs
+///
+/// Line numbers start at 1, and lines come without their terminator. A line is given as many
+/// times as the patterns call for it, and nothing more is read once no pattern can select
+/// another line. Selected lines must be valid UTF-8; one that isn't is given as an error, and
+/// ends the iteration
+///
+/// # Example
+///
+/// ```
+/// use dagan_utils::select::{Selection, select_iter};
+///
+/// let selection: Selection = "2,4..".parse()?;
+/// let input: &[u8] = b"a\nb\nc\nd\ne\n";
+/// let lines = select_iter(&selection, input).collect::<anyhow::Result<Vec<_>>>()?;
+/// assert_eq!(lines, [(2, "b".into()), (4, "d".into()), (5, "e".into())]);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub fn select_iter<'a, R: Read + 'a>(
+    selection: &'a Selection,
+    reader: R,
+) -> impl Iterator<Item = Result<(usize, String)>> + 'a {
+    SelectIter {
+        selector: Selector::new(selection.patterns().to_vec()),
+        reader: BufReader::new(reader),
+        number: 0,
+        ready: VecDeque::new(),
+        done: false,
+    }
+}
+
+struct SelectIter<R: Read> {
+    selector: Selector,
+    reader: BufReader<R>,
+    // The number of the last line read
+    number: usize,
+    // Selected lines waiting to be handed out
+    ready: VecDeque<(usize, String)>,
+    done: bool,
+}
+
+impl<R: Read> SelectIter<R> {
+    // Read the next line and queue up whatever the patterns select from it
+    // Returns false once there's nothing left to read
+    fn advance(&mut self) -> Result<bool> {
+        if let Some(number) = NonZeroUsize::new(self.number)
+            && self.selector.is_exhausted(number)
+        {
+            return Ok(false);
+        }
+        let mut buf = Vec::new();
+        if !read_line(&mut self.reader, &mut buf, None)? {
+            return Ok(false);
+        }
+        self.number += 1;
+        let number = NonZeroUsize::new(self.number).expect("Overflow");
+        if self.selector.refers_to_last() && self.reader.fill_buf()?.is_empty() {
+            self.selector.resolve_last(number);
+        }
+        let ready = &mut self.ready;
+        self.selector
+            .push(number, trim_terminator(&buf), true, |_, number, line| {
+                ready.push_back((number.get(), String::from_utf8(line.to_vec())?));
+                Ok(())
+            })?;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Iterator for SelectIter<R> {
+    type Item = Result<(usize, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.ready.is_empty() && !self.done {
+            let advanced = match self.advance() {
+                Ok(true) => Ok(()),
+                Ok(false) => {
+                    // The input ended partway through a descending range
+                    self.done = true;
+                    let ready = &mut self.ready;
+                    self.selector.finish(|_, number, line| {
+                        ready.push_back((number.get(), String::from_utf8(line.to_vec())?));
+                        Ok(())
+                    })
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = advanced {
+                self.done = true;
+                return Some(Err(err));
+            }
+        }
+        self.ready.pop_front().map(Ok)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_parsing() {
+        let p = Pattern::parse("1").unwrap();
+        assert_eq!(p.start.unwrap().get(), 1);
+        assert_eq!(p.end.unwrap().get(), 1);
+
+        let p = Pattern::parse("..").unwrap();
+        assert_eq!(p.start, None);
+        assert_eq!(p.end, None);
+
+        let p = Pattern::parse("5..").unwrap();
+        assert_eq!(p.start.unwrap().get(), 5);
+        assert_eq!(p.end, None);
+
+        let p = Pattern::parse("42..100").unwrap();
+        assert_eq!(p.start.unwrap().get(), 42);
+        assert_eq!(p.end.unwrap().get(), 99);
+
+        let p = Pattern::parse("..2").unwrap();
+        assert_eq!(p.start, None);
+        assert_eq!(p.end.unwrap().get(), 1);
+
+        let p = Pattern::parse("..=2").unwrap();
+        assert_eq!(p.start, None);
+        assert_eq!(p.end.unwrap().get(), 2);
+
+        let p = Pattern::parse("1..=1").unwrap();
+        assert_eq!(p.start.unwrap().get(), 1);
+        assert_eq!(p.end.unwrap().get(), 1);
+
+        let p = Pattern::parse("5..=100").unwrap();
+        assert_eq!(p.start.unwrap().get(), 5);
+        assert_eq!(p.end.unwrap().get(), 100);
+
+        assert!(Pattern::parse("0..5").is_err());
+        assert!(Pattern::parse("..0").is_err());
+        assert!(Pattern::parse("..1").is_err());
+        assert!(Pattern::parse("0").is_err());
+    }
+
+    #[test]
+    fn last_line_parsing() {
+        for tv in ["$", "$..$", "$..=$", "$.."] {
+            let p = Pattern::parse(tv).unwrap();
+            assert_eq!(p.start, Some(LAST_LINE), "{tv}");
+            assert_eq!(p.end, None, "{tv}");
+            assert!(p.refers_to_last(), "{tv}");
+        }
+        for tv in ["5..$", "5..=$"] {
+            let p = Pattern::parse(tv).unwrap();
+            assert_eq!(p.start.unwrap().get(), 5, "{tv}");
+            assert_eq!(p.end, None, "{tv}");
+            assert!(!p.refers_to_last(), "{tv}");
+        }
+
+        let mut p = Pattern::parse("$").unwrap();
+        assert_eq!(p.resolve(7), Some((7, 7)));
+        assert_eq!(p.resolve(0), None);
+        p.resolve_last(NonZeroUsize::new(7).unwrap());
+        assert_eq!(p.start.unwrap().get(), 7);
+
+        for tv in ["$..5", "$..=5", "$$", "$5", "$..=1:-1", "5..$:-1", "$:2"] {
+            assert!(Pattern::parse(tv).is_err(), "{tv}");
+        }
+    }
+
+    #[test]
+    fn underscore_separators() {
+        let p = Pattern::parse("1_000").unwrap();
+        assert_eq!(p.start.unwrap().get(), 1000);
+        assert_eq!(p.end.unwrap().get(), 1000);
+
+        let p = Pattern::parse("1_000_000..=2_000_000").unwrap();
+        assert_eq!(p.start.unwrap().get(), 1_000_000);
+        assert_eq!(p.end.unwrap().get(), 2_000_000);
+
+        let p = Pattern::parse("1_0..1_1").unwrap();
+        assert_eq!(p.start.unwrap().get(), 10);
+        assert_eq!(p.end.unwrap().get(), 10);
+
+        for tv in [
+            "_1000",
+            "1000_",
+            "1__000",
+            "_",
+            "1_000.._2000",
+            "1_000..=2000_",
+        ] {
+            assert!(Pattern::parse(tv).is_err(), "{tv}");
+        }
+    }
+
+    #[test]
+    fn step_parsing() {
+        let p = Pattern::parse("1..=9:2").unwrap();
+        assert_eq!(p.start.unwrap().get(), 1);
+        assert_eq!(p.end.unwrap().get(), 9);
+        assert_eq!(p.step.get(), 2);
+        assert!(!p.descending);
+
+        let p = Pattern::parse("10..=2:-2").unwrap();
+        assert_eq!(p.start.unwrap().get(), 2);
+        assert_eq!(p.end.unwrap().get(), 10);
+        assert_eq!(p.step.get(), 2);
+        assert!(p.descending);
+
+        let p = Pattern::parse("10..2:-1").unwrap();
+        assert_eq!(p.start.unwrap().get(), 3);
+        assert_eq!(p.end.unwrap().get(), 10);
+
+        let p = Pattern::parse("3..0:-1").unwrap();
+        assert_eq!(p.start.unwrap().get(), 1);
+        assert_eq!(p.end.unwrap().get(), 3);
+
+        for tv in [
+            "2..=10:-2",
+            "5..=5:-1",
+            "10..:-1",
+            "..=2:-1",
+            "10..=2",
+            "1..=9:0",
+            "1..=9:-0",
+            "5:2",
+            "1..=9:",
+            "1..=9:x",
+            "3..=0:-1",
+        ] {
+            assert!(Pattern::parse(tv).is_err(), "{tv}");
+        }
+    }
+
+    #[test]
+    fn radix_parsing() {
+        let tvs = [
+            ("0x10", 16),
+            ("0x1f", 31),
+            ("0xFF", 255),
+            ("0o17", 15),
+            ("0b101", 5),
+            ("0b1111_0000", 240),
+            ("010", 10),
+        ];
+        for (number, expected) in tvs {
+            assert_eq!(parse_number(number).unwrap(), expected, "{number}");
+        }
+
+        let p = Pattern::parse("0x10..0x20").unwrap();
+        assert_eq!(p.start.unwrap().get(), 16);
+        assert_eq!(p.end.unwrap().get(), 31);
+
+        for tv in [
+            "0x", "0xg", "0o8", "0b2", "0x+1", "0X10", "0x_ff", "0x1__0", "0x10_",
+        ] {
+            assert!(parse_number(tv).is_err(), "{tv}");
+        }
+        assert!(Pattern::parse("0x0").is_err());
+    }
+
+    #[test]
+    fn iterate() -> Result<()> {
+        let input = "Foo\nBar\r\nBaz\nQux\n";
+        let tvs: &[(&str, &[(usize, &str)])] = &[
+            ("..", &[(1, "Foo"), (2, "Bar"), (3, "Baz"), (4, "Qux")]),
+            ("2,2..=3", &[(2, "Bar"), (2, "Bar"), (3, "Baz")]),
+            ("4..=1:-2", &[(4, "Qux"), (2, "Bar")]),
+            // The input ends before the descending range does
+            ("9..=2:-1", &[(4, "Qux"), (3, "Baz"), (2, "Bar")]),
+            ("5..", &[]),
+            ("$", &[(4, "Qux")]),
+            ("1,3..$", &[(1, "Foo"), (3, "Baz"), (4, "Qux")]),
+            ("$..$,$", &[(4, "Qux"), (4, "Qux")]),
+        ];
+        for tv in tvs {
+            let selection: Selection = tv.0.parse()?;
+            let lines = select_iter(&selection, input.as_bytes()).collect::<Result<Vec<_>>>()?;
+            let expected =
+                tv.1.iter()
+                    .map(|&(n, line)| (n, line.into()))
+                    .collect::<Vec<_>>();
+            assert_eq!(lines, expected, "{tv:?}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn iterate_stops_early() -> Result<()> {
+        // Reading past the selection would hit the error
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("read past the selection"))
+            }
+        }
+        let input = || b"Foo\nBar\nBaz\n".chain(Broken);
+        let selection: Selection = "..=3".parse()?;
+        let lines = select_iter(&selection, input()).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            lines,
+            [(1, "Foo".into()), (2, "Bar".into()), (3, "Baz".into())]
+        );
+
+        let selection: Selection = "2..".parse()?;
+        assert!(select_iter(&selection, input()).any(|line| line.is_err()));
+
+        assert!("2,1".parse::<Selection>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn iterate_invalid_utf8() -> Result<()> {
+        let input: &[u8] = b"Foo\n\xff\nBar\n";
+        // Lines that aren't selected aren't checked
+        let selection: Selection = "1,3".parse()?;
+        let lines = select_iter(&selection, input).collect::<Result<Vec<_>>>()?;
+        assert_eq!(lines, [(1, "Foo".into()), (3, "Bar".into())]);
+
+        let selection: Selection = "..".parse()?;
+        let mut lines = select_iter(&selection, input);
+        assert_eq!(lines.next().transpose()?, Some((1, "Foo".into())));
+        assert!(lines.next().is_some_and(|line| line.is_err()));
+        assert!(lines.next().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn iterate_parsed() -> Result<()> {
+        let anchors = HashMap::from([("BODY".to_string(), "2".to_string())]);
+        let selection = Selection::parse("1#head;BODY..=3#body", ';', &anchors)?;
+        let labels = selection
+            .patterns()
+            .iter()
+            .map(|pattern| pattern.label.as_deref())
+            .collect::<Vec<_>>();
+        assert_eq!(labels, [Some("head"), Some("body")]);
+        let lines =
+            select_iter(&selection, &b"Foo\nBar\nBaz\nQux\n"[..]).collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            lines,
+            [(1, "Foo".into()), (2, "Bar".into()), (3, "Baz".into())]
+        );
+
+        assert!(Selection::parse("1,2", ';', &anchors).is_err());
+        assert!(Selection::parse("HEAD", ',', &anchors).is_err());
+        Ok(())
+    }
+}
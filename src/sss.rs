@@ -1,60 +1,501 @@
 //! Standard Stream Split - duplicate stdin to both stdout and stderr
 // TODO: alt names: speek? steek? ssp?
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use clap::Parser;
-use std::io::{self, Read, Write};
-
-const PAGE_SIZE: usize = 4096;
+use dagan_utils::{SplitOptions, numbered_name, select::Pattern, stream_split_with};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    num::NonZeroUsize,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
 
 /// Standard Stream Split - duplicate stdin to both stdout and stderr
 #[derive(Parser)]
-struct Args {}
+struct Args {
+    /// Instead of duplicating, deal lines out to N files in turn
+    #[clap(long, value_name = "N", requires = "template")]
+    split: Option<NonZeroUsize>,
+    /// With `--split`, also copy stdin to stdout
+    #[clap(long, requires = "split")]
+    mirror: bool,
+    /// Name of each file to split into. `%d` is replaced with the file's index, starting at 0,
+    /// and `%%` with `%`
+    #[clap(requires = "split")]
+    template: Option<String>,
+    /// With `--split`, don't create any files until there's input to put in them
+    #[clap(long, requires = "split")]
+    lazy: bool,
+    /// With `--split`, make sure each file has made it to disk before exiting
+    #[clap(long, requires = "split")]
+    fsync: bool,
+    /// Flush both outputs after every block, so neither gets ahead of the other. Slower
+    #[clap(long, conflicts_with = "split")]
+    sync: bool,
+    /// When stdin seems to have ended, keep checking it for more until it's been quiet for this
+    /// many SECONDS. For sources that report the end of input before they're really done
+    #[clap(long, value_name = "SECONDS", value_parser = parse_seconds, conflicts_with = "split")]
+    keep_reading: Option<Duration>,
+    /// Stop after copying N bytes, even if there's more on stdin
+    #[clap(long, value_name = "N", conflicts_with = "split")]
+    max_bytes: Option<u64>,
+    /// Don't copy stdin to stdout
+    #[clap(long, conflicts_with_all = ["no_stderr", "split"])]
+    no_stdout: bool,
+    /// Don't copy stdin to stderr
+    #[clap(long, conflicts_with = "split")]
+    no_stderr: bool,
+    /// Instead of copying stdin straight to stderr, feed it to COMMAND, whose output goes to
+    /// stderr. COMMAND is run with `sh -c`, so it may be a pipeline, like `grep ERROR | sort`.
+    /// sss fails if COMMAND does, including when `grep` finds nothing
+    #[clap(long, value_name = "COMMAND", conflicts_with_all = ["no_stderr", "split"])]
+    stderr_filter: Option<String>,
+    /// Number each line copied to stderr, like `cat -n`. Stdout still gets an exact copy
+    #[clap(long, conflicts_with_all = ["no_stderr", "split"])]
+    number_stderr: bool,
+    /// Only copy bytes START..END of stdin to stderr. Stdout still gets everything. Bytes count
+    /// from 1, and ranges are written the same way as `line`'s, so `..=` includes the end
+    #[clap(long, value_name = "START..END", value_parser = parse_window, conflicts_with_all = ["no_stderr", "split"])]
+    window: Option<Pattern>,
+}
 
-fn stream_split(
-    mut stdin: impl Read,
-    mut stdout: impl Write,
-    mut stderr: impl Write,
+// Write each line to the next shard in turn, wrapping around after the last
+// A final line with no terminator is written as-is
+fn round_robin(
+    stdin: impl Read,
+    shards: &mut [impl Write],
+    mut mirror: Option<impl Write>,
 ) -> Result<()> {
-    let mut buf = [0u8; PAGE_SIZE];
-    while let bytes = stdin.read(&mut buf)?
-        && bytes != 0
-    {
-        stdout.write_all(&buf[0..bytes])?;
-        stderr.write_all(&buf[0..bytes])?;
+    let mut stdin = BufReader::new(stdin);
+    let mut line = Vec::new();
+    for index in (0..shards.len()).cycle() {
+        line.clear();
+        if stdin.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        shards[index].write_all(&line)?;
+        if let Some(mirror) = &mut mirror {
+            mirror.write_all(&line)?;
+        }
+    }
+
+    for shard in shards {
+        shard.flush()?;
+    }
+    if let Some(mirror) = &mut mirror {
+        mirror.flush()?;
+    }
+    Ok(())
+}
+
+fn split_into_files(
+    stdin: impl Read,
+    split: NonZeroUsize,
+    template: &str,
+    mirror: Option<impl Write>,
+    lazy: bool,
+    fsync: bool,
+) -> Result<()> {
+    if !template.contains("%d") && split.get() > 1 {
+        bail!("Template must contain `%d` to split into more than one file");
+    }
+
+    let mut stdin = BufReader::new(stdin);
+    if lazy && stdin.fill_buf()?.is_empty() {
+        return Ok(());
     }
 
+    let mut shards = (0..split.get())
+        .map(|index| {
+            Ok(BufWriter::new(File::create(numbered_name(
+                template, index,
+            )?)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    round_robin(stdin, &mut shards, mirror)?;
+    if fsync {
+        for shard in &shards {
+            shard.get_ref().sync_all()?;
+        }
+    }
     Ok(())
 }
 
 fn main() -> Result<()> {
-    let _args = Args::parse();
-    stream_split(io::stdin().lock(), io::stdout().lock(), io::stderr().lock())
+    let args = Args::parse();
+    if let (Some(split), Some(template)) = (args.split, args.template) {
+        let mirror = args.mirror.then(|| io::stdout().lock());
+        return split_into_files(
+            io::stdin().lock(),
+            split,
+            &template,
+            mirror,
+            args.lazy,
+            args.fsync,
+        );
+    }
+    let mut stdout = suppressible(io::stdout().lock(), args.no_stdout);
+    let mut filter = args
+        .stderr_filter
+        .map(|command| spawn_filter(&command, io::stderr()))
+        .transpose()?;
+    let mut stderr: Box<dyn Write> = match &mut filter {
+        Some(filter) => Box::new(filter.stdin.take().expect("Filter's stdin should be piped")),
+        None => suppressible(io::stderr().lock(), args.no_stderr),
+    };
+    if args.number_stderr {
+        stderr = Box::new(NumberLines::new(stderr));
+    }
+    if let Some(window) = args.window {
+        stderr = Box::new(ByteWindow::new(stderr, &window));
+    }
+    let options = SplitOptions {
+        sync: args.sync,
+        keep_reading: args.keep_reading,
+        max_bytes: args.max_bytes,
+    };
+    let result = stream_split_with(
+        io::stdin().lock(),
+        &mut [&mut stdout, &mut stderr],
+        &options,
+    );
+
+    // Closing its stdin lets the filter know there's nothing more coming
+    drop(stderr);
+    let filtered = filter.map(wait_for_filter).transpose();
+    result?;
+    filtered?;
+    Ok(())
+}
+
+// Run `command` with the shell, with a pipe to its stdin
+fn spawn_filter(command: &str, output: impl Into<Stdio>) -> Result<Child> {
+    Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(output)
+        .spawn()
+        .map_err(|err| anyhow!("Could not run filter `{command}`: {err}"))
+}
+
+// Wait for a filter to finish, failing if it did
+fn wait_for_filter(mut filter: Child) -> Result<()> {
+    let status = filter.wait()?;
+    if !status.success() {
+        bail!("Filter failed: {status}");
+    }
+    Ok(())
+}
+
+fn parse_window(window: &str) -> Result<Pattern> {
+    let pattern = Pattern::parse(window)?;
+    if pattern.step != NonZeroUsize::MIN || pattern.descending {
+        bail!("Byte windows can't have a step: {window}");
+    }
+    Ok(pattern)
+}
+
+fn parse_seconds(seconds: &str) -> Result<Duration> {
+    let seconds = seconds
+        .parse()
+        .map_err(|_| anyhow!("Could not interpret duration: {seconds}"))?;
+    Ok(Duration::try_from_secs_f64(seconds)?)
+}
+
+// Numbers each line written through it, like `cat -n`
+// A final line with no terminator is still numbered
+struct NumberLines<W: Write> {
+    inner: W,
+    // The number of the line being written
+    number: usize,
+    // True if the next byte starts a new line
+    at_line_start: bool,
+}
+
+impl<W: Write> NumberLines<W> {
+    fn new(inner: W) -> Self {
+        Self {
+            inner,
+            number: 0,
+            at_line_start: true,
+        }
+    }
+}
+
+impl<W: Write> Write for NumberLines<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for line in buf.split_inclusive(|&byte| byte == b'\n') {
+            if self.at_line_start {
+                self.number += 1;
+                write!(self.inner, "{:>6}\t", self.number)?;
+            }
+            self.inner.write_all(line)?;
+            self.at_line_start = line.ends_with(b"\n");
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Passes on only the bytes that fall in a window, counting from 1
+struct ByteWindow<W: Write> {
+    inner: W,
+    // The first and last bytes to pass on, as 0-based offsets
+    start: usize,
+    end: usize,
+    // How many bytes have been written so far
+    position: usize,
+}
+
+impl<W: Write> ByteWindow<W> {
+    fn new(inner: W, window: &Pattern) -> Self {
+        Self {
+            inner,
+            start: window.start.map_or(0, |start| start.get() - 1),
+            end: window.end.map_or(usize::MAX, |end| end.get() - 1),
+            position: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for ByteWindow<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The part of the window that this block overlaps, relative to the block
+        let from = self.start.saturating_sub(self.position);
+        let to = (self.end - self.position.min(self.end)).saturating_add(1);
+        if self.position <= self.end && from < buf.len() {
+            self.inner.write_all(&buf[from..to.min(buf.len())])?;
+        }
+        self.position += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Discard everything written to a sink if it's been suppressed
+fn suppressible<'a>(sink: impl Write + 'a, suppress: bool) -> Box<dyn Write + 'a> {
+    if suppress {
+        Box::new(io::sink())
+    } else {
+        Box::new(sink)
+    }
 }
 
+#[cfg(test)]
+mod test_util;
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Cursor;
+    use crate::test_util::TempDir;
+    use dagan_utils::stream_split;
+    use std::{fs, io::Cursor};
+
+    #[test]
+    fn round_robin_shards() -> Result<()> {
+        let tvs: &[(&str, [&str; 3])] = &[
+            ("", ["", "", ""]),
+            ("1\n2\n", ["1\n", "2\n", ""]),
+            ("1\n2\n3\n4\n5\n6\n7\n", ["1\n4\n7\n", "2\n5\n", "3\n6\n"]),
+            ("1\n2\n3\n4", ["1\n4", "2\n", "3\n"]),
+            ("\n\r\n👋", ["\n", "\r\n", "👋"]),
+        ];
+        for tv in tvs {
+            let mut shards = vec![Vec::<u8>::new(); 3];
+            let mut mirror = Vec::<u8>::new();
+            round_robin(Cursor::new(tv.0), &mut shards, Some(&mut mirror))?;
+            for (expected, actual) in tv.1.iter().zip(shards) {
+                assert_eq!(*expected, String::from_utf8(actual)?);
+            }
+            assert_eq!(tv.0, String::from_utf8(mirror)?);
+        }
+        Ok(())
+    }
 
     #[test]
-    fn basic_functionality() {
+    fn suppression() -> Result<()> {
         let tvs = [
-            "",
-            "hi",
-            "foo\nbar",
-            "\n",
-            "\r",
-            "\r\nbaz\n\t",
-            "The quic\nk brown fox jamped\n over the lazy dorg\n\n",
-            "👋",
+            (vec![], true, true),
+            (vec!["--no-stdout"], false, true),
+            (vec!["--no-stderr"], true, false),
+        ];
+        for (flags, has_stdout, has_stderr) in tvs {
+            let args = Args::try_parse_from([&["sss"], &flags[..]].concat())?;
+            let mut stdout = Vec::<u8>::new();
+            let mut stderr = Vec::<u8>::new();
+            stream_split(
+                Cursor::new("foo\nbar"),
+                suppressible(&mut stdout, args.no_stdout),
+                suppressible(&mut stderr, args.no_stderr),
+            )?;
+            let expected = |shown: bool| if shown { "foo\nbar" } else { "" };
+            assert_eq!(expected(has_stdout), String::from_utf8(stdout)?);
+            assert_eq!(expected(has_stderr), String::from_utf8(stderr)?);
+        }
+
+        // At least one sink has to remain
+        assert!(Args::try_parse_from(["sss", "--no-stdout", "--no-stderr"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn number_stderr() -> Result<()> {
+        let tvs = [
+            ("", ""),
+            ("foo\n", "     1\tfoo\n"),
+            ("foo\n\nbar", "     1\tfoo\n     2\t\n     3\tbar"),
+            ("\r\n👋\n", "     1\t\r\n     2\t👋\n"),
         ];
         for tv in tvs {
-            let stdin = Cursor::new(String::from(tv));
             let mut stdout = Vec::<u8>::new();
             let mut stderr = Vec::<u8>::new();
-            stream_split(stdin, &mut stdout, &mut stderr).unwrap();
-            assert_eq!(tv, String::from_utf8(stdout).unwrap());
-            assert_eq!(tv, String::from_utf8(stderr).unwrap());
+            stream_split(
+                Cursor::new(tv.0),
+                &mut stdout,
+                NumberLines::new(&mut stderr),
+            )?;
+            assert_eq!(tv.0, String::from_utf8(stdout)?);
+            assert_eq!(tv.1, String::from_utf8(stderr)?);
+        }
+
+        // Lines may be split across writes
+        let mut stderr = Vec::<u8>::new();
+        let mut numbered = NumberLines::new(&mut stderr);
+        for piece in ["fo", "o\nba", "r\n", "\n"] {
+            numbered.write_all(piece.as_bytes())?;
         }
+        assert_eq!(
+            "     1\tfoo\n     2\tbar\n     3\t\n",
+            String::from_utf8(stderr)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn byte_window() -> Result<()> {
+        let input = "0123456789";
+        let tvs = [
+            ("..", input),
+            ("1", "0"),
+            ("3..6", "234"),
+            ("3..=6", "2345"),
+            ("8..", "789"),
+            ("..=2", "01"),
+            ("10..=20", "9"),
+            ("11..", ""),
+        ];
+        for tv in tvs {
+            let window = parse_window(tv.0)?;
+            // Window edges land in different places depending on how the input is split up
+            for block in [1, 3, input.len()] {
+                let mut stderr = Vec::<u8>::new();
+                let mut windowed = ByteWindow::new(&mut stderr, &window);
+                for chunk in input.as_bytes().chunks(block) {
+                    windowed.write_all(chunk)?;
+                }
+                assert_eq!(tv.1, String::from_utf8(stderr)?, "{} in {block}s", tv.0);
+            }
+        }
+        assert!(parse_window("1..10:2").is_err());
+        assert!(parse_window("10..=1:-1").is_err());
+
+        // Capture the middle of the stream to a file, while stdout gets all of it
+        let dir = TempDir::new("window")?;
+        let path = dir.join("window.txt");
+        let input = "The quick brown fox\n".repeat(1000);
+        let mut stdout = Vec::<u8>::new();
+        stream_split(
+            Cursor::new(&input),
+            &mut stdout,
+            ByteWindow::new(File::create(&path)?, &parse_window("5000..=10000")?),
+        )?;
+        assert_eq!(input, String::from_utf8(stdout)?);
+        assert_eq!(fs::read_to_string(&path)?, &input[4999..10000]);
+        Ok(())
+    }
+
+    #[test]
+    fn stderr_filter() -> Result<()> {
+        let tvs = [
+            ("cat", "foo\nbar\n", "foo\nbar\n"),
+            ("grep a | tr a-z A-Z", "foo\nbar\nbaz\n", "BAR\nBAZ\n"),
+        ];
+        for tv in tvs {
+            let mut filter = spawn_filter(tv.0, Stdio::piped())?;
+            let mut stdout = Vec::<u8>::new();
+            stream_split(Cursor::new(tv.1), &mut stdout, filter.stdin.take().unwrap())?;
+            let output = filter.wait_with_output()?;
+            assert!(output.status.success());
+            assert_eq!(tv.1, String::from_utf8(stdout)?);
+            assert_eq!(tv.2, String::from_utf8(output.stdout)?);
+        }
+
+        // The filter's failure is ours too
+        let mut filter = spawn_filter("cat; exit 3", Stdio::null())?;
+        drop(filter.stdin.take());
+        assert!(wait_for_filter(filter).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn lazy_split() -> Result<()> {
+        let dir = TempDir::new("lazy")?;
+        let template = dir.join("shard_%d.txt");
+        let template = template.to_str().unwrap();
+        let split = NonZeroUsize::new(2).unwrap();
+        let no_mirror = None::<Vec<u8>>;
+
+        // Nothing to split, so nothing to create
+        split_into_files(
+            Cursor::new(""),
+            split,
+            template,
+            no_mirror.clone(),
+            true,
+            false,
+        )?;
+        assert_eq!(fs::read_dir(&dir)?.count(), 0);
+
+        split_into_files(
+            Cursor::new("foo\n"),
+            split,
+            template,
+            no_mirror.clone(),
+            true,
+            false,
+        )?;
+        assert_eq!(fs::read_dir(&dir)?.count(), 2);
+        assert_eq!(fs::read_to_string(numbered_name(template, 0)?)?, "foo\n");
+        assert_eq!(fs::read_to_string(numbered_name(template, 1)?)?, "");
+        Ok(())
+    }
+
+    #[test]
+    fn fsync() -> Result<()> {
+        let dir = TempDir::new("fsync")?;
+        let template = dir.join("shard_%d.txt");
+        let template = template.to_str().unwrap();
+        let split = NonZeroUsize::new(2).unwrap();
+
+        // Whether it reached the disk can't be checked from here, but the contents can
+        split_into_files(
+            Cursor::new("foo\nbar\nbaz"),
+            split,
+            template,
+            None::<Vec<u8>>,
+            false,
+            true,
+        )?;
+        assert_eq!(fs::read_to_string(numbered_name(template, 0)?)?, "foo\nbaz");
+        assert_eq!(fs::read_to_string(numbered_name(template, 1)?)?, "bar\n");
+        Ok(())
     }
 }
@@ -0,0 +1,43 @@
+//! Helpers for the tests of more than one binary
+use anyhow::Result;
+use std::{
+    env, fs,
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+};
+
+// A directory for a test's files, deleted with everything in it when dropped, even if the test
+// fails partway through
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    // Named after the binary being tested as well as `name`, so tests of different binaries
+    // don't share directories
+    pub fn new(name: &str) -> Result<Self> {
+        let bin = env!("CARGO_BIN_NAME");
+        let path = env::temp_dir().join(format!("{bin}-{name}-{}", process::id()));
+        fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}